@@ -0,0 +1,319 @@
+//! Runs one EventSub session per account, for applications serving many broadcasters. Twitch only
+//! accepts WebSocket subscriptions created with the token of the user the session belongs to, so
+//! every account gets its own session, with subscriptions created through a Helix client using
+//! the account's token.
+//!
+//! Each session reconnects on its own, and an account whose session ends, e.g. because its token
+//! was revoked, is marked as stopped without affecting the others. The messages of all accounts
+//! arrive on one channel, tagged with the account they came from.
+
+use crate::error::{AccountErr, EventSubErr};
+use crate::helix::{HelixClient, HELIX_URL};
+use crate::source::{Connector, WebSocketConnector};
+use crate::subscriptions::{DesiredSubscription, SubscriptionCreator, SubscriptionManager};
+use crate::types::{Session, StopHandle, TwitchMessage};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use url::Url;
+
+/// A user whose session is run by the `AccountRegistry`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Account {
+    /// Identifies the account in the registry and on its messages, e.g. the user ID.
+    pub id: String,
+    pub client_id: String,
+    /// The user access token the account's subscriptions are created with.
+    pub access_token: String,
+}
+
+impl fmt::Debug for Account {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Keeps tokens out of logs
+        f.debug_struct("Account")
+            .field("id", &self.id)
+            .field("client_id", &self.client_id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A message, along with the ID of the account whose session received it.
+#[derive(Debug)]
+pub struct TaggedMessage {
+    pub account: String,
+    pub message: TwitchMessage,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccountStatus {
+    Running,
+    /// The account's message loop ended, for the given reason.
+    Stopped(String),
+}
+
+struct AccountEntry {
+    session: Arc<Mutex<Session>>,
+    stop: StopHandle,
+    status: Arc<Mutex<AccountStatus>>,
+    listener: JoinHandle<()>,
+}
+
+/// The sessions of all accounts, by account ID.
+pub struct AccountRegistry {
+    url: Url,
+    connector: Arc<dyn Connector>,
+    /// The Helix API the accounts' subscriptions are created with.
+    pub helix_url: Url,
+    forwarder: Sender<TaggedMessage>,
+    accounts: BTreeMap<String, AccountEntry>,
+}
+
+impl AccountRegistry {
+    /// Creates an empty registry, whose accounts connect to the `url`. The messages of all
+    /// accounts are sent through the `message_forwarder`.
+    pub fn new(url: Url, message_forwarder: Sender<TaggedMessage>) -> AccountRegistry {
+        AccountRegistry::with_connector(url, Arc::new(WebSocketConnector), message_forwarder)
+    }
+
+    pub fn with_connector(
+        url: Url,
+        connector: Arc<dyn Connector>,
+        message_forwarder: Sender<TaggedMessage>,
+    ) -> AccountRegistry {
+        AccountRegistry {
+            url,
+            connector,
+            helix_url: Url::parse(HELIX_URL).expect("HELIX_URL is a valid url"),
+            forwarder: message_forwarder,
+            accounts: BTreeMap::new(),
+        }
+    }
+
+    /// Opens a session for the `account`, which creates the `subscriptions` with the account's
+    /// token once it is welcomed, and again whenever it reconnects. An account that was already
+    /// registered is replaced.
+    pub fn add(
+        &mut self,
+        account: &Account,
+        subscriptions: Vec<DesiredSubscription>,
+    ) -> Result<(), AccountErr> {
+        let helix = HelixClient::with_base_url(
+            &account.client_id,
+            &account.access_token,
+            self.helix_url.clone(),
+        )?;
+        Ok(self.add_with_creator(&account.id, helix, subscriptions)?)
+    }
+
+    /// Like `add`, but creates the subscriptions through the `creator`, e.g. to route the
+    /// requests through an application's own API layer.
+    pub fn add_with_creator(
+        &mut self,
+        account_id: &str,
+        creator: impl SubscriptionCreator + Sync + 'static,
+        subscriptions: Vec<DesiredSubscription>,
+    ) -> Result<(), EventSubErr> {
+        self.remove(account_id)?;
+        let session = crate::get_session_with(self.url.clone(), Arc::clone(&self.connector))?;
+        let mut manager = SubscriptionManager::new(creator);
+        for subscription in subscriptions {
+            manager.remember(subscription);
+        }
+        let stop = {
+            let mut session = session.lock()?;
+            session.subscriptions = Some(manager);
+            session.stop_handle()
+        };
+
+        let status = Arc::new(Mutex::new(AccountStatus::Running));
+        let (tx, rx) = mpsc::channel();
+        let forwarder = self.forwarder.clone();
+        let account = account_id.to_owned();
+        let listener_session = Arc::clone(&session);
+        let listener_status = Arc::clone(&status);
+        let listener = thread::Builder::new()
+            .name(format!("account-{}", account_id))
+            .spawn(move || {
+                let tagger = thread::spawn(move || {
+                    for message in rx {
+                        let tagged = TaggedMessage {
+                            account: account.clone(),
+                            message,
+                        };
+                        if forwarder.send(tagged).is_err() {
+                            break;
+                        }
+                    }
+                });
+                let reason = match crate::create_message_processor(listener_session, &tx) {
+                    Ok(()) => "closed".to_owned(),
+                    Err(err) => err.to_string(),
+                };
+                drop(tx);
+                let _ = tagger.join();
+                *lock(&listener_status) = AccountStatus::Stopped(reason);
+            })?;
+        self.accounts.insert(
+            account_id.to_owned(),
+            AccountEntry {
+                session,
+                stop,
+                status,
+                listener,
+            },
+        );
+        Ok(())
+    }
+
+    /// Stops the account's session and waits for its message loop to end, which closes the
+    /// connection once it is done with the message at hand. Returns whether the account was
+    /// registered.
+    pub fn remove(&mut self, account_id: &str) -> Result<bool, EventSubErr> {
+        let Some(entry) = self.accounts.remove(account_id) else {
+            return Ok(false);
+        };
+        entry.stop.stop();
+        let _ = entry.listener.join();
+        Ok(true)
+    }
+
+    pub fn session(&self, account_id: &str) -> Option<Arc<Mutex<Session>>> {
+        self.accounts
+            .get(account_id)
+            .map(|entry| Arc::clone(&entry.session))
+    }
+
+    pub fn status(&self, account_id: &str) -> Option<AccountStatus> {
+        self.accounts
+            .get(account_id)
+            .map(|entry| lock(&entry.status).clone())
+    }
+
+    /// The IDs of all registered accounts, in order.
+    pub fn accounts(&self) -> Vec<String> {
+        self.accounts.keys().cloned().collect()
+    }
+}
+
+impl fmt::Debug for AccountRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccountRegistry")
+            .field("url", &self.url)
+            .field("accounts", &self.accounts())
+            .finish_non_exhaustive()
+    }
+}
+
+fn lock(status: &Mutex<AccountStatus>) -> std::sync::MutexGuard<'_, AccountStatus> {
+    status.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::close::CloseReason;
+    use crate::error::SubscriptionErr;
+    use crate::events::ClientEvent;
+    use crate::mock::{self, MockServer, Step};
+    use serde_json::json;
+    use std::time::{Duration, Instant};
+
+    struct FakeCreator {
+        revoked: bool,
+    }
+
+    impl SubscriptionCreator for FakeCreator {
+        fn create_subscription(
+            &self,
+            _subscription: &DesiredSubscription,
+            _session_id: &str,
+        ) -> Result<(), SubscriptionErr> {
+            if self.revoked {
+                return Err(SubscriptionErr::Rejected("invalid OAuth token".to_owned()));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn revoked_account_does_not_stop_the_others() {
+        let server = MockServer::start(vec![
+            // Twitch closes connections nothing was subscribed on in time
+            vec![Step::Send(mock::welcome("revoked", 10)), Step::Close(4003)],
+            vec![
+                Step::Send(mock::welcome("healthy", 10)),
+                Step::Send(mock::notification("stream.online", json!({}))),
+            ],
+        ]);
+        let (tx, messages) = mpsc::channel();
+        let mut registry = AccountRegistry::new(server.url(), tx);
+        let online = DesiredSubscription::new("stream.online", "1", json!({}));
+        registry
+            .add_with_creator(
+                "revoked",
+                FakeCreator { revoked: true },
+                vec![online.clone()],
+            )
+            .unwrap();
+        registry
+            .add_with_creator("healthy", FakeCreator { revoked: false }, vec![online])
+            .unwrap();
+
+        let tagged: Vec<TaggedMessage> = messages
+            .iter()
+            .filter(|msg| msg.account == "healthy")
+            .take(2)
+            .collect();
+        assert!(matches!(tagged[1].message, TwitchMessage::Notification(_)));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while registry.status("revoked") == Some(AccountStatus::Running) {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(matches!(
+            registry.status("revoked"),
+            Some(AccountStatus::Stopped(reason))
+                if reason == EventSubErr::Closed(CloseReason::ConnectionUnused).to_string()
+        ));
+        assert_eq!(registry.status("healthy"), Some(AccountStatus::Running));
+        assert_eq!(registry.accounts(), vec!["healthy", "revoked"]);
+        assert!(registry.remove("healthy").unwrap());
+        assert!(!registry.remove("healthy").unwrap());
+    }
+
+    #[test]
+    fn removing_ends_a_session_waiting_to_reconnect() {
+        // Once the script is over, the mock server refuses every reconnect
+        let server = MockServer::start(vec![vec![
+            Step::Send(mock::welcome("session", 10)),
+            Step::Close(4000),
+        ]]);
+        let (tx, messages) = mpsc::channel();
+        let mut registry = AccountRegistry::new(server.url(), tx);
+        registry
+            .add_with_creator("account", FakeCreator { revoked: false }, vec![])
+            .unwrap();
+        let (event_forwarder, events) = mpsc::channel();
+        registry
+            .session("account")
+            .unwrap()
+            .lock()
+            .unwrap()
+            .event_forwarder = Some(event_forwarder);
+        assert!(matches!(
+            messages.recv().unwrap().message,
+            TwitchMessage::Welcome(_)
+        ));
+        while !matches!(
+            events.recv().unwrap(),
+            ClientEvent::Reconnecting { attempt: 2, .. }
+        ) {}
+
+        let removing = Instant::now();
+        assert!(registry.remove("account").unwrap());
+        assert!(removing.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,490 @@
+//! Access tokens for Helix requests. A `TokenProvider` hands out a token that is valid right now,
+//! and replaces it when Twitch rejects it anyway. `StaticToken` always returns the same token,
+//! while `RefreshingToken` validates its token against Twitch's `/oauth2/validate` endpoint and
+//! refreshes it with a refresh token before it expires.
+//!
+//! User tokens are obtained through one of the OAuth `flows`, and kept between runs in a
+//! `store::TokenStore`.
+
+use crate::auth::store::TokenStore;
+use crate::clock::{Clock, SystemClock};
+use crate::error::AuthErr;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use url::Url;
+
+pub mod flows;
+pub mod store;
+
+pub const OAUTH_URL: &str = "https://id.twitch.tv/oauth2";
+
+/// Twitch asks applications to validate their tokens at least once an hour.
+pub const VALIDATE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long before it expires a token is refreshed, so requests made with it don't fail.
+pub const REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// Supplies the access tokens Helix requests are authenticated with.
+pub trait TokenProvider: fmt::Debug + Send + Sync {
+    /// A token that is valid right now, as far as the provider knows.
+    fn access_token(&self) -> Result<String, AuthErr>;
+    /// Replaces the `rejected` token, after Twitch rejected it. If another request already
+    /// replaced it, the current token is returned instead.
+    fn refresh(&self, rejected: &str) -> Result<String, AuthErr>;
+}
+
+/// A token which is used as is, and can't be refreshed.
+#[derive(Clone)]
+pub struct StaticToken {
+    access_token: String,
+}
+
+impl StaticToken {
+    pub fn new(access_token: &str) -> StaticToken {
+        StaticToken {
+            access_token: access_token.to_owned(),
+        }
+    }
+}
+
+impl fmt::Debug for StaticToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StaticToken").finish_non_exhaustive()
+    }
+}
+
+impl TokenProvider for StaticToken {
+    fn access_token(&self) -> Result<String, AuthErr> {
+        Ok(self.access_token.clone())
+    }
+
+    fn refresh(&self, _rejected: &str) -> Result<String, AuthErr> {
+        Err(AuthErr::CantRefresh(
+            "static tokens have no refresh token".to_owned(),
+        ))
+    }
+}
+
+/// Where Twitch's OAuth endpoints are, which can be pointed at a local mock with `at`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OAuthEndpoints {
+    pub validate: Url,
+    pub token: Url,
+    pub device: Url,
+    pub authorize: Url,
+}
+
+impl OAuthEndpoints {
+    /// The endpoints below `base`, e.g. `OAUTH_URL`.
+    pub fn at(base: &str) -> Result<OAuthEndpoints, url::ParseError> {
+        let base = base.trim_end_matches('/');
+        Ok(OAuthEndpoints {
+            validate: Url::parse(&format!("{}/validate", base))?,
+            token: Url::parse(&format!("{}/token", base))?,
+            device: Url::parse(&format!("{}/device", base))?,
+            authorize: Url::parse(&format!("{}/authorize", base))?,
+        })
+    }
+}
+
+impl Default for OAuthEndpoints {
+    fn default() -> Self {
+        OAuthEndpoints::at(OAUTH_URL).expect("OAUTH_URL is a valid url")
+    }
+}
+
+/// What `/oauth2/validate` reports about a token.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TokenInfo {
+    pub client_id: String,
+    /// Only set for user access tokens.
+    pub login: Option<String>,
+    pub user_id: Option<String>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Seconds until the token expires, or 0 if it doesn't.
+    pub expires_in: u64,
+}
+
+/// The response of `/oauth2/token`, for every grant type.
+#[derive(Deserialize)]
+pub(crate) struct TokenResponse {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub expires_in: Option<u64>,
+    #[serde(default)]
+    pub scope: Vec<String>,
+}
+
+impl TokenResponse {
+    /// The token, which expires `expires_in` seconds after `now`.
+    pub fn into_user_token(self, now: DateTime<Utc>) -> UserToken {
+        UserToken {
+            access_token: self.access_token,
+            refresh_token: self.refresh_token,
+            scopes: self.scope,
+            expires_at: self
+                .expires_in
+                .filter(|expires_in| *expires_in > 0)
+                .and_then(|expires_in| i64::try_from(expires_in).ok())
+                .map(|expires_in| now + chrono::Duration::seconds(expires_in)),
+        }
+    }
+}
+
+/// A user access token as obtained through one of the `flows`, in the form it is stored in.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct UserToken {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl fmt::Debug for UserToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Keeps the tokens out of logs
+        f.debug_struct("UserToken")
+            .field("scopes", &self.scopes)
+            .field("expires_at", &self.expires_at)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A user access token that is validated at least every `VALIDATE_INTERVAL`, and refreshed once
+/// it expires within `REFRESH_MARGIN` or Twitch rejected it.
+pub struct RefreshingToken {
+    client_id: String,
+    client_secret: String,
+    endpoints: OAuthEndpoints,
+    agent: ureq::Agent,
+    clock: Arc<dyn Clock>,
+    store: Option<TokenStore>,
+    state: Mutex<TokenState>,
+}
+
+struct TokenState {
+    access_token: String,
+    refresh_token: Option<String>,
+    scopes: Vec<String>,
+    expires_at: Option<Instant>,
+    validated_at: Option<Instant>,
+    info: Option<TokenInfo>,
+}
+
+impl RefreshingToken {
+    pub fn new(
+        client_id: &str,
+        client_secret: &str,
+        access_token: &str,
+        refresh_token: Option<&str>,
+    ) -> Result<RefreshingToken, AuthErr> {
+        Ok(RefreshingToken {
+            client_id: client_id.to_owned(),
+            client_secret: client_secret.to_owned(),
+            endpoints: OAuthEndpoints::default(),
+            agent: flows::agent()?,
+            clock: Arc::new(SystemClock),
+            store: None,
+            state: Mutex::new(TokenState {
+                access_token: access_token.to_owned(),
+                refresh_token: refresh_token.map(str::to_owned),
+                scopes: vec![],
+                expires_at: None,
+                validated_at: None,
+                info: None,
+            }),
+        })
+    }
+
+    /// Keeps a token obtained through one of the `flows` fresh. The `client_secret` is empty
+    /// for public clients, such as those using the `DeviceFlow`.
+    pub fn from_user_token(
+        client_id: &str,
+        client_secret: &str,
+        token: &UserToken,
+    ) -> Result<RefreshingToken, AuthErr> {
+        let tokens = RefreshingToken::new(
+            client_id,
+            client_secret,
+            &token.access_token,
+            token.refresh_token.as_deref(),
+        )?;
+        tokens.lock().scopes = token.scopes.clone();
+        Ok(tokens)
+    }
+
+    pub fn endpoints(mut self, endpoints: OAuthEndpoints) -> RefreshingToken {
+        self.endpoints = endpoints;
+        self
+    }
+
+    /// Replaces the `SystemClock` expiry and validation times are measured with.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> RefreshingToken {
+        self.clock = clock;
+        self
+    }
+
+    /// Saves the token to the `store` after every refresh, so a rotated refresh token survives a
+    /// restart.
+    pub fn store(mut self, store: TokenStore) -> RefreshingToken {
+        self.store = Some(store);
+        self
+    }
+
+    /// The current token, in the form it is stored in.
+    pub fn user_token(&self) -> UserToken {
+        let state = self.lock();
+        self.user_token_locked(&state)
+    }
+
+    /// Validates the current token, and returns what Twitch knows about it.
+    pub fn validate(&self) -> Result<TokenInfo, AuthErr> {
+        let mut state = self.lock();
+        self.validate_locked(&mut state)
+    }
+
+    /// What the last validation reported about the token, if it was validated yet.
+    pub fn info(&self) -> Option<TokenInfo> {
+        self.lock().info.clone()
+    }
+
+    fn validate_locked(&self, state: &mut TokenState) -> Result<TokenInfo, AuthErr> {
+        let info: TokenInfo = self
+            .agent
+            .request_url("GET", &self.endpoints.validate)
+            .set("Authorization", &format!("OAuth {}", state.access_token))
+            .call()?
+            .into_json()?;
+        let now = self.clock.now();
+        state.validated_at = Some(now);
+        state.expires_at =
+            (info.expires_in > 0).then(|| now + Duration::from_secs(info.expires_in));
+        state.scopes = info.scopes.clone();
+        state.info = Some(info.clone());
+        Ok(info)
+    }
+
+    fn user_token_locked(&self, state: &TokenState) -> UserToken {
+        let now = self.clock.now();
+        let expires_at = state.expires_at.map(|expires_at| {
+            let left = expires_at.saturating_duration_since(now);
+            self.clock.wall() + chrono::Duration::from_std(left).unwrap_or_default()
+        });
+        UserToken {
+            access_token: state.access_token.clone(),
+            refresh_token: state.refresh_token.clone(),
+            scopes: state.scopes.clone(),
+            expires_at,
+        }
+    }
+
+    fn refresh_locked(&self, state: &mut TokenState) -> Result<String, AuthErr> {
+        let refresh_token = state
+            .refresh_token
+            .clone()
+            .ok_or_else(|| AuthErr::CantRefresh("no refresh token was given".to_owned()))?;
+        let mut form = vec![
+            ("grant_type", "refresh_token"),
+            ("refresh_token", &refresh_token),
+            ("client_id", &self.client_id),
+        ];
+        // Public clients refresh with their client ID alone
+        if !self.client_secret.is_empty() {
+            form.push(("client_secret", &self.client_secret));
+        }
+        let refreshed: TokenResponse = self
+            .agent
+            .request_url("POST", &self.endpoints.token)
+            .send_form(&form)?
+            .into_json()?;
+        let now = self.clock.now();
+        state.access_token = refreshed.access_token;
+        if let Some(refresh_token) = refreshed.refresh_token {
+            state.refresh_token = Some(refresh_token);
+        }
+        if !refreshed.scope.is_empty() {
+            state.scopes = refreshed.scope;
+        }
+        // A freshly issued token doesn't need to be validated right away
+        state.validated_at = Some(now);
+        state.expires_at = refreshed
+            .expires_in
+            .filter(|expires_in| *expires_in > 0)
+            .map(|expires_in| now + Duration::from_secs(expires_in));
+        if let Some(store) = &self.store {
+            store.save(&self.user_token_locked(state))?;
+        }
+        Ok(state.access_token.clone())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, TokenState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl TokenProvider for RefreshingToken {
+    fn access_token(&self) -> Result<String, AuthErr> {
+        let mut state = self.lock();
+        let now = self.clock.now();
+        if state
+            .expires_at
+            .is_some_and(|expires_at| now + REFRESH_MARGIN >= expires_at)
+        {
+            return self.refresh_locked(&mut state);
+        }
+        let validation_due = state.validated_at.map_or(true, |validated_at| {
+            now.saturating_duration_since(validated_at) >= VALIDATE_INTERVAL
+        });
+        if validation_due {
+            match self.validate_locked(&mut state) {
+                Ok(info) if info.expires_in > 0 && info.expires_in <= REFRESH_MARGIN.as_secs() => {
+                    return self.refresh_locked(&mut state);
+                }
+                Ok(_) => {}
+                Err(AuthErr::Status { status: 401, .. }) => return self.refresh_locked(&mut state),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(state.access_token.clone())
+    }
+
+    fn refresh(&self, rejected: &str) -> Result<String, AuthErr> {
+        let mut state = self.lock();
+        // A rotated refresh token can only be used once, so concurrent requests rejected with
+        // the same token share one refresh
+        if state.access_token != rejected {
+            return Ok(state.access_token.clone());
+        }
+        self.refresh_locked(&mut state)
+    }
+}
+
+impl fmt::Debug for RefreshingToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Keeps the tokens and the client secret out of logs
+        f.debug_struct("RefreshingToken")
+            .field("client_id", &self.client_id)
+            .field("endpoints", &self.endpoints)
+            .field("info", &self.info())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::helix::{mock_api, CreateSubscriptionRequest, HelixClient};
+    use crate::subscriptions::DesiredSubscription;
+    use serde_json::json;
+
+    fn validated(expires_in: u64) -> (u16, String) {
+        let body = json!({
+            "client_id": "id", "login": "user", "user_id": "1",
+            "scopes": ["moderator:read:followers"], "expires_in": expires_in
+        });
+        (200, body.to_string())
+    }
+
+    fn refreshed(access_token: &str) -> (u16, String) {
+        let body = json!({
+            "access_token": access_token, "refresh_token": "refresh-2",
+            "expires_in": 14400, "scope": [], "token_type": "bearer"
+        });
+        (200, body.to_string())
+    }
+
+    #[test]
+    fn tokens_are_refreshed_before_they_expire() {
+        let (url, requests) = mock_api::serve(vec![validated(3600), refreshed("new")]);
+        let clock = Arc::new(ManualClock::new());
+        let tokens = RefreshingToken::new("id", "secret", "old", Some("refresh-1"))
+            .unwrap()
+            .endpoints(OAuthEndpoints::at(&format!("{}/oauth2", url)).unwrap())
+            .clock(clock.clone());
+
+        assert_eq!(tokens.access_token().unwrap(), "old");
+        assert_eq!(tokens.access_token().unwrap(), "old");
+        assert_eq!(tokens.info().unwrap().login.as_deref(), Some("user"));
+        clock.advance(Duration::from_secs(56 * 60));
+        assert_eq!(tokens.access_token().unwrap(), "new");
+
+        let validate = requests.recv().unwrap();
+        assert_eq!(validate.path, "/oauth2/validate");
+        assert_eq!(validate.header("Authorization"), Some("OAuth old"));
+        let refresh = requests.recv().unwrap();
+        assert_eq!(refresh.path, "/oauth2/token");
+        assert!(refresh.body.contains("grant_type=refresh_token"));
+        assert!(refresh.body.contains("refresh_token=refresh-1"));
+    }
+
+    #[test]
+    fn helix_retries_once_after_401() {
+        let unauthorized =
+            json!({"error": "Unauthorized", "status": 401, "message": "Invalid OAuth token"});
+        let created = json!({
+            "data": [{
+                "id": "a", "status": "enabled", "type": "stream.online", "version": "1",
+                "condition": {"broadcaster_user_id": "1"}, "created_at": "2023-01-01T00:00:00Z",
+                "transport": {"method": "websocket", "session_id": "session"}, "cost": 0
+            }],
+            "total": 1, "total_cost": 0, "max_total_cost": 10
+        });
+        let (url, requests) = mock_api::serve(vec![
+            validated(3600),
+            (401, unauthorized.to_string()),
+            refreshed("new"),
+            (202, created.to_string()),
+        ]);
+        let tokens = RefreshingToken::new("id", "secret", "old", Some("refresh-1"))
+            .unwrap()
+            .endpoints(OAuthEndpoints::at(&format!("{}/oauth2", url)).unwrap());
+        let client =
+            HelixClient::with_token_provider("id", Arc::new(tokens), Url::parse(&url).unwrap())
+                .unwrap();
+        let desired =
+            DesiredSubscription::new("stream.online", "1", json!({"broadcaster_user_id": "1"}));
+        client
+            .create_subscription(&CreateSubscriptionRequest::websocket(&desired, "session"))
+            .unwrap();
+
+        let requests: Vec<_> = requests.iter().take(4).collect();
+        assert_eq!(requests[1].header("Authorization"), Some("Bearer old"));
+        assert_eq!(requests[2].path, "/oauth2/token");
+        assert_eq!(requests[3].header("Authorization"), Some("Bearer new"));
+    }
+
+    #[test]
+    fn refreshed_tokens_are_saved() {
+        let (url, requests) = mock_api::serve(vec![refreshed("new")]);
+        let dir = std::env::temp_dir().join(format!("eventsub-refresh-{}", std::process::id()));
+        let store = TokenStore::new(dir.join("token.json"));
+        let stored = UserToken {
+            access_token: "old".to_owned(),
+            refresh_token: Some("refresh-1".to_owned()),
+            scopes: vec!["bits:read".to_owned()],
+            expires_at: None,
+        };
+        let tokens = RefreshingToken::from_user_token("id", "", &stored)
+            .unwrap()
+            .endpoints(OAuthEndpoints::at(&format!("{}/oauth2", url)).unwrap())
+            .store(store.clone());
+
+        assert_eq!(tokens.refresh("old").unwrap(), "new");
+        // The rejected token was replaced already
+        assert_eq!(tokens.refresh("old").unwrap(), "new");
+        let saved = store.load().unwrap().unwrap();
+        assert_eq!(saved.access_token, "new");
+        assert_eq!(saved.refresh_token.as_deref(), Some("refresh-2"));
+        assert_eq!(saved.scopes, ["bits:read"]);
+        assert!(saved.expires_at.is_some());
+        assert!(!requests.recv().unwrap().body.contains("client_secret"));
+        store.delete().unwrap();
+        std::fs::remove_dir(dir).unwrap();
+    }
+}
@@ -0,0 +1,429 @@
+//! The OAuth flows a command line application can obtain a user access token with.
+//!
+//! - The `DeviceFlow` shows the user a code to enter on Twitch's activation page, on any device,
+//!   while it polls for the token. It doesn't need a client secret.
+//! - The `AuthorizationCodeFlow` sends the user to Twitch's authorization page in a browser, and
+//!   receives the redirect back on a listener on the local machine.
+
+use crate::auth::{OAuthEndpoints, TokenResponse, UserToken};
+use crate::clock::{Clock, SystemClock};
+use crate::error::AuthErr;
+use crate::helix::{CONNECT_TIMEOUT, REQUEST_TIMEOUT};
+use serde::Deserialize;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// How long a connection to the redirect listener may take to send its request. Browsers open
+/// connections ahead of time, which may never carry a request.
+const REDIRECT_READ_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The code the user enters at the `verification_uri`, to authorize the `DeviceFlow`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeviceCode {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    /// Seconds until the code can no longer be used.
+    pub expires_in: u64,
+    /// Seconds to wait between polling for the token.
+    pub interval: u64,
+}
+
+/// Twitch's device code grant flow, for public clients.
+#[derive(Debug)]
+pub struct DeviceFlow {
+    client_id: String,
+    scopes: Vec<String>,
+    endpoints: OAuthEndpoints,
+    agent: ureq::Agent,
+    clock: Arc<dyn Clock>,
+}
+
+impl DeviceFlow {
+    pub fn new(client_id: &str, scopes: &[&str]) -> Result<DeviceFlow, AuthErr> {
+        Ok(DeviceFlow {
+            client_id: client_id.to_owned(),
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+            endpoints: OAuthEndpoints::default(),
+            agent: agent()?,
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    pub fn endpoints(mut self, endpoints: OAuthEndpoints) -> DeviceFlow {
+        self.endpoints = endpoints;
+        self
+    }
+
+    /// Replaces the `SystemClock`, which the polling interval is waited out on.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> DeviceFlow {
+        self.clock = clock;
+        self
+    }
+
+    /// Requests the code to show to the user.
+    pub fn start(&self) -> Result<DeviceCode, AuthErr> {
+        Ok(self
+            .agent
+            .request_url("POST", &self.endpoints.device)
+            .send_form(&[
+                ("client_id", &self.client_id),
+                ("scopes", &self.scopes.join(" ")),
+            ])?
+            .into_json()?)
+    }
+
+    /// Polls for the token until the user authorized the `code`, or it expired.
+    pub fn poll(&self, code: &DeviceCode) -> Result<UserToken, AuthErr> {
+        let mut interval = Duration::from_secs(code.interval.max(1));
+        let deadline = self.clock.now() + Duration::from_secs(code.expires_in);
+        loop {
+            self.clock.sleep(interval);
+            if self.clock.now() >= deadline {
+                return Err(AuthErr::Denied("the device code expired".to_owned()));
+            }
+            let response = self
+                .agent
+                .request_url("POST", &self.endpoints.token)
+                .send_form(&[
+                    ("client_id", &self.client_id),
+                    ("scopes", &self.scopes.join(" ")),
+                    ("device_code", &code.device_code),
+                    ("grant_type", DEVICE_CODE_GRANT),
+                ]);
+            match response.map_err(AuthErr::from) {
+                Ok(response) => {
+                    let token: TokenResponse = response.into_json()?;
+                    return Ok(token.into_user_token(self.clock.wall()));
+                }
+                Err(AuthErr::Status { message, .. }) if message == "authorization_pending" => {}
+                Err(AuthErr::Status { message, .. }) if message == "slow_down" => {
+                    interval += Duration::from_secs(5);
+                }
+                Err(AuthErr::Status {
+                    status: 400,
+                    message,
+                }) => return Err(AuthErr::Denied(message)),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// Twitch's authorization code grant flow, for confidential clients. The `redirect_uri` has to be
+/// registered with the application, and point at the local machine, e.g.
+/// `http://localhost:3000`.
+#[derive(Debug)]
+pub struct AuthorizationCodeFlow {
+    client_id: String,
+    client_secret: String,
+    redirect_uri: Url,
+    scopes: Vec<String>,
+    endpoints: OAuthEndpoints,
+    agent: ureq::Agent,
+    clock: Arc<dyn Clock>,
+}
+
+impl AuthorizationCodeFlow {
+    pub fn new(
+        client_id: &str,
+        client_secret: &str,
+        redirect_uri: Url,
+        scopes: &[&str],
+    ) -> Result<AuthorizationCodeFlow, AuthErr> {
+        Ok(AuthorizationCodeFlow {
+            client_id: client_id.to_owned(),
+            client_secret: client_secret.to_owned(),
+            redirect_uri,
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+            endpoints: OAuthEndpoints::default(),
+            agent: agent()?,
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    pub fn endpoints(mut self, endpoints: OAuthEndpoints) -> AuthorizationCodeFlow {
+        self.endpoints = endpoints;
+        self
+    }
+
+    /// The page to send the user to. The `state` comes back with the redirect, and should be
+    /// unguessable, e.g. from `random_state`.
+    pub fn authorize_url(&self, state: &str) -> Url {
+        let mut url = self.endpoints.authorize.clone();
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", self.redirect_uri.as_str())
+            .append_pair("scope", &self.scopes.join(" "))
+            .append_pair("state", state);
+        url
+    }
+
+    /// Waits for the redirect to the `redirect_uri`, and exchanges the code it carries for a
+    /// token. Requests to other paths, or without a code or an error, are answered with
+    /// `404 Not Found` or `400 Bad Request` and otherwise ignored, as are connections which don't
+    /// send a request within the `REDIRECT_READ_TIMEOUT`.
+    pub fn listen(&self, state: &str) -> Result<UserToken, AuthErr> {
+        let host = self.redirect_uri.host_str().unwrap_or("localhost");
+        let port = self.redirect_uri.port_or_known_default().unwrap_or(80);
+        let listener = TcpListener::bind((host, port)).map_err(AuthErr::Redirect)?;
+        loop {
+            let (stream, _) = listener.accept().map_err(AuthErr::Redirect)?;
+            stream
+                .set_read_timeout(Some(REDIRECT_READ_TIMEOUT))
+                .map_err(AuthErr::Redirect)?;
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            if reader.read_line(&mut line).is_err() {
+                // Idle or broken, the redirect has to come through another connection
+                continue;
+            }
+            let target = line.split_whitespace().nth(1).unwrap_or("/");
+            let redirect = self
+                .redirect_uri
+                .join(target)
+                .map_err(|err| AuthErr::Denied(err.to_string()))?;
+            let mut stream = reader.into_inner();
+            if redirect.path() != self.redirect_uri.path() {
+                respond(&mut stream, "404 Not Found", "Not found")?;
+                continue;
+            }
+            let param = |name: &str| {
+                redirect
+                    .query_pairs()
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| value.into_owned())
+            };
+            let error = param("error_description").or_else(|| param("error"));
+            let code = param("code");
+            if code.is_none() && error.is_none() {
+                respond(&mut stream, "400 Bad Request", "No authorization code")?;
+                continue;
+            }
+            if param("state").as_deref() != Some(state) {
+                respond(&mut stream, "400 Bad Request", "Invalid state")?;
+                return Err(AuthErr::StateMismatch);
+            }
+            let Some(code) = code else {
+                respond(&mut stream, "400 Bad Request", "Authorization failed")?;
+                return Err(AuthErr::Denied(error.unwrap_or_default()));
+            };
+            let token = self.exchange(&code);
+            match &token {
+                Ok(_) => respond(
+                    &mut stream,
+                    "200 OK",
+                    "Authorized, you can close this page now.",
+                )?,
+                Err(_) => respond(&mut stream, "502 Bad Gateway", "Authorization failed")?,
+            }
+            return token;
+        }
+    }
+
+    /// Exchanges the `code` from the redirect for a token.
+    pub fn exchange(&self, code: &str) -> Result<UserToken, AuthErr> {
+        let token: TokenResponse = self
+            .agent
+            .request_url("POST", &self.endpoints.token)
+            .send_form(&[
+                ("client_id", &self.client_id),
+                ("client_secret", &self.client_secret),
+                ("code", code),
+                ("grant_type", "authorization_code"),
+                ("redirect_uri", self.redirect_uri.as_str()),
+            ])?
+            .into_json()?;
+        Ok(token.into_user_token(self.clock.wall()))
+    }
+}
+
+/// A value for the `state` of an authorization, drawn from the operating system's random number
+/// generator.
+pub fn random_state() -> Result<String, AuthErr> {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).map_err(AuthErr::Random)?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+fn respond(stream: &mut impl Write, status: &str, body: &str) -> Result<(), AuthErr> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+    .map_err(AuthErr::Redirect)
+}
+
+/// An agent for Twitch's OAuth endpoints, with the same timeouts as the Helix client's.
+pub(crate) fn agent() -> Result<ureq::Agent, AuthErr> {
+    Ok(ureq::AgentBuilder::new()
+        .tls_connector(Arc::new(native_tls::TlsConnector::new()?))
+        .timeout_connect(CONNECT_TIMEOUT)
+        .timeout(REQUEST_TIMEOUT)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::helix::mock_api;
+    use serde_json::json;
+    use std::thread;
+
+    fn issued(access_token: &str) -> (u16, String) {
+        let body = json!({
+            "access_token": access_token, "refresh_token": "refresh",
+            "expires_in": 14400, "scope": ["moderator:read:followers"], "token_type": "bearer"
+        });
+        (200, body.to_string())
+    }
+
+    #[test]
+    fn device_flow_polls_until_authorized() {
+        let device = json!({
+            "device_code": "device", "user_code": "ABCDEFGH", "expires_in": 1800,
+            "interval": 5, "verification_uri": "https://www.twitch.tv/activate?device-code=ABCDEFGH"
+        });
+        let pending = json!({"status": 400, "message": "authorization_pending"});
+        let (url, requests) = mock_api::serve(vec![
+            (200, device.to_string()),
+            (400, pending.to_string()),
+            issued("user-token"),
+        ]);
+        let clock = Arc::new(ManualClock::new());
+        let flow = DeviceFlow::new("id", &["moderator:read:followers"])
+            .unwrap()
+            .endpoints(OAuthEndpoints::at(&url).unwrap())
+            .clock(clock.clone());
+
+        let code = flow.start().unwrap();
+        assert_eq!(code.user_code, "ABCDEFGH");
+        let token = flow.poll(&code).unwrap();
+        assert_eq!(token.access_token, "user-token");
+        assert_eq!(token.scopes, vec!["moderator:read:followers"]);
+        assert_eq!(clock.elapsed(), Duration::from_secs(10));
+
+        let requests: Vec<_> = requests.iter().take(3).collect();
+        assert_eq!(requests[0].path, "/device");
+        assert!(requests[0]
+            .body
+            .contains("scopes=moderator%3Aread%3Afollowers"));
+        assert_eq!(requests[2].path, "/token");
+        assert!(requests[2].body.contains("device_code=device"));
+    }
+
+    #[test]
+    fn authorization_code_arrives_through_the_redirect() {
+        let (url, requests) = mock_api::serve(vec![issued("user-token")]);
+        // Nothing listens on the port of a listener that was dropped right away
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let redirect_uri = Url::parse(&format!("http://127.0.0.1:{}/callback", port)).unwrap();
+        let flow = AuthorizationCodeFlow::new("id", "secret", redirect_uri.clone(), &["bits:read"])
+            .unwrap()
+            .endpoints(OAuthEndpoints::at(&url).unwrap());
+        let state = random_state().unwrap();
+        assert_eq!(state.len(), 32);
+        assert_ne!(state, random_state().unwrap());
+        let authorize = flow.authorize_url(&state);
+        assert!(authorize.as_str().contains("response_type=code"));
+
+        let listener = {
+            let state = state.clone();
+            thread::spawn(move || flow.listen(&state))
+        };
+        let redirect = format!(
+            "{}?code=the-code&scope=bits%3Aread&state={}",
+            redirect_uri, state
+        );
+        let page = loop {
+            match ureq::get(&redirect).call() {
+                Ok(response) => break response.into_string().unwrap(),
+                // The listener might not be bound yet
+                Err(ureq::Error::Transport(_)) => thread::sleep(Duration::from_millis(10)),
+                Err(err) => panic!("redirect failed: {}", err),
+            }
+        };
+        assert!(page.starts_with("Authorized"));
+        assert_eq!(listener.join().unwrap().unwrap().access_token, "user-token");
+        let exchange = requests.recv().unwrap();
+        assert!(exchange.body.contains("code=the-code"));
+        assert!(exchange.body.contains("grant_type=authorization_code"));
+    }
+
+    #[test]
+    fn failed_exchange_is_answered_with_an_error_status() {
+        let invalid = json!({"status": 400, "message": "Invalid authorization code"});
+        let (url, _requests) = mock_api::serve(vec![(400, invalid.to_string())]);
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let redirect_uri = Url::parse(&format!("http://127.0.0.1:{}/callback", port)).unwrap();
+        let flow = AuthorizationCodeFlow::new("id", "secret", redirect_uri.clone(), &[])
+            .unwrap()
+            .endpoints(OAuthEndpoints::at(&url).unwrap());
+        let listener = thread::spawn(move || flow.listen("state"));
+        let redirect = format!("{}?code=expired&state=state", redirect_uri);
+        let status = loop {
+            match ureq::get(&redirect).call() {
+                Ok(response) => panic!("redirect succeeded: {}", response.status()),
+                Err(ureq::Error::Status(status, _)) => break status,
+                Err(ureq::Error::Transport(_)) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        assert_eq!(status, 502);
+        assert!(matches!(
+            listener.join().unwrap(),
+            Err(AuthErr::Status { status: 400, .. })
+        ));
+    }
+
+    #[test]
+    fn stray_connections_dont_block_the_redirect() {
+        let (url, _requests) = mock_api::serve(vec![issued("user-token")]);
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let redirect_uri = Url::parse(&format!("http://127.0.0.1:{}/callback", port)).unwrap();
+        let flow = AuthorizationCodeFlow::new("id", "secret", redirect_uri.clone(), &[])
+            .unwrap()
+            .endpoints(OAuthEndpoints::at(&url).unwrap());
+        let listener = thread::spawn(move || flow.listen("state"));
+
+        // Like a browser's preconnect, which never sends a request
+        let _idle = loop {
+            match std::net::TcpStream::connect(("127.0.0.1", port)) {
+                Ok(stream) => break stream,
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        match ureq::get(redirect_uri.as_str()).call() {
+            Err(ureq::Error::Status(status, _)) => assert_eq!(status, 400),
+            other => panic!("request without a code was answered with {:?}", other),
+        }
+        let page = ureq::get(&format!("{}?code=the-code&state=state", redirect_uri))
+            .call()
+            .unwrap()
+            .into_string()
+            .unwrap();
+        assert!(page.starts_with("Authorized"));
+        assert_eq!(listener.join().unwrap().unwrap().access_token, "user-token");
+    }
+}
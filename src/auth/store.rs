@@ -0,0 +1,114 @@
+//! Keeps a `UserToken` between runs, in a JSON file only its owner can read.
+
+use crate::auth::UserToken;
+use crate::error::AuthErr;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// The file a token is stored in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenStore {
+    pub path: PathBuf,
+}
+
+impl TokenStore {
+    pub fn new(path: impl Into<PathBuf>) -> TokenStore {
+        TokenStore { path: path.into() }
+    }
+
+    /// `eventsub_websocket/token.json` in the user's configuration directory, which is
+    /// `$XDG_CONFIG_HOME`, `$HOME/.config` or `%APPDATA%`, whichever is set first.
+    pub fn default_location() -> Option<TokenStore> {
+        let config = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+            .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))?;
+        Some(TokenStore::new(
+            config.join("eventsub_websocket").join("token.json"),
+        ))
+    }
+
+    /// The stored token, or `None` if none was stored yet.
+    pub fn load(&self) -> Result<Option<UserToken>, AuthErr> {
+        match fs::read(&self.path) {
+            Ok(contents) => Ok(Some(
+                serde_json::from_slice(&contents).map_err(|err| AuthErr::Store(err.into()))?,
+            )),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(AuthErr::Store(err)),
+        }
+    }
+
+    /// Replaces the stored token. The token is written to a new file, which is only readable by
+    /// the current user on Unix, and then moved into place, so a crash never leaves a partially
+    /// written token behind.
+    pub fn save(&self, token: &UserToken) -> Result<(), AuthErr> {
+        self.write(token).map_err(AuthErr::Store)
+    }
+
+    pub fn delete(&self) -> Result<(), AuthErr> {
+        match fs::remove_file(&self.path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(AuthErr::Store(err)),
+            _ => Ok(()),
+        }
+    }
+
+    fn write(&self, token: &UserToken) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let partial = self.path.with_extension("json.partial");
+        // A partial file left behind by a crash may have been created with other permissions,
+        // which opening it again wouldn't change
+        match fs::remove_file(&partial) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&partial)?;
+        file.write_all(&serde_json::to_vec_pretty(token)?)?;
+        file.sync_all()?;
+        fs::rename(&partial, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_survive_a_round_trip_privately() {
+        let dir = std::env::temp_dir().join(format!("eventsub-token-{}", std::process::id()));
+        let store = TokenStore::new(dir.join("token.json"));
+        assert_eq!(store.load().unwrap(), None);
+        // Left behind by a crash, readable by everyone
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(store.path.with_extension("json.partial"), "{").unwrap();
+
+        let token = UserToken {
+            access_token: "access".to_owned(),
+            refresh_token: Some("refresh".to_owned()),
+            scopes: vec!["bits:read".to_owned()],
+            expires_at: None,
+        };
+        store.save(&token).unwrap();
+        assert_eq!(store.load().unwrap(), Some(token));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&store.path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        store.delete().unwrap();
+        assert_eq!(store.load().unwrap(), None);
+        fs::remove_dir(dir).unwrap();
+    }
+}
@@ -0,0 +1,309 @@
+//! Keeps count of what subscriptions cost, and how many each session holds, so a subscription
+//! that would exceed a limit can be held back instead of being rejected by Helix. Helix reports
+//! the totals of the client ID with every subscription response, and each subscription's cost
+//! and session. Notifications carry the same details of their subscription, so subscriptions
+//! created elsewhere are counted as soon as they deliver, and revocations stop counting them.
+//!
+//! Twitch drops every subscription of a session once the session is gone, so a session is
+//! forgotten when a `Welcome` replaces it with a new one, or when Helix lists one of its
+//! subscriptions as disconnected.
+
+use crate::error::BudgetErr;
+use crate::helix::{Subscription, SubscriptionCosts, SubscriptionList, SubscriptionStatus};
+use crate::pool::SUBSCRIPTIONS_PER_CONNECTION;
+use crate::subscriptions::DesiredSubscription;
+use crate::types::{SubscriptionPayload, TwitchMessage};
+use std::collections::BTreeMap;
+
+/// An enabled subscription, as far as the `Budget` knows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackedSubscription {
+    pub subscription: DesiredSubscription,
+    pub session_id: String,
+    pub cost: u64,
+}
+
+/// The limits as of the last Helix response, and the subscriptions seen since.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Budget {
+    costs: Option<SubscriptionCosts>,
+    /// The enabled WebSocket subscriptions, by ID.
+    subscriptions: BTreeMap<String, TrackedSubscription>,
+}
+
+/// A snapshot of the `Budget`, for schedulers deciding where and whether to subscribe.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BudgetView {
+    /// The totals of the client ID, once Helix reported them.
+    pub costs: Option<SubscriptionCosts>,
+    /// The number of enabled subscriptions on each session.
+    pub sessions: BTreeMap<String, usize>,
+    pub per_session_limit: usize,
+}
+
+impl BudgetView {
+    /// The cost that can still be spent, if known.
+    pub fn remaining_cost(&self) -> Option<u64> {
+        self.costs.map(|costs| costs.remaining())
+    }
+
+    /// How many more subscriptions the session can hold.
+    pub fn session_room(&self, session_id: &str) -> usize {
+        let used = self.sessions.get(session_id).copied().unwrap_or(0);
+        self.per_session_limit.saturating_sub(used)
+    }
+}
+
+impl Budget {
+    pub fn costs(&self) -> Option<SubscriptionCosts> {
+        self.costs
+    }
+
+    /// Takes the totals, and the subscriptions, from a response to creating or listing
+    /// subscriptions.
+    pub fn record(&mut self, list: &SubscriptionList) {
+        self.costs = Some(SubscriptionCosts {
+            total: list.total,
+            total_cost: list.total_cost,
+            max_total_cost: list.max_total_cost,
+        });
+        for subscription in &list.data {
+            self.track(subscription);
+        }
+    }
+
+    /// Stops counting a deleted subscription, and takes it off the totals until Helix reports
+    /// them again.
+    pub fn record_deleted(&mut self, id: &str) {
+        let cost = self
+            .subscriptions
+            .remove(id)
+            .map_or(0, |tracked| tracked.cost);
+        if let Some(costs) = &mut self.costs {
+            costs.total = costs.total.saturating_sub(1);
+            costs.total_cost = costs.total_cost.saturating_sub(cost);
+        }
+    }
+
+    /// Stops counting the subscriptions of the session with `session_id`.
+    pub fn forget_session(&mut self, session_id: &str) {
+        self.subscriptions
+            .retain(|_, tracked| tracked.session_id != session_id);
+    }
+
+    /// Counts the subscription of a notification, and stops counting a revoked one. A `Welcome`
+    /// to a new session forgets the session with `session_id`, on which the message arrived.
+    pub fn observe(&mut self, session_id: &str, message: &TwitchMessage) {
+        match message {
+            TwitchMessage::Welcome(msg)
+                if !session_id.is_empty() && msg.payload.session.id != session_id =>
+            {
+                self.forget_session(session_id);
+            }
+            TwitchMessage::Notification(msg) => {
+                let subscription = &msg.payload.subscription;
+                if let Some(session_id) = session_of(subscription) {
+                    self.subscriptions
+                        .entry(subscription.id.clone())
+                        .or_insert_with(|| TrackedSubscription {
+                            subscription: subscription.to_desired(),
+                            session_id,
+                            cost: subscription.cost.as_u64().unwrap_or(0),
+                        });
+                }
+            }
+            TwitchMessage::Revocation(msg) => {
+                self.subscriptions.remove(&msg.payload.subscription.id);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn view(&self) -> BudgetView {
+        let mut sessions = BTreeMap::new();
+        for tracked in self.subscriptions.values() {
+            *sessions.entry(tracked.session_id.clone()).or_insert(0) += 1;
+        }
+        BudgetView {
+            costs: self.costs,
+            sessions,
+            per_session_limit: SUBSCRIPTIONS_PER_CONNECTION,
+        }
+    }
+
+    /// Predicts whether creating the `subscription` on the session, at the expected `cost`, would
+    /// be rejected: with `409 Conflict` if it already exists there, or `429 Too Many Requests` if
+    /// the session is full or the cost exceeds what is left.
+    pub fn check(
+        &self,
+        subscription: &DesiredSubscription,
+        session_id: &str,
+        cost: u64,
+    ) -> Result<(), BudgetErr> {
+        let on_session = self
+            .subscriptions
+            .iter()
+            .filter(|(_, tracked)| tracked.session_id == session_id);
+        let mut count = 0;
+        for (id, tracked) in on_session {
+            if tracked.subscription == *subscription {
+                return Err(BudgetErr::Duplicate(id.clone()));
+            }
+            count += 1;
+        }
+        if count >= SUBSCRIPTIONS_PER_CONNECTION {
+            return Err(BudgetErr::SessionFull {
+                session_id: session_id.to_owned(),
+                limit: SUBSCRIPTIONS_PER_CONNECTION,
+            });
+        }
+        match self.costs {
+            Some(costs) if cost > costs.remaining() => Err(BudgetErr::CostExceeded {
+                cost,
+                remaining: costs.remaining(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn track(&mut self, subscription: &Subscription) {
+        let session_id = match (&subscription.transport.session_id, subscription.status) {
+            (Some(session_id), SubscriptionStatus::Enabled) => session_id.clone(),
+            (Some(session_id), status) if is_disconnected(status) => {
+                self.forget_session(session_id);
+                return;
+            }
+            _ => {
+                self.subscriptions.remove(&subscription.id);
+                return;
+            }
+        };
+        self.subscriptions.insert(
+            subscription.id.clone(),
+            TrackedSubscription {
+                subscription: subscription.to_desired(),
+                session_id,
+                cost: subscription.cost,
+            },
+        );
+    }
+}
+
+/// Whether the subscription's session is gone.
+fn is_disconnected(status: SubscriptionStatus) -> bool {
+    matches!(
+        status,
+        SubscriptionStatus::WebsocketDisconnected
+            | SubscriptionStatus::WebsocketFailedPingPong
+            | SubscriptionStatus::WebsocketReceivedInboundTraffic
+            | SubscriptionStatus::WebsocketConnectionUnused
+            | SubscriptionStatus::WebsocketInternalError
+            | SubscriptionStatus::WebsocketNetworkTimeout
+            | SubscriptionStatus::WebsocketNetworkError
+    )
+}
+
+fn session_of(subscription: &SubscriptionPayload) -> Option<String> {
+    subscription.transport["session_id"]
+        .as_str()
+        .map(str::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+    use serde_json::json;
+
+    fn list(subscriptions: &[(&str, &str, u64)], total_cost: u64) -> SubscriptionList {
+        let data = subscriptions
+            .iter()
+            .map(|(id, session_id, cost)| {
+                json!({
+                    "id": id, "status": "enabled", "type": "stream.online", "version": "1",
+                    "condition": {"broadcaster_user_id": id},
+                    "created_at": "2023-01-01T00:00:00Z",
+                    "transport": {"method": "websocket", "session_id": session_id},
+                    "cost": cost
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::from_value(json!({
+            "data": data,
+            "total": subscriptions.len(),
+            "total_cost": total_cost,
+            "max_total_cost": 3
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn predicts_conflicts_and_exhausted_cost() {
+        let mut budget = Budget::default();
+        budget.record(&list(&[("1", "session", 1), ("2", "session", 1)], 2));
+        let view = budget.view();
+        assert_eq!(view.remaining_cost(), Some(1));
+        assert_eq!(
+            view.session_room("session"),
+            SUBSCRIPTIONS_PER_CONNECTION - 2
+        );
+
+        let existing =
+            DesiredSubscription::new("stream.online", "1", json!({"broadcaster_user_id": "1"}));
+        assert_eq!(
+            budget.check(&existing, "session", 1),
+            Err(BudgetErr::Duplicate("1".to_owned()))
+        );
+        let new =
+            DesiredSubscription::new("stream.online", "1", json!({"broadcaster_user_id": "3"}));
+        assert_eq!(budget.check(&new, "session", 1), Ok(()));
+        assert_eq!(
+            budget.check(&new, "session", 2),
+            Err(BudgetErr::CostExceeded {
+                cost: 2,
+                remaining: 1
+            })
+        );
+
+        budget.record_deleted("2");
+        assert_eq!(budget.view().remaining_cost(), Some(2));
+        assert_eq!(budget.view().sessions["session"], 1);
+    }
+
+    #[test]
+    fn notifications_and_revocations_update_session_counts() {
+        let mut budget = Budget::default();
+        let notification =
+            crate::parse_message::<TwitchMessage>(&mock::notification("stream.online", json!({})))
+                .unwrap();
+        budget.observe("session", &notification);
+        assert_eq!(budget.view().sessions["session"], 1);
+
+        let revocation = crate::parse_message::<TwitchMessage>(&mock::revocation(
+            "stream.online",
+            "authorization_revoked",
+        ))
+        .unwrap();
+        budget.observe("session", &revocation);
+        assert!(budget.view().sessions.is_empty());
+    }
+
+    #[test]
+    fn replaced_and_disconnected_sessions_are_forgotten() {
+        let mut budget = Budget::default();
+        budget.record(&list(&[("1", "old", 1), ("2", "other", 1)], 2));
+        let welcome = crate::parse_message::<TwitchMessage>(&mock::welcome("new", 10)).unwrap();
+        // A reconnect keeps the session
+        budget.observe(
+            "other",
+            &crate::parse_message(&mock::welcome("other", 10)).unwrap(),
+        );
+        budget.observe("old", &welcome);
+        assert_eq!(budget.view().sessions.keys().collect::<Vec<_>>(), ["other"]);
+
+        let mut disconnected = list(&[("3", "other", 0)], 2);
+        disconnected.data[0].status = SubscriptionStatus::WebsocketDisconnected;
+        budget.record(&disconnected);
+        assert!(budget.view().sessions.is_empty());
+    }
+}
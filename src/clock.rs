@@ -0,0 +1,99 @@
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The source of time for everything the session times: reconnect backoff, the keepalive
+/// watchdog, migrations, latency measurements and the expiry of handled message IDs. Replacing
+/// the `SystemClock` with a `ManualClock` lets tests run through hours of waiting in an instant.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// The monotonic time, to measure durations with.
+    fn now(&self) -> Instant;
+    /// The wall-clock time, to compare with Twitch's message timestamps.
+    fn wall(&self) -> DateTime<Utc>;
+    fn sleep(&self, duration: Duration);
+}
+
+/// The real time of the system.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// A clock which only moves forward when told to. Sleeping returns right away, after advancing
+/// the clock by the duration slept.
+#[derive(Debug)]
+pub struct ManualClock {
+    start: Instant,
+    wall_start: DateTime<Utc>,
+    elapsed: Mutex<Duration>,
+}
+
+impl ManualClock {
+    /// Starts at the current time of the system.
+    pub fn new() -> ManualClock {
+        ManualClock {
+            start: Instant::now(),
+            wall_start: Utc::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+
+    /// How far the clock was moved since it was created.
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn wall(&self) -> DateTime<Utc> {
+        self.wall_start + chrono::Duration::from_std(self.elapsed()).unwrap_or_default()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock_moves_only_when_told() {
+        let clock = ManualClock::new();
+        let (now, wall) = (clock.now(), clock.wall());
+        clock.sleep(Duration::from_secs(3600));
+        assert_eq!(clock.now() - now, Duration::from_secs(3600));
+        assert_eq!(clock.wall() - wall, chrono::Duration::hours(1));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(clock.elapsed(), Duration::from_secs(3601));
+    }
+}
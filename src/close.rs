@@ -0,0 +1,122 @@
+use std::fmt;
+
+/// The reason Twitch gave for closing the connection, as documented for the close codes of the
+/// EventSub WebSocket server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// 4000: Twitch ran into an internal error.
+    InternalServerError,
+    /// 4001: The client sent something other than a pong to the server.
+    ClientSentInboundTraffic,
+    /// 4002: The client did not answer a ping in time.
+    ClientFailedPingPong,
+    /// 4003: No subscription was created within the time limit after the `Welcome` message.
+    ConnectionUnused,
+    /// 4004: The client did not connect to the url of a `Reconnect` message in time.
+    ReconnectGraceTimeExpired,
+    /// 4005: The connection timed out.
+    NetworkTimeout,
+    /// 4006: The connection failed.
+    NetworkError,
+    /// 4007: The reconnect url was invalid.
+    InvalidReconnect,
+    /// Any other close code, including a missing one (1005).
+    Other(u16),
+}
+
+/// How the client should react to the connection being closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retry {
+    /// Connect again right away.
+    Immediately,
+    /// Connect again after waiting, doubling the wait time on every failed attempt.
+    WithBackoff,
+    /// Reconnecting would lead to the same result, so the error is returned to the caller.
+    Never,
+}
+
+impl CloseReason {
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::InternalServerError => 4000,
+            Self::ClientSentInboundTraffic => 4001,
+            Self::ClientFailedPingPong => 4002,
+            Self::ConnectionUnused => 4003,
+            Self::ReconnectGraceTimeExpired => 4004,
+            Self::NetworkTimeout => 4005,
+            Self::NetworkError => 4006,
+            Self::InvalidReconnect => 4007,
+            Self::Other(code) => *code,
+        }
+    }
+
+    /// Classifies the reason to decide whether a new connection should be attempted. The codes
+    /// caused by the client's behavior would only repeat themselves, while problems with the
+    /// connection or on Twitch's side are worth retrying.
+    pub fn retry(&self) -> Retry {
+        match self {
+            Self::ClientSentInboundTraffic | Self::ConnectionUnused => Retry::Never,
+            Self::ClientFailedPingPong
+            | Self::ReconnectGraceTimeExpired
+            | Self::NetworkTimeout
+            | Self::InvalidReconnect => Retry::Immediately,
+            Self::InternalServerError | Self::NetworkError | Self::Other(_) => Retry::WithBackoff,
+        }
+    }
+}
+
+impl From<u16> for CloseReason {
+    fn from(code: u16) -> Self {
+        match code {
+            4000 => Self::InternalServerError,
+            4001 => Self::ClientSentInboundTraffic,
+            4002 => Self::ClientFailedPingPong,
+            4003 => Self::ConnectionUnused,
+            4004 => Self::ReconnectGraceTimeExpired,
+            4005 => Self::NetworkTimeout,
+            4006 => Self::NetworkError,
+            4007 => Self::InvalidReconnect,
+            code => Self::Other(code),
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            Self::InternalServerError => "internal server error",
+            Self::ClientSentInboundTraffic => "client sent inbound traffic",
+            Self::ClientFailedPingPong => "client failed ping-pong",
+            Self::ConnectionUnused => "connection unused",
+            Self::ReconnectGraceTimeExpired => "reconnect grace time expired",
+            Self::NetworkTimeout => "network timeout",
+            Self::NetworkError => "network error",
+            Self::InvalidReconnect => "invalid reconnect",
+            Self::Other(_) => "closed",
+        };
+        write!(f, "{} ({})", description, self.code())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn twitch_close_codes_round_trip() {
+        for code in 4000..=4007 {
+            let reason = CloseReason::from(code);
+            assert!(!matches!(reason, CloseReason::Other(_)));
+            assert_eq!(reason.code(), code);
+        }
+        assert_eq!(CloseReason::from(1000), CloseReason::Other(1000));
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        assert_eq!(CloseReason::from(4001).retry(), Retry::Never);
+        assert_eq!(CloseReason::from(4003).retry(), Retry::Never);
+        assert_eq!(CloseReason::from(4004).retry(), Retry::Immediately);
+        assert_eq!(CloseReason::from(4000).retry(), Retry::WithBackoff);
+    }
+}
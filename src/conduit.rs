@@ -0,0 +1,340 @@
+//! Runs the WebSocket shards of an EventSub conduit. Each shard is a regular `Session`, whose
+//! message loop runs on its own thread, and whose messages all end up on one shared channel.
+//! Whenever a shard's session receives a new session ID, from its first `Welcome` message or
+//! after it reconnected, the shard is pointed at the new session through the `ShardAssigner`.
+//!
+//! Conduits themselves are created, resized and deleted through the `HelixClient`, as are the
+//! subscriptions delivered to them, see `CreateSubscriptionRequest::conduit`.
+
+use crate::error::{EventSubErr, HelixErr, SubscriptionErr};
+use crate::helix::{HelixClient, Shard, ShardStatus};
+use crate::types::{Session, TwitchMessage};
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Points a conduit's shard at a WebSocket session. This is implemented by the Helix client, but
+/// can be replaced, e.g. to route the requests through an application's own API layer.
+pub trait ShardAssigner: Send + Sync {
+    fn assign_shard(
+        &self,
+        conduit_id: &str,
+        shard_id: &str,
+        session_id: &str,
+    ) -> Result<(), SubscriptionErr>;
+}
+
+/// What is known about a shard, from assigning it and from Helix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardState {
+    pub id: String,
+    /// The session the shard was last assigned to.
+    pub session_id: Option<String>,
+    pub status: ShardStatus,
+    /// Why the shard couldn't be assigned to its current session, if it couldn't.
+    pub error: Option<String>,
+}
+
+/// The shards of a conduit run by this process.
+pub struct ConduitShards {
+    pub conduit_id: String,
+    assigner: Arc<dyn ShardAssigner>,
+    forwarder: Sender<TwitchMessage>,
+    states: Arc<Mutex<Vec<ShardState>>>,
+    sessions: Vec<Arc<Mutex<Session>>>,
+    listeners: Vec<JoinHandle<Result<(), EventSubErr>>>,
+}
+
+impl ConduitShards {
+    /// Returns the shards along with the receiver of the messages of every shard.
+    pub fn new(
+        conduit_id: &str,
+        assigner: impl ShardAssigner + 'static,
+    ) -> (ConduitShards, Receiver<TwitchMessage>) {
+        let (forwarder, messages) = mpsc::channel();
+        let shards = ConduitShards {
+            conduit_id: conduit_id.to_owned(),
+            assigner: Arc::new(assigner),
+            forwarder,
+            states: Arc::new(Mutex::new(vec![])),
+            sessions: vec![],
+            listeners: vec![],
+        };
+        (shards, messages)
+    }
+
+    /// Runs the `session`, such as one opened by `get_session`, as the next shard, and returns
+    /// the shard's ID. The shard is assigned as soon as the session is welcomed, from within the
+    /// `on_welcome` callback, after any callback which was already set. Failing to assign it is
+    /// recorded in the shard's `ShardState`, and doesn't end the session; see `assign`.
+    pub fn add_shard(&mut self, session: Arc<Mutex<Session>>) -> Result<String, EventSubErr> {
+        let shard_id = self.sessions.len().to_string();
+        let index = self.sessions.len();
+        lock(&self.states).push(ShardState {
+            id: shard_id.clone(),
+            session_id: None,
+            status: ShardStatus::WebsocketDisconnected,
+            error: None,
+        });
+
+        let conduit_id = self.conduit_id.clone();
+        let assigner = Arc::clone(&self.assigner);
+        let states = Arc::clone(&self.states);
+        {
+            let mut session = session.lock()?;
+            let mut previous = session.on_welcome.take();
+            session.on_welcome = Some(Box::new(move |session_id| {
+                if let Some(previous) = &mut previous {
+                    previous(session_id)?;
+                }
+                lock(&states)[index].session_id = Some(session_id.to_owned());
+                // The error is kept in the shard's state, for `shards` and `assign`
+                let _ = assign(&*assigner, &conduit_id, &states, index, session_id);
+                Ok(())
+            }));
+        }
+
+        let forwarder = self.forwarder.clone();
+        let states = Arc::clone(&self.states);
+        let listener_session = Arc::clone(&session);
+        let listener = thread::Builder::new()
+            .name(format!("eventsub-shard-{}", shard_id))
+            .spawn(move || {
+                let result = crate::create_message_processor(listener_session, &forwarder);
+                lock(&states)[index].status = ShardStatus::WebsocketDisconnected;
+                result
+            })?;
+        self.sessions.push(session);
+        self.listeners.push(listener);
+        Ok(shard_id)
+    }
+
+    /// Assigns the shard with the given ID to its current session again, e.g. after a transient
+    /// Helix error left it unassigned. Does nothing for a shard that wasn't welcomed yet.
+    pub fn assign(&self, shard_id: &str) -> Result<(), SubscriptionErr> {
+        let Some(index) = lock(&self.states)
+            .iter()
+            .position(|state| state.id == shard_id)
+        else {
+            return Err(SubscriptionErr::Rejected(format!(
+                "unknown shard: {}",
+                shard_id
+            )));
+        };
+        let Some(session_id) = lock(&self.states)[index].session_id.clone() else {
+            return Ok(());
+        };
+        assign(
+            &*self.assigner,
+            &self.conduit_id,
+            &self.states,
+            index,
+            &session_id,
+        )
+    }
+
+    /// The session running the shard with the given ID.
+    pub fn session(&self, shard_id: &str) -> Option<&Arc<Mutex<Session>>> {
+        shard_id
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| self.sessions.get(index))
+    }
+
+    pub fn shards(&self) -> Vec<ShardState> {
+        lock(&self.states).clone()
+    }
+
+    /// Takes over the statuses Helix reported for the shards run here.
+    pub fn update_statuses(&self, shards: &[Shard]) {
+        let mut states = lock(&self.states);
+        for shard in shards {
+            if let Some(state) = states.iter_mut().find(|state| state.id == shard.id) {
+                state.status = shard.status;
+            }
+        }
+    }
+
+    /// Fetches the statuses of the conduit's shards from Helix.
+    pub fn refresh(&self, helix: &HelixClient) -> Result<(), HelixErr> {
+        let shards = helix.list_shards(&self.conduit_id, None)?;
+        self.update_statuses(&shards);
+        Ok(())
+    }
+
+    /// Waits for the message loops of all shards to end, and returns their results in the order
+    /// of the shards.
+    pub fn join(self) -> Vec<Result<(), EventSubErr>> {
+        self.listeners
+            .into_iter()
+            .map(|listener| {
+                listener
+                    .join()
+                    .unwrap_or_else(|_| Err(EventSubErr::Poison("shard panicked".to_owned())))
+            })
+            .collect()
+    }
+}
+
+impl fmt::Debug for ConduitShards {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConduitShards")
+            .field("conduit_id", &self.conduit_id)
+            .field("shards", &self.shards())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Assigns the shard at `index` to the session, and records the outcome in its `ShardState`. The
+/// states are unlocked during the Helix request.
+fn assign(
+    assigner: &dyn ShardAssigner,
+    conduit_id: &str,
+    states: &Mutex<Vec<ShardState>>,
+    index: usize,
+    session_id: &str,
+) -> Result<(), SubscriptionErr> {
+    let assigned = assigner.assign_shard(conduit_id, &index.to_string(), session_id);
+    let state = &mut lock(states)[index];
+    match &assigned {
+        Ok(()) => {
+            state.status = ShardStatus::Enabled;
+            state.error = None;
+        }
+        Err(err) => {
+            state.status = ShardStatus::WebsocketDisconnected;
+            state.error = Some(err.to_string());
+        }
+    }
+    assigned
+}
+
+fn lock(states: &Mutex<Vec<ShardState>>) -> std::sync::MutexGuard<'_, Vec<ShardState>> {
+    states.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::get_session;
+    use crate::mock::{self, MockServer, Step};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[derive(Clone, Default)]
+    struct FakeAssigner {
+        assigned: Arc<Mutex<Vec<(String, String)>>>,
+        failing: Arc<AtomicBool>,
+    }
+
+    impl ShardAssigner for FakeAssigner {
+        fn assign_shard(
+            &self,
+            _conduit_id: &str,
+            shard_id: &str,
+            session_id: &str,
+        ) -> Result<(), SubscriptionErr> {
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(SubscriptionErr::Rejected("Helix unavailable".to_owned()));
+            }
+            self.assigned
+                .lock()
+                .unwrap()
+                .push((shard_id.to_owned(), session_id.to_owned()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn shards_feed_one_stream_and_are_reassigned_after_reconnect() {
+        let first = MockServer::start(vec![
+            vec![
+                Step::Send(mock::welcome("first", 10)),
+                Step::DropMidFrame(mock::keepalive()),
+            ],
+            vec![
+                Step::Send(mock::welcome("first-again", 10)),
+                Step::Send(mock::notification("channel.follow", json!({"n": 1}))),
+            ],
+        ]);
+        let second = MockServer::start(vec![vec![
+            Step::Send(mock::welcome("second", 10)),
+            Step::Send(mock::notification("channel.follow", json!({"n": 2}))),
+        ]]);
+        let assigner = FakeAssigner::default();
+        let (mut shards, messages) = ConduitShards::new("conduit", assigner.clone());
+        assert_eq!(
+            shards.add_shard(get_session(first.url()).unwrap()).unwrap(),
+            "0"
+        );
+        assert_eq!(
+            shards
+                .add_shard(get_session(second.url()).unwrap())
+                .unwrap(),
+            "1"
+        );
+
+        let notifications: Vec<TwitchMessage> = messages
+            .iter()
+            .filter(|msg| matches!(msg, TwitchMessage::Notification(_)))
+            .take(2)
+            .collect();
+        assert_eq!(notifications.len(), 2);
+
+        let mut assigned = assigner.assigned.lock().unwrap().clone();
+        assigned.sort();
+        let expected = [("0", "first"), ("0", "first-again"), ("1", "second")];
+        assert_eq!(
+            assigned,
+            expected.map(|(shard, session)| (shard.to_owned(), session.to_owned()))
+        );
+        let states = shards.shards();
+        assert_eq!(states[0].session_id.as_deref(), Some("first-again"));
+        assert!(states
+            .iter()
+            .all(|state| state.status == ShardStatus::Enabled));
+
+        shards.update_statuses(&[Shard {
+            id: "1".to_owned(),
+            status: ShardStatus::WebsocketFailedPingPong,
+            transport: Default::default(),
+        }]);
+        assert_eq!(
+            shards.shards()[1].status,
+            ShardStatus::WebsocketFailedPingPong
+        );
+    }
+
+    #[test]
+    fn failed_assignment_keeps_the_shard_running() {
+        let server = MockServer::start(vec![vec![
+            Step::Send(mock::welcome("session", 10)),
+            Step::Send(mock::notification("channel.follow", json!({}))),
+            Step::Wait(std::time::Duration::from_secs(1)),
+        ]]);
+        let assigner = FakeAssigner::default();
+        assigner.failing.store(true, Ordering::SeqCst);
+        let (mut shards, messages) = ConduitShards::new("conduit", assigner.clone());
+        shards
+            .add_shard(get_session(server.url()).unwrap())
+            .unwrap();
+
+        // The notification still arrives after the welcome
+        assert!(messages
+            .iter()
+            .any(|msg| matches!(msg, TwitchMessage::Notification(_))));
+        let state = &shards.shards()[0];
+        assert_eq!(state.status, ShardStatus::WebsocketDisconnected);
+        assert_eq!(
+            state.error.as_deref(),
+            Some("subscription was rejected: Helix unavailable")
+        );
+
+        assigner.failing.store(false, Ordering::SeqCst);
+        shards.assign("0").unwrap();
+        let state = &shards.shards()[0];
+        assert_eq!(state.status, ShardStatus::Enabled);
+        assert_eq!(state.error, None);
+    }
+}
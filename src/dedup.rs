@@ -0,0 +1,107 @@
+use chrono::{DateTime, Utc};
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+/// How long message IDs are remembered. Twitch recommends ignoring messages whose timestamp is
+/// older than this, since duplicates of them can no longer be recognized.
+pub const MESSAGE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// The IDs of the messages which have already been handled, to avoid taking action twice when
+/// Twitch repeats a message. IDs are forgotten once they are older than the `ttl`, so the log
+/// doesn't grow for as long as the session lives.
+#[derive(Debug, Clone)]
+pub struct HandledMessages {
+    ids: HashSet<String>,
+    handled_at: VecDeque<(Instant, String)>,
+    pub ttl: Duration,
+}
+
+impl HandledMessages {
+    pub fn new(ttl: Duration) -> HandledMessages {
+        HandledMessages {
+            ids: HashSet::new(),
+            handled_at: VecDeque::new(),
+            ttl,
+        }
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.ids.contains(id)
+    }
+
+    /// Remembers the `id`, and forgets those which have expired by `now`.
+    pub fn insert(&mut self, id: String, now: Instant) {
+        self.expire(now);
+        if self.ids.insert(id.clone()) {
+            self.handled_at.push_back((now, id));
+        }
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        while let Some((handled_at, _)) = self.handled_at.front() {
+            if now.saturating_duration_since(*handled_at) < self.ttl {
+                break;
+            }
+            if let Some((_, id)) = self.handled_at.pop_front() {
+                self.ids.remove(&id);
+            }
+        }
+    }
+
+    /// Whether a message sent at the `timestamp` is too old to be deduplicated by `now`. Messages
+    /// with a timestamp that can't be parsed are never stale.
+    pub fn is_stale(&self, timestamp: &str, now: DateTime<Utc>) -> bool {
+        match DateTime::parse_from_rfc3339(timestamp) {
+            Ok(sent_at) => (now - sent_at.with_timezone(&Utc))
+                .to_std()
+                .is_ok_and(|age| age > self.ttl),
+            Err(_) => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
+
+impl Default for HandledMessages {
+    fn default() -> Self {
+        HandledMessages::new(MESSAGE_TTL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_expire_after_ttl() {
+        let mut handled = HandledMessages::default();
+        let start = Instant::now();
+        handled.insert("a".to_owned(), start);
+        handled.insert("b".to_owned(), start + Duration::from_secs(5 * 60));
+        assert!(handled.contains("a"));
+
+        handled.expire(start + MESSAGE_TTL);
+        assert!(!handled.contains("a"));
+        assert!(handled.contains("b"));
+        assert_eq!(handled.len(), 1);
+    }
+
+    #[test]
+    fn old_timestamps_are_stale() {
+        let handled = HandledMessages::default();
+        let now = Utc::now();
+        let recent = (now - chrono::Duration::minutes(9)).to_rfc3339();
+        let old = (now - chrono::Duration::minutes(11)).to_rfc3339();
+        let future = (now + chrono::Duration::minutes(11)).to_rfc3339();
+        assert!(!handled.is_stale(&recent, now));
+        assert!(handled.is_stale(&old, now));
+        assert!(!handled.is_stale(&future, now));
+        assert!(!handled.is_stale("not a timestamp", now));
+    }
+}
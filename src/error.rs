@@ -1,3 +1,4 @@
+use crate::close::CloseReason;
 use crate::types::Session;
 use crate::TwitchMessage;
 use std::io;
@@ -25,6 +26,10 @@ pub enum EventSubErr {
     Poison(String),
     #[error("couldn't parse url: {0}")]
     Url(url::ParseError),
+    #[error("Twitch closed the connection: {0}")]
+    Closed(CloseReason),
+    #[error("couldn't reconnect after {0} attempts")]
+    ReconnectFailed(u32),
 }
 
 #[derive(Error, Debug)]
@@ -33,6 +38,8 @@ pub enum HandlerErr {
     Welcome(WelcomeHandlerErr),
     #[error("error handling erconnect message: {0}")]
     Reconnect(ReconnectHandlerErr),
+    #[error("error handling revocation message: {0}")]
+    Revocation(RevocationHandlerErr),
 }
 
 #[derive(Error, Debug)]
@@ -43,8 +50,18 @@ pub enum WelcomeHandlerErr {
     InvalidKeepalive(String),
     #[error("no session was provided: {0}")]
     NoSession(String),
-    #[error("error when setting keepalive: {0}")]
-    CannotSetKeepalive(KeepaliveErr),
+    #[error("session mutex has been poisoned: {0}")]
+    Poison(String),
+    #[error("no subscription was created within 10 seconds of the welcome message: {0}")]
+    MissedSubscribeDeadline(String),
+    #[error("welcome callback failed: {0}")]
+    Callback(SubscriptionErr),
+}
+
+#[derive(Error, Debug)]
+pub enum RevocationHandlerErr {
+    #[error("no session was provided: {0}")]
+    NoSession(String),
     #[error("session mutex has been poisoned: {0}")]
     Poison(String),
 }
@@ -65,6 +82,8 @@ pub enum ReconnectHandlerErr {
     Poison(String),
     #[error("couldn't parse url: {0}")]
     Url(url::ParseError),
+    #[error("reconnect url was rejected: {0}")]
+    RejectedUrl(String),
 }
 
 #[derive(Error, Debug)]
@@ -76,9 +95,123 @@ pub enum SessionErr {
 }
 
 #[derive(Error, Debug)]
-pub enum KeepaliveErr {
-    #[error("error setting the socket's timeout to keepalive: {0}")]
-    Timeout(io::Error),
-    #[error("session mutex has been poisoned: {0}")]
-    Poison(String),
+pub enum SubscriptionErr {
+    #[error("subscription was rejected: {0}")]
+    Rejected(String),
+    #[error("error calling Helix: {0}")]
+    Helix(HelixErr),
+}
+
+#[derive(Error, Debug)]
+pub enum PoolErr {
+    #[error("every connection is full, and no more connections may be opened")]
+    Full,
+    #[error("the subscription cost budget is used up")]
+    BudgetExhausted,
+    #[error("couldn't open a connection: {0}")]
+    Connection(EventSubErr),
+    #[error("couldn't create subscription: {0}")]
+    Subscription(SubscriptionErr),
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum BudgetErr {
+    #[error("an identical subscription already exists on the session: {0}")]
+    Duplicate(String),
+    #[error("session {session_id} already holds {limit} subscriptions")]
+    SessionFull { session_id: String, limit: usize },
+    #[error("the subscription costs {cost}, but only {remaining} is left")]
+    CostExceeded { cost: u64, remaining: u64 },
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ReplayErr {
+    #[error("the replay speed has to be finite and above 0, but is {0}")]
+    InvalidSpeed(f64),
+}
+
+#[derive(Error, Debug)]
+pub enum AccountErr {
+    #[error("couldn't set up the account's Helix client: {0}")]
+    Helix(HelixErr),
+    #[error("couldn't start the account's session: {0}")]
+    Session(EventSubErr),
+}
+
+#[derive(Error, Debug)]
+pub enum HelixErr {
+    #[error("Helix responded with {status}: {message}")]
+    Status { status: u16, message: String },
+    #[error("error sending request to Helix: {0}")]
+    Transport(String),
+    #[error("Helix response did not contain any data")]
+    Empty,
+    #[error("couldn't parse Helix response: {0}")]
+    Parse(io::Error),
+    #[error("couldn't build request url: {0}")]
+    Url(url::ParseError),
+    #[error("couldn't set up TLS: {0}")]
+    Tls(native_tls::Error),
+    #[error("couldn't get an access token: {0}")]
+    Auth(AuthErr),
+    #[error("subscriptions can't be listed by the unknown status")]
+    UnknownStatusFilter,
+    #[error("the subscription would be rejected: {0}")]
+    Budget(BudgetErr),
+}
+
+#[derive(Error, Debug)]
+pub enum AuthErr {
+    #[error("Twitch responded with {status}: {message}")]
+    Status { status: u16, message: String },
+    #[error("error sending request to Twitch: {0}")]
+    Transport(String),
+    #[error("couldn't parse Twitch's response: {0}")]
+    Parse(io::Error),
+    #[error("couldn't set up TLS: {0}")]
+    Tls(native_tls::Error),
+    #[error("the token expired, and can't be refreshed: {0}")]
+    CantRefresh(String),
+    #[error("authorization was not granted: {0}")]
+    Denied(String),
+    #[error("the redirect's state doesn't match the one the authorization was started with")]
+    StateMismatch,
+    #[error("couldn't receive the redirect: {0}")]
+    Redirect(io::Error),
+    #[error("couldn't access the token store: {0}")]
+    Store(io::Error),
+    #[error("couldn't draw a random state: {0}")]
+    Random(getrandom::Error),
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ScopeErr {
+    #[error("{0} version {1} is not in the scope requirements table")]
+    UnknownType(String, String),
+    #[error("WebSocket subscriptions need a user access token")]
+    NoUser,
+    #[error("the condition is missing the {0} field")]
+    MissingCondition(&'static str),
+    #[error("the token is missing the scope {}", .0.join(" or "))]
+    MissingScope(Vec<&'static str>),
+    #[error("the condition's {field} is {expected}, but the token belongs to user {actual}")]
+    WrongUser {
+        field: &'static str,
+        expected: String,
+        actual: String,
+    },
+}
+
+#[derive(Error, Debug)]
+pub enum WebhookErr {
+    #[error("request is missing the {0} header")]
+    MissingHeader(&'static str),
+    #[error("request signature doesn't match its body")]
+    InvalidSignature,
+    #[error("callback verification request has no challenge")]
+    MissingChallenge,
+    #[error("unknown webhook message type: {0}")]
+    UnknownMessageType(String),
+    #[error("couldn't parse request body: {0}")]
+    Body(serde_json::Error),
 }
@@ -62,19 +62,26 @@ impl From<ReconnectHandlerErr> for HandlerErr {
     }
 }
 
-// Implementations for the `WelcomeHandlerErr` Error type
-impl From<KeepaliveErr> for WelcomeHandlerErr {
-    fn from(err: KeepaliveErr) -> Self {
-        WelcomeHandlerErr::CannotSetKeepalive(err)
+impl From<RevocationHandlerErr> for HandlerErr {
+    fn from(err: RevocationHandlerErr) -> Self {
+        HandlerErr::Revocation(err)
     }
 }
 
+// Implementations for the `WelcomeHandlerErr` Error type
 impl From<PoisonError<MutexGuard<'_, Session>>> for WelcomeHandlerErr {
     fn from(err: PoisonError<MutexGuard<'_, Session>>) -> Self {
         WelcomeHandlerErr::Poison(err.to_string())
     }
 }
 
+// Implementations for the `RevocationHandlerErr` Error type
+impl From<PoisonError<MutexGuard<'_, Session>>> for RevocationHandlerErr {
+    fn from(err: PoisonError<MutexGuard<'_, Session>>) -> Self {
+        RevocationHandlerErr::Poison(err.to_string())
+    }
+}
+
 // Implementations for the `ReconnectHandlerErr` Error type
 impl From<tungstenite::Error> for ReconnectHandlerErr {
     fn from(err: tungstenite::Error) -> ReconnectHandlerErr {
@@ -119,9 +126,127 @@ impl From<tungstenite::Error> for SessionErr {
     }
 }
 
-// Implementations for the `KeepaliveErr` Error type
-impl From<io::Error> for KeepaliveErr {
+// Implementations for the `SubscriptionErr` Error type
+impl From<HelixErr> for SubscriptionErr {
+    fn from(err: HelixErr) -> Self {
+        SubscriptionErr::Helix(err)
+    }
+}
+
+// Implementations for the `PoolErr` Error type
+impl From<EventSubErr> for PoolErr {
+    fn from(err: EventSubErr) -> Self {
+        PoolErr::Connection(err)
+    }
+}
+
+impl From<SubscriptionErr> for PoolErr {
+    fn from(err: SubscriptionErr) -> Self {
+        PoolErr::Subscription(err)
+    }
+}
+
+impl From<PoisonError<MutexGuard<'_, Session>>> for PoolErr {
+    fn from(err: PoisonError<MutexGuard<'_, Session>>) -> Self {
+        PoolErr::Connection(err.into())
+    }
+}
+
+// Implementations for the `AccountErr` Error type
+impl From<HelixErr> for AccountErr {
+    fn from(err: HelixErr) -> Self {
+        AccountErr::Helix(err)
+    }
+}
+
+impl From<EventSubErr> for AccountErr {
+    fn from(err: EventSubErr) -> Self {
+        AccountErr::Session(err)
+    }
+}
+
+// Implementations for the `HelixErr` Error type
+impl From<ureq::Error> for HelixErr {
+    fn from(err: ureq::Error) -> Self {
+        match err {
+            ureq::Error::Status(status, response) => {
+                // Helix explains errors in a JSON body like
+                // `{"error": "Bad Request", "status": 400, "message": "..."}`
+                let message = response
+                    .into_json::<serde_json::Value>()
+                    .ok()
+                    .and_then(|body| body["message"].as_str().map(str::to_owned))
+                    .unwrap_or_default();
+                HelixErr::Status { status, message }
+            }
+            ureq::Error::Transport(err) => HelixErr::Transport(err.to_string()),
+        }
+    }
+}
+
+impl From<io::Error> for HelixErr {
     fn from(err: io::Error) -> Self {
-        KeepaliveErr::Timeout(err)
+        HelixErr::Parse(err)
+    }
+}
+
+impl From<ParseError> for HelixErr {
+    fn from(err: ParseError) -> Self {
+        HelixErr::Url(err)
+    }
+}
+
+impl From<native_tls::Error> for HelixErr {
+    fn from(err: native_tls::Error) -> Self {
+        HelixErr::Tls(err)
+    }
+}
+
+impl From<AuthErr> for HelixErr {
+    fn from(err: AuthErr) -> Self {
+        HelixErr::Auth(err)
+    }
+}
+
+impl From<BudgetErr> for HelixErr {
+    fn from(err: BudgetErr) -> Self {
+        HelixErr::Budget(err)
+    }
+}
+
+// Implementations for the `AuthErr` Error type
+impl From<ureq::Error> for AuthErr {
+    fn from(err: ureq::Error) -> Self {
+        match err {
+            ureq::Error::Status(status, response) => {
+                // Twitch's OAuth endpoints answer like `{"status": 400, "message": "..."}`
+                let message = response
+                    .into_json::<serde_json::Value>()
+                    .ok()
+                    .and_then(|body| body["message"].as_str().map(str::to_owned))
+                    .unwrap_or_default();
+                AuthErr::Status { status, message }
+            }
+            ureq::Error::Transport(err) => AuthErr::Transport(err.to_string()),
+        }
+    }
+}
+
+impl From<io::Error> for AuthErr {
+    fn from(err: io::Error) -> Self {
+        AuthErr::Parse(err)
+    }
+}
+
+impl From<native_tls::Error> for AuthErr {
+    fn from(err: native_tls::Error) -> Self {
+        AuthErr::Tls(err)
+    }
+}
+
+// Implementations for the `WebhookErr` Error type
+impl From<serde_json::Error> for WebhookErr {
+    fn from(err: serde_json::Error) -> Self {
+        WebhookErr::Body(err)
     }
 }
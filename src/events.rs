@@ -0,0 +1,53 @@
+use crate::close::CloseReason;
+use crate::subscriptions::{DesiredSubscription, Revoked, SubscribeReport};
+use std::time::Duration;
+
+/// Lifecycle events of the connection to Twitch's EventSub server. These are sent through the
+/// `Session`'s event forwarder, next to the `TwitchMessage`s, so that applications can react to
+/// them, e.g. by recreating their subscriptions after a reconnect.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientEvent {
+    /// The initial `Welcome` message was received, and subscriptions can now be created for the
+    /// session.
+    Connected { session_id: String },
+    /// The connection to Twitch was lost unexpectedly.
+    Disconnected { reason: String },
+    /// No message arrived for longer than the keepalive time plus the watchdog's grace period, so
+    /// the connection is considered dead and will be replaced.
+    KeepaliveMissed { silent_for: Duration },
+    /// A new connection will be attempted after waiting for `delay`.
+    Reconnecting { attempt: u32, delay: Duration },
+    /// Every attempt the session's `max_reconnect_attempts` allowed failed, so the message loop
+    /// ends.
+    ReconnectFailed { attempts: u32 },
+    /// A new connection was established after the old one had been lost. Twitch does not carry
+    /// subscriptions over to a new session, so `subscriptions_lost` is set whenever the session ID
+    /// changed.
+    Reconnected {
+        new_session_id: String,
+        subscriptions_lost: bool,
+    },
+    /// The desired subscriptions were created on the session after the initial `Welcome`.
+    Subscribed(SubscribeReport),
+    /// The desired subscriptions were recreated on the new session after a reconnect.
+    Resubscribed(SubscribeReport),
+    /// Twitch revoked a subscription, which was removed from the desired subscriptions.
+    Revoked(Revoked),
+    /// A message was dropped for being older than handled message IDs are kept, since it can't
+    /// be told whether it was handled before.
+    StaleMessage { message_id: String },
+    /// The `Recorder` failed to write a frame, and was dropped from the session.
+    RecordingStopped { reason: String },
+    /// A subscription of a `SessionPool` connection which was lost for good couldn't be moved to
+    /// another connection, and was dropped.
+    Unplaced {
+        subscription: DesiredSubscription,
+        reason: String,
+    },
+    /// The connection was moved to a new url, after Twitch sent a `Reconnect` message.
+    /// Subscriptions are kept in this case.
+    Migrated,
+    /// The connection was closed with the given close code. If Twitch closed it, the `reason`
+    /// decides whether a new connection is attempted.
+    Closed { code: u16, reason: CloseReason },
+}
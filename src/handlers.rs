@@ -1,18 +1,17 @@
-use crate::types::{Reconnect, Session, TwitchMessage, Welcome};
-use crate::{create_message_processor, error::*};
-use std::sync::mpsc::Sender;
+use crate::error::*;
+use crate::events::ClientEvent;
+use crate::migration::Migration;
+use crate::subscriptions::{Revoked, SUBSCRIBE_DEADLINE};
+use crate::types::{Reconnect, Revocation, Session, TwitchMessage, Welcome};
 use std::sync::{Arc, Mutex};
-use url::Url;
+use std::time::Instant;
 
 impl TwitchMessage {
-    pub fn handle(
-        &self,
-        session: Option<Arc<Mutex<Session>>>,
-        tx: &Sender<TwitchMessage>,
-    ) -> Result<(), HandlerErr> {
+    pub fn handle(&self, session: Option<Arc<Mutex<Session>>>) -> Result<(), HandlerErr> {
         match self {
             TwitchMessage::Welcome(msg) => Ok(msg.handle(session)?),
-            TwitchMessage::Reconnect(msg) => Ok(msg.handle(session, tx)?),
+            TwitchMessage::Reconnect(msg) => Ok(msg.handle(session)?),
+            TwitchMessage::Revocation(msg) => Ok(msg.handle(session)?),
             _ => Ok(()),
         }
     }
@@ -21,17 +20,56 @@ impl TwitchMessage {
 impl Welcome {
     fn handle(&self, session: Option<Arc<Mutex<Session>>>) -> Result<(), WelcomeHandlerErr> {
         if let Some(session) = session {
-            let mut session = session.lock()?;
-            session.id = self.payload.session.id.to_string();
-            let keepalive = self.payload.session.keepalive_timeout_seconds.as_u64();
-            match keepalive {
-                Some(time) => session.set_keepalive(time)?,
-                None => {
-                    return Err(WelcomeHandlerErr::InvalidKeepalive(format!(
-                        "invalid keepalive time received: {:#?}",
-                        keepalive
-                    )))
+            let (received_at, reconnected, subscriptions_lost) = {
+                let mut session = session.lock()?;
+                let received_at = session.clock.now();
+                if let Some(migration) = session.migration.take() {
+                    if migration.is_awaiting_welcome() {
+                        // The session ID and subscriptions are carried over to the new
+                        // connection, so all that is left to do is draining the old one.
+                        session.migration = Some(migration.welcomed());
+                        if let Some(keepalive) = self.keepalive() {
+                            session.set_keepalive(keepalive);
+                        }
+                        return Ok(());
+                    }
+                    session.migration = Some(migration);
+                }
+                let old_id =
+                    std::mem::replace(&mut session.id, self.payload.session.id.to_string());
+                let reconnected = session.reconnecting;
+                let subscriptions_lost = old_id != session.id;
+                if reconnected {
+                    session.reconnecting = false;
+                    session.emit(ClientEvent::Reconnected {
+                        subscriptions_lost,
+                        new_session_id: session.id.clone(),
+                    });
+                } else {
+                    session.emit(ClientEvent::Connected {
+                        session_id: session.id.clone(),
+                    });
+                }
+                match &self.payload.session.keepalive_timeout_seconds {
+                    Some(keepalive) => match keepalive.as_u64() {
+                        Some(time) => session.set_keepalive(time),
+                        None => {
+                            return Err(WelcomeHandlerErr::InvalidKeepalive(format!(
+                                "invalid keepalive time received: {:#?}",
+                                keepalive
+                            )))
+                        }
+                    },
+                    None => {
+                        return Err(WelcomeHandlerErr::NoKeepalive(
+                            "initial Welcome message has no keepalive time".to_owned(),
+                        ))
+                    }
                 }
+                (received_at, reconnected, subscriptions_lost)
+            };
+            if subscriptions_lost {
+                subscribe_before_deadline(&session, received_at, reconnected)?;
             }
         } else {
             return Err(WelcomeHandlerErr::NoSession(
@@ -40,15 +78,131 @@ impl Welcome {
         };
         Ok(())
     }
+
+    fn keepalive(&self) -> Option<u64> {
+        self.payload
+            .session
+            .keepalive_timeout_seconds
+            .as_ref()
+            .and_then(|keepalive| keepalive.as_u64())
+    }
+}
+
+/// Creates the desired subscriptions and runs the welcome callback for a new session. Twitch
+/// closes the connection if nothing was subscribed to within `SUBSCRIBE_DEADLINE`, so this fails
+/// once that deadline has passed without a subscription, instead of waiting for the connection to
+/// be closed. Subscriptions that fail before then are only reported, e.g. after a scope was
+/// revoked, and the callback or the application may still subscribe in time.
+///
+/// Every subscription takes a Helix request, so the session is only locked to take a snapshot of
+/// the `SubscriptionManager` and the callback, and to report the outcome.
+fn subscribe_before_deadline(
+    session: &Mutex<Session>,
+    received_at: Instant,
+    reconnected: bool,
+) -> Result<(), WelcomeHandlerErr> {
+    let deadline = received_at + SUBSCRIBE_DEADLINE;
+    let (manager, mut callback, session_id, clock) = {
+        let mut session = session.lock()?;
+        (
+            session.subscriptions.clone(),
+            session.on_welcome.take(),
+            session.id.clone(),
+            Arc::clone(&session.clock),
+        )
+    };
+    let mut subscribed = false;
+    let mut missed = vec![];
+    if let Some(manager) = manager.filter(|manager| !manager.desired().is_empty()) {
+        let report = manager.subscribe_all(&session_id, Some(deadline), &*clock);
+        subscribed = !report.created.is_empty();
+        if !report.failed.is_empty() {
+            missed.push(format!("{} subscription(s) failed", report.failed.len()));
+        }
+        session.lock()?.emit(if reconnected {
+            ClientEvent::Resubscribed(report)
+        } else {
+            ClientEvent::Subscribed(report)
+        });
+    }
+    let called = callback.as_mut().map(|callback| callback(&session_id));
+    {
+        let mut session = session.lock()?;
+        // Unless a new callback was set in the meantime
+        if session.on_welcome.is_none() {
+            session.on_welcome = callback;
+        }
+    }
+    if let Some(called) = called {
+        called.map_err(WelcomeHandlerErr::Callback)?;
+        missed.push("welcome callback did not finish in time".to_owned());
+    }
+    if !subscribed && !missed.is_empty() && clock.now() >= deadline {
+        return Err(WelcomeHandlerErr::MissedSubscribeDeadline(
+            missed.join(", "),
+        ));
+    }
+    Ok(())
+}
+
+impl Revocation {
+    /// Stops the `SubscriptionManager` from recreating the revoked subscription, or creates it
+    /// again right away if the session is set to `resubscribe_revoked` and the reason is
+    /// recoverable. Either way, the revocation is passed to `on_revoked` and emitted. Like for a
+    /// `Welcome`, the session is unlocked while the subscription is created and the callback runs.
+    fn handle(&self, session: Option<Arc<Mutex<Session>>>) -> Result<(), RevocationHandlerErr> {
+        let session = session.ok_or_else(|| {
+            RevocationHandlerErr::NoSession(
+                "Revocation handler needs to be called with valid session".to_owned(),
+            )
+        })?;
+        let subscription = self.payload.subscription.to_desired();
+        let reason = self.payload.subscription.parsed_status();
+        let (manager, mut callback, session_id) = {
+            let mut session = session.lock()?;
+            let resubscribe = session.resubscribe_revoked && reason.is_recoverable();
+            let mut manager = None;
+            if let Some(subscriptions) = &mut session.subscriptions {
+                subscriptions.forget(&subscription);
+                if resubscribe {
+                    manager = Some(subscriptions.clone());
+                }
+            }
+            (manager, session.on_revoked.take(), session.id.clone())
+        };
+        let resubscribed = manager.map(|manager| {
+            manager
+                .create(&subscription, &session_id)
+                .map_err(|err| err.to_string())
+        });
+        let revoked = Revoked {
+            id: self.payload.subscription.id.clone(),
+            subscription,
+            reason,
+            resubscribed,
+        };
+        if let Some(callback) = &mut callback {
+            callback(&revoked);
+        }
+        let mut session = session.lock()?;
+        if let (Some(Ok(())), Some(manager)) = (&revoked.resubscribed, &mut session.subscriptions) {
+            manager.remember(revoked.subscription.clone());
+        }
+        // Unless a new callback was set in the meantime
+        if session.on_revoked.is_none() {
+            session.on_revoked = callback;
+        }
+        session.emit(ClientEvent::Revoked(revoked));
+        Ok(())
+    }
 }
 
 impl Reconnect {
-    fn handle(
-        &self,
-        session: Option<Arc<Mutex<Session>>>,
-        tx: &Sender<TwitchMessage>,
-    ) -> Result<(), ReconnectHandlerErr> {
-        let old_session = match session {
+    /// Opens the connection to the new url, and leaves the rest of the `Migration` to the
+    /// message loop. If the new url can't be reached, the old connection is kept until Twitch
+    /// closes it, which then triggers a regular reconnect.
+    fn handle(&self, session: Option<Arc<Mutex<Session>>>) -> Result<(), ReconnectHandlerErr> {
+        let session = match session {
             Some(session) => session,
             None => {
                 return Err(ReconnectHandlerErr::Session(
@@ -57,18 +211,296 @@ impl Reconnect {
             }
         };
 
-        let url = Url::parse(&self.payload.session.reconnect_url)?;
-        let new_session =
-            crate::get_session(url).map_err(|err| ReconnectHandlerErr::Session(err.to_string()))?;
+        let (url, connector) = {
+            let session = session.lock()?;
+            let validated = session
+                .reconnect_policy
+                .validate(&self.payload.session.reconnect_url);
+            match validated {
+                Ok(url) => (url, Arc::clone(&session.connector)),
+                // Like an unreachable url, this keeps the old connection until Twitch closes it
+                Err(err) => {
+                    session.emit(ClientEvent::Disconnected {
+                        reason: format!("couldn't migrate to the reconnect url: {}", err),
+                    });
+                    return Ok(());
+                }
+            }
+        };
+        let connection = connector.connect(&url);
+        let mut session = session.lock()?;
+        match connection {
+            Ok(socket) => {
+                let connection = session.next_connection_id();
+                session.migration = Some(Migration::begin(socket, connection, session.clock.now()));
+            }
+            Err(err) => session.emit(ClientEvent::Disconnected {
+                reason: format!("couldn't migrate to the reconnect url: {}", err),
+            }),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::helix::SubscriptionStatus;
+    use crate::mock::{self, MockServer, Step};
+    use crate::subscriptions::{DesiredSubscription, SubscriptionCreator, SubscriptionManager};
+    use serde_json::Value;
+    use std::sync::mpsc::{self, Receiver};
+    use std::time::Duration;
+
+    /// Rejects every subscription, after taking `delay` on the `clock`.
+    struct Rejecting {
+        clock: Arc<ManualClock>,
+        delay: Duration,
+    }
+
+    impl SubscriptionCreator for Rejecting {
+        fn create_subscription(
+            &self,
+            _subscription: &DesiredSubscription,
+            _session_id: &str,
+        ) -> Result<(), SubscriptionErr> {
+            self.clock.advance(self.delay);
+            Err(SubscriptionErr::Rejected("missing scope".to_owned()))
+        }
+    }
+
+    /// Accepts every subscription, noting whether the session was unlocked meanwhile.
+    #[derive(Default)]
+    struct Probing {
+        session: Mutex<std::sync::Weak<Mutex<Session>>>,
+        unlocked: Mutex<Vec<bool>>,
+    }
+
+    impl SubscriptionCreator for Probing {
+        fn create_subscription(
+            &self,
+            _subscription: &DesiredSubscription,
+            _session_id: &str,
+        ) -> Result<(), SubscriptionErr> {
+            let session = self.session.lock().unwrap().upgrade().unwrap();
+            let unlocked = session.try_lock().is_ok();
+            self.unlocked.lock().unwrap().push(unlocked);
+            Ok(())
+        }
+    }
+
+    fn welcomed_session(
+        server: &MockServer,
+        clock: Arc<ManualClock>,
+        subscriptions: Option<SubscriptionManager>,
+    ) -> (Arc<Mutex<Session>>, Receiver<ClientEvent>) {
+        let session = crate::get_session(server.url()).unwrap();
+        let (tx, events) = mpsc::channel();
+        {
+            let mut session = session.lock().unwrap();
+            session.set_clock(clock);
+            session.event_forwarder = Some(tx);
+            session.subscriptions = subscriptions;
+        }
+        (session, events)
+    }
+
+    fn welcome() -> TwitchMessage {
+        crate::parse_message(&mock::welcome("session", 10)).unwrap()
+    }
+
+    fn rejecting(clock: &Arc<ManualClock>, delay: Duration) -> SubscriptionManager {
+        let mut manager = SubscriptionManager::new(Rejecting {
+            clock: Arc::clone(clock),
+            delay,
+        });
+        manager.remember(DesiredSubscription::new("stream.online", "1", Value::Null));
+        manager
+    }
+
+    #[test]
+    fn failed_subscriptions_keep_the_connection_before_the_deadline() {
+        let server = MockServer::start(vec![vec![Step::Wait(Duration::from_secs(1))]]);
+        let clock = Arc::new(ManualClock::new());
+        let manager = rejecting(&clock, Duration::from_secs(1));
+        let (session, events) = welcomed_session(&server, clock, Some(manager));
+
+        welcome().handle(Some(session)).unwrap();
+        let report = events
+            .try_iter()
+            .find_map(|event| match event {
+                ClientEvent::Subscribed(report) => Some(report),
+                _ => None,
+            })
+            .unwrap();
+        assert!(report.created.is_empty());
+        assert_eq!(report.failed.len(), 1);
+    }
+
+    #[test]
+    fn missing_the_deadline_fails_the_welcome() {
+        let server = MockServer::start(vec![vec![Step::Wait(Duration::from_secs(1))]]);
+        let clock = Arc::new(ManualClock::new());
+        let manager = rejecting(&clock, SUBSCRIBE_DEADLINE);
+        let (session, _events) = welcomed_session(&server, clock, Some(manager));
+
+        assert!(matches!(
+            welcome().handle(Some(session)),
+            Err(HandlerErr::Welcome(WelcomeHandlerErr::MissedSubscribeDeadline(summary)))
+                if summary == "1 subscription(s) failed"
+        ));
+    }
+
+    #[test]
+    fn welcome_callback_only_fails_once_it_is_too_late() {
+        let server = MockServer::start(vec![
+            vec![Step::Wait(Duration::from_secs(1))],
+            vec![Step::Wait(Duration::from_secs(1))],
+        ]);
+        for delay in [Duration::from_secs(1), SUBSCRIBE_DEADLINE] {
+            let clock = Arc::new(ManualClock::new());
+            let (session, _events) = welcomed_session(&server, Arc::clone(&clock), None);
+            session.lock().unwrap().on_welcome = Some(Box::new(move |_| {
+                clock.advance(delay);
+                Ok(())
+            }));
+            let handled = welcome().handle(Some(session));
+            if delay < SUBSCRIBE_DEADLINE {
+                assert!(handled.is_ok());
+            } else {
+                assert!(matches!(
+                    handled,
+                    Err(HandlerErr::Welcome(
+                        WelcomeHandlerErr::MissedSubscribeDeadline(_)
+                    ))
+                ));
+            }
+        }
+    }
 
-        create_message_processor(Arc::clone(&new_session), tx, true, false)?;
+    #[test]
+    fn late_callback_keeps_the_failed_subscriptions_in_the_summary() {
+        let server = MockServer::start(vec![vec![Step::Wait(Duration::from_secs(1))]]);
+        let clock = Arc::new(ManualClock::new());
+        let manager = rejecting(&clock, Duration::from_secs(1));
+        let (session, _events) = welcomed_session(&server, Arc::clone(&clock), Some(manager));
+        session.lock().unwrap().on_welcome = Some(Box::new(move |_| {
+            clock.advance(SUBSCRIBE_DEADLINE);
+            Ok(())
+        }));
 
-        create_message_processor(Arc::clone(&old_session), tx, false, true)?;
+        assert!(matches!(
+            welcome().handle(Some(session)),
+            Err(HandlerErr::Welcome(WelcomeHandlerErr::MissedSubscribeDeadline(summary)))
+                if summary == "1 subscription(s) failed, welcome callback did not finish in time"
+        ));
+    }
+
+    #[test]
+    fn session_is_unlocked_while_subscribing() {
+        let server = MockServer::start(vec![vec![Step::Wait(Duration::from_secs(1))]]);
+        let clock = Arc::new(ManualClock::new());
+        let (session, _events) = welcomed_session(&server, clock, None);
+        let weak = Arc::downgrade(&session);
+        let (tx, unlocked) = mpsc::channel();
+        session.lock().unwrap().on_welcome = Some(Box::new(move |_| {
+            let session = weak.upgrade().unwrap();
+            tx.send(session.try_lock().is_ok()).unwrap();
+            Ok(())
+        }));
 
-        std::mem::swap(
-            &mut old_session.lock()?.socket,
-            &mut new_session.lock()?.socket,
+        welcome().handle(Some(Arc::clone(&session))).unwrap();
+        assert!(unlocked.try_recv().unwrap());
+        // The callback is put back for the next session
+        assert!(session.lock().unwrap().on_welcome.is_some());
+    }
+
+    #[test]
+    fn documented_revocation_forgets_the_subscription() {
+        // The example from Twitch's EventSub WebSocket reference
+        let revocation = r#"{
+            "metadata": {
+                "message_id": "84c1e79a-2a4b-4c13-ba0b-4312293e9308",
+                "message_type": "revocation",
+                "message_timestamp": "2022-11-16T10:11:12.464757833Z",
+                "subscription_type": "channel.follow",
+                "subscription_version": "1"
+            },
+            "payload": {
+                "subscription": {
+                    "id": "f1c2a387-161a-49f9-a165-0f21d7a4e1c4",
+                    "status": "authorization_revoked",
+                    "type": "channel.follow",
+                    "version": "1",
+                    "cost": 1,
+                    "condition": {"broadcaster_user_id": "12826"},
+                    "transport": {
+                        "method": "websocket",
+                        "session_id": "AQoQexAWVYKSTIu4ec_2VAxyuhAB"
+                    },
+                    "created_at": "2022-11-16T10:11:12.464757833Z"
+                }
+            }
+        }"#;
+        let follow = DesiredSubscription::new(
+            "channel.follow",
+            "1",
+            serde_json::json!({"broadcaster_user_id": "12826"}),
+        );
+        let server = MockServer::start(vec![vec![Step::Wait(Duration::from_secs(1))]]);
+        let clock = Arc::new(ManualClock::new());
+        let mut manager = rejecting(&clock, Duration::ZERO);
+        manager.remember(follow.clone());
+        let (session, events) = welcomed_session(&server, clock, Some(manager));
+        session.lock().unwrap().resubscribe_revoked = true;
+
+        let message: TwitchMessage = crate::parse_message(revocation).unwrap();
+        message.handle(Some(Arc::clone(&session))).unwrap();
+        assert!(!session
+            .lock()
+            .unwrap()
+            .subscriptions
+            .as_ref()
+            .unwrap()
+            .desired()
+            .contains(&follow));
+        assert_eq!(
+            events.try_recv().unwrap(),
+            ClientEvent::Revoked(Revoked {
+                id: "f1c2a387-161a-49f9-a165-0f21d7a4e1c4".to_owned(),
+                subscription: follow,
+                reason: SubscriptionStatus::AuthorizationRevoked,
+                resubscribed: None,
+            })
+        );
+    }
+
+    #[test]
+    fn revoked_subscription_is_recreated_without_the_session_lock() {
+        let server = MockServer::start(vec![vec![Step::Wait(Duration::from_secs(1))]]);
+        let probing = Arc::new(Probing::default());
+        let offline = DesiredSubscription::new(
+            "stream.offline",
+            "1",
+            serde_json::json!({"broadcaster_user_id": "12826"}),
+        );
+        let mut manager = SubscriptionManager::new(Arc::clone(&probing));
+        manager.remember(offline.clone());
+        let clock = Arc::new(ManualClock::new());
+        let (session, events) = welcomed_session(&server, clock, Some(manager));
+        *probing.session.lock().unwrap() = Arc::downgrade(&session);
+        session.lock().unwrap().resubscribe_revoked = true;
+
+        let message: TwitchMessage =
+            crate::parse_message(&mock::revocation("stream.offline", "beta_maintenance")).unwrap();
+        message.handle(Some(Arc::clone(&session))).unwrap();
+        assert_eq!(*probing.unlocked.lock().unwrap(), [true]);
+        let session = session.lock().unwrap();
+        assert_eq!(session.subscriptions.as_ref().unwrap().desired(), [offline]);
+        assert!(
+            matches!(events.try_recv().unwrap(), ClientEvent::Revoked(revoked)
+            if revoked.resubscribed == Some(Ok(())))
         );
-        Ok(())
     }
 }
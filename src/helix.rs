@@ -0,0 +1,896 @@
+use crate::auth::{StaticToken, TokenProvider};
+use crate::budget::{Budget, BudgetView};
+use crate::conduit::ShardAssigner;
+use crate::error::{BudgetErr, HelixErr, SubscriptionErr};
+use crate::subscriptions::{DesiredSubscription, SubscriptionCreator};
+use crate::types::TwitchMessage;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use url::Url;
+
+pub const HELIX_URL: &str = "https://api.twitch.tv/helix";
+
+/// How long connecting to Helix, or Twitch's OAuth endpoints, may take.
+pub(crate) const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a whole request to Helix, or Twitch's OAuth endpoints, may take. Subscriptions are
+/// created from within the message loop, so this is kept well below the `SUBSCRIBE_DEADLINE`.
+pub(crate) const REQUEST_TIMEOUT: Duration = Duration::from_secs(8);
+
+/// A client for the EventSub endpoints of Twitch's Helix API, used to create, list and delete
+/// subscriptions. Every response updates the cost accounting, available through `costs` and
+/// `budget_view`.
+///
+/// The base url defaults to `HELIX_URL`, but can be pointed at a local mock, such as the one
+/// started by `twitch mock-api start`, with `with_base_url`.
+#[derive(Debug)]
+pub struct HelixClient {
+    base_url: Url,
+    client_id: String,
+    tokens: Arc<dyn TokenProvider>,
+    agent: ureq::Agent,
+    budget: Mutex<Budget>,
+}
+
+/// The `transport` of a subscription. Only the fields relevant to the `method` are set.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct Transport {
+    pub method: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub callback: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conduit_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connected_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disconnected_at: Option<String>,
+}
+
+impl Transport {
+    pub fn websocket(session_id: &str) -> Transport {
+        Transport {
+            method: "websocket".to_owned(),
+            session_id: Some(session_id.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    pub fn conduit(conduit_id: &str) -> Transport {
+        Transport {
+            method: "conduit".to_owned(),
+            conduit_id: Some(conduit_id.to_owned()),
+            ..Default::default()
+        }
+    }
+}
+
+/// The body of a `POST /eventsub/subscriptions` request.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CreateSubscriptionRequest {
+    pub r#type: String,
+    pub version: String,
+    pub condition: Value,
+    pub transport: Transport,
+}
+
+impl CreateSubscriptionRequest {
+    pub fn websocket(subscription: &DesiredSubscription, session_id: &str) -> Self {
+        CreateSubscriptionRequest {
+            r#type: subscription.r#type.clone(),
+            version: subscription.version.clone(),
+            condition: subscription.condition.clone(),
+            transport: Transport::websocket(session_id),
+        }
+    }
+
+    /// Subscribes the `conduit_id`, whose shards then receive the notifications.
+    pub fn conduit(subscription: &DesiredSubscription, conduit_id: &str) -> Self {
+        CreateSubscriptionRequest {
+            r#type: subscription.r#type.clone(),
+            version: subscription.version.clone(),
+            condition: subscription.condition.clone(),
+            transport: Transport::conduit(conduit_id),
+        }
+    }
+}
+
+/// The status of a subscription, as reported by Helix, and used to filter listed subscriptions.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriptionStatus {
+    Enabled,
+    WebhookCallbackVerificationPending,
+    WebhookCallbackVerificationFailed,
+    NotificationFailuresExceeded,
+    AuthorizationRevoked,
+    ModeratorRemoved,
+    UserRemoved,
+    VersionRemoved,
+    BetaMaintenance,
+    WebsocketDisconnected,
+    WebsocketFailedPingPong,
+    WebsocketReceivedInboundTraffic,
+    WebsocketConnectionUnused,
+    WebsocketInternalError,
+    WebsocketNetworkTimeout,
+    WebsocketNetworkError,
+    /// Any status added by Twitch after this was written.
+    #[serde(other)]
+    Unknown,
+}
+
+impl SubscriptionStatus {
+    /// The value Helix uses for this status, e.g. in the `status` query parameter.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Enabled => "enabled",
+            Self::WebhookCallbackVerificationPending => "webhook_callback_verification_pending",
+            Self::WebhookCallbackVerificationFailed => "webhook_callback_verification_failed",
+            Self::NotificationFailuresExceeded => "notification_failures_exceeded",
+            Self::AuthorizationRevoked => "authorization_revoked",
+            Self::ModeratorRemoved => "moderator_removed",
+            Self::UserRemoved => "user_removed",
+            Self::VersionRemoved => "version_removed",
+            Self::BetaMaintenance => "beta_maintenance",
+            Self::WebsocketDisconnected => "websocket_disconnected",
+            Self::WebsocketFailedPingPong => "websocket_failed_ping_pong",
+            Self::WebsocketReceivedInboundTraffic => "websocket_received_inbound_traffic",
+            Self::WebsocketConnectionUnused => "websocket_connection_unused",
+            Self::WebsocketInternalError => "websocket_internal_error",
+            Self::WebsocketNetworkTimeout => "websocket_network_timeout",
+            Self::WebsocketNetworkError => "websocket_network_error",
+            Self::Unknown => "unknown",
+        }
+    }
+
+    /// Whether a subscription revoked with this status may succeed when it is created again,
+    /// without the user granting anything. This is the case for Twitch's own failures, but not
+    /// for revoked authorizations or removed users and versions.
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(
+            self,
+            Self::AuthorizationRevoked
+                | Self::ModeratorRemoved
+                | Self::UserRemoved
+                | Self::VersionRemoved
+                | Self::Unknown
+        )
+    }
+}
+
+/// A subscription as returned by Helix.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Subscription {
+    pub id: String,
+    pub status: SubscriptionStatus,
+    pub r#type: String,
+    pub version: String,
+    pub condition: Value,
+    pub created_at: String,
+    pub transport: Transport,
+    pub cost: u64,
+}
+
+impl Subscription {
+    pub fn to_desired(&self) -> DesiredSubscription {
+        DesiredSubscription::new(&self.r#type, &self.version, self.condition.clone())
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct Pagination {
+    pub cursor: Option<String>,
+}
+
+/// The response to both creating and listing subscriptions.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SubscriptionList {
+    pub data: Vec<Subscription>,
+    pub total: u64,
+    pub total_cost: u64,
+    pub max_total_cost: u64,
+    #[serde(default)]
+    pub pagination: Pagination,
+}
+
+/// The cost accounting Helix returns with every subscription response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubscriptionCosts {
+    /// The number of subscriptions the client ID has created.
+    pub total: u64,
+    pub total_cost: u64,
+    pub max_total_cost: u64,
+}
+
+impl SubscriptionCosts {
+    pub fn remaining(&self) -> u64 {
+        self.max_total_cost.saturating_sub(self.total_cost)
+    }
+}
+
+/// A conduit, which spreads the notifications of its subscriptions across its shards.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Conduit {
+    pub id: String,
+    pub shard_count: u64,
+}
+
+/// The status of a conduit shard, as reported by Helix.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ShardStatus {
+    Enabled,
+    WebhookCallbackVerificationPending,
+    WebhookCallbackVerificationFailed,
+    NotificationFailuresExceeded,
+    WebsocketDisconnected,
+    WebsocketFailedPingPong,
+    WebsocketReceivedInboundTraffic,
+    WebsocketInternalError,
+    WebsocketNetworkTimeout,
+    WebsocketNetworkError,
+    WebsocketFailedToReconnect,
+    /// Any status added by Twitch after this was written.
+    #[serde(other)]
+    Unknown,
+}
+
+impl ShardStatus {
+    /// The value Helix uses for this status, e.g. in the `status` query parameter.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Enabled => "enabled",
+            Self::WebhookCallbackVerificationPending => "webhook_callback_verification_pending",
+            Self::WebhookCallbackVerificationFailed => "webhook_callback_verification_failed",
+            Self::NotificationFailuresExceeded => "notification_failures_exceeded",
+            Self::WebsocketDisconnected => "websocket_disconnected",
+            Self::WebsocketFailedPingPong => "websocket_failed_ping_pong",
+            Self::WebsocketReceivedInboundTraffic => "websocket_received_inbound_traffic",
+            Self::WebsocketInternalError => "websocket_internal_error",
+            Self::WebsocketNetworkTimeout => "websocket_network_timeout",
+            Self::WebsocketNetworkError => "websocket_network_error",
+            Self::WebsocketFailedToReconnect => "websocket_failed_to_reconnect",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+/// A shard of a conduit as returned by Helix, with the transport it delivers notifications to.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Shard {
+    pub id: String,
+    pub status: ShardStatus,
+    pub transport: Transport,
+}
+
+/// Points a shard at a new transport, in a `PATCH /eventsub/conduits/shards` request.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ShardUpdate {
+    pub id: String,
+    pub transport: Transport,
+}
+
+impl ShardUpdate {
+    pub fn websocket(shard_id: &str, session_id: &str) -> ShardUpdate {
+        ShardUpdate {
+            id: shard_id.to_owned(),
+            transport: Transport::websocket(session_id),
+        }
+    }
+}
+
+/// A shard which couldn't be updated, along with the reason.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ShardError {
+    pub id: String,
+    pub message: String,
+    pub code: String,
+}
+
+/// The response to updating shards. Helix accepts the request even if some shards failed, which
+/// are then listed in `errors`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ShardUpdateReport {
+    #[serde(rename = "data")]
+    pub updated: Vec<Shard>,
+    #[serde(default)]
+    pub errors: Vec<ShardError>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct ConduitList {
+    data: Vec<Conduit>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct ShardList {
+    data: Vec<Shard>,
+    #[serde(default)]
+    pagination: Pagination,
+}
+
+/// Filters for listing subscriptions. Helix only accepts one of these at a time.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SubscriptionFilter {
+    pub status: Option<SubscriptionStatus>,
+    pub r#type: Option<String>,
+    pub user_id: Option<String>,
+}
+
+impl HelixClient {
+    pub fn new(client_id: &str, access_token: &str) -> Result<HelixClient, HelixErr> {
+        HelixClient::with_base_url(client_id, access_token, Url::parse(HELIX_URL)?)
+    }
+
+    pub fn with_base_url(
+        client_id: &str,
+        access_token: &str,
+        base_url: Url,
+    ) -> Result<HelixClient, HelixErr> {
+        let tokens = Arc::new(StaticToken::new(access_token));
+        HelixClient::with_token_provider(client_id, tokens, base_url)
+    }
+
+    /// Authenticates every request with a token from the `tokens`. A request Helix rejects with
+    /// `401 Unauthorized` is retried once, after refreshing the token.
+    pub fn with_token_provider(
+        client_id: &str,
+        tokens: Arc<dyn TokenProvider>,
+        base_url: Url,
+    ) -> Result<HelixClient, HelixErr> {
+        let agent = ureq::AgentBuilder::new()
+            .tls_connector(Arc::new(native_tls::TlsConnector::new()?))
+            .timeout_connect(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .build();
+        Ok(HelixClient {
+            base_url,
+            client_id: client_id.to_owned(),
+            tokens,
+            agent,
+            budget: Mutex::new(Budget::default()),
+        })
+    }
+
+    /// The costs reported with the most recent response, if any request has been made yet.
+    pub fn costs(&self) -> Option<SubscriptionCosts> {
+        self.budget().costs()
+    }
+
+    /// The costs and the subscriptions per session, as far as this client has seen them.
+    pub fn budget_view(&self) -> BudgetView {
+        self.budget().view()
+    }
+
+    /// Predicts whether creating the `subscription` on the session would be rejected, see
+    /// `Budget::check`.
+    pub fn check_budget(
+        &self,
+        subscription: &DesiredSubscription,
+        session_id: &str,
+        cost: u64,
+    ) -> Result<(), BudgetErr> {
+        self.budget().check(subscription, session_id, cost)
+    }
+
+    /// Updates the budget with a message that arrived on the session with `session_id`, see
+    /// `Budget::observe`. Sessions feed their messages to it through
+    /// `SubscriptionCreator::observe`, or `Session::on_message`.
+    pub fn observe(&self, session_id: &str, message: &TwitchMessage) {
+        self.budget().observe(session_id, message);
+    }
+
+    /// Creates a subscription, returning the created subscription as reported by Helix. A
+    /// subscription that already exists on its session, or whose session is full, isn't sent, and
+    /// fails with `HelixErr::Budget` instead. Its cost isn't known until Helix reports it, since
+    /// Twitch waives it for subscriptions the user authorized, so exceeding the cost is left to
+    /// Helix to reject; schedulers can check the cost they expect with `check_budget`.
+    pub fn create_subscription(
+        &self,
+        request: &CreateSubscriptionRequest,
+    ) -> Result<Subscription, HelixErr> {
+        let desired =
+            DesiredSubscription::new(&request.r#type, &request.version, request.condition.clone());
+        let session_id = request.transport.session_id.as_deref().unwrap_or_default();
+        self.check_budget(&desired, session_id, 0)?;
+        let list: SubscriptionList = self
+            .send("POST", self.subscriptions_url()?, request)?
+            .into_json()?;
+        self.record_costs(&list);
+        list.data.into_iter().next().ok_or(HelixErr::Empty)
+    }
+
+    /// Fetches a single page of subscriptions, starting at the `after` cursor. `Unknown` is
+    /// rejected as a status filter, since Helix doesn't know it.
+    pub fn list_subscriptions_page(
+        &self,
+        filter: &SubscriptionFilter,
+        after: Option<&str>,
+    ) -> Result<SubscriptionList, HelixErr> {
+        let mut url = self.subscriptions_url()?;
+        {
+            let mut query = url.query_pairs_mut();
+            if let Some(status) = filter.status {
+                if status == SubscriptionStatus::Unknown {
+                    return Err(HelixErr::UnknownStatusFilter);
+                }
+                query.append_pair("status", status.as_str());
+            }
+            if let Some(r#type) = &filter.r#type {
+                query.append_pair("type", r#type);
+            }
+            if let Some(user_id) = &filter.user_id {
+                query.append_pair("user_id", user_id);
+            }
+            if let Some(after) = after {
+                query.append_pair("after", after);
+            }
+        }
+        if url.query() == Some("") {
+            url.set_query(None);
+        }
+        let list: SubscriptionList = self.call("GET", url)?.into_json()?;
+        self.record_costs(&list);
+        Ok(list)
+    }
+
+    /// Lists all subscriptions matching the `filter`, following the pagination cursor until
+    /// every page has been fetched.
+    pub fn list_subscriptions(
+        &self,
+        filter: &SubscriptionFilter,
+    ) -> Result<Vec<Subscription>, HelixErr> {
+        let mut subscriptions = vec![];
+        let mut cursor = None;
+        loop {
+            let page = self.list_subscriptions_page(filter, cursor.as_deref())?;
+            subscriptions.extend(page.data);
+            match page.pagination.cursor {
+                Some(next) if !next.is_empty() => cursor = Some(next),
+                _ => break Ok(subscriptions),
+            }
+        }
+    }
+
+    pub fn delete_subscription(&self, id: &str) -> Result<(), HelixErr> {
+        let mut url = self.subscriptions_url()?;
+        url.query_pairs_mut().append_pair("id", id);
+        self.call("DELETE", url)?;
+        self.budget().record_deleted(id);
+        Ok(())
+    }
+
+    /// Creates a conduit with `shard_count` shards, which don't have a transport yet.
+    pub fn create_conduit(&self, shard_count: u64) -> Result<Conduit, HelixErr> {
+        let list: ConduitList = self
+            .send(
+                "POST",
+                self.endpoint("eventsub/conduits")?,
+                &serde_json::json!({ "shard_count": shard_count }),
+            )?
+            .into_json()?;
+        list.data.into_iter().next().ok_or(HelixErr::Empty)
+    }
+
+    pub fn list_conduits(&self) -> Result<Vec<Conduit>, HelixErr> {
+        let list: ConduitList = self
+            .call("GET", self.endpoint("eventsub/conduits")?)?
+            .into_json()?;
+        Ok(list.data)
+    }
+
+    /// Changes the number of shards of a conduit. Shards removed this way stop receiving
+    /// notifications right away.
+    pub fn update_conduit(&self, id: &str, shard_count: u64) -> Result<Conduit, HelixErr> {
+        let list: ConduitList = self
+            .send(
+                "PATCH",
+                self.endpoint("eventsub/conduits")?,
+                &serde_json::json!({ "id": id, "shard_count": shard_count }),
+            )?
+            .into_json()?;
+        list.data.into_iter().next().ok_or(HelixErr::Empty)
+    }
+
+    pub fn delete_conduit(&self, id: &str) -> Result<(), HelixErr> {
+        let mut url = self.endpoint("eventsub/conduits")?;
+        url.query_pairs_mut().append_pair("id", id);
+        self.call("DELETE", url)?;
+        Ok(())
+    }
+
+    /// Lists all shards of a conduit, optionally only those with the given `status`.
+    pub fn list_shards(
+        &self,
+        conduit_id: &str,
+        status: Option<ShardStatus>,
+    ) -> Result<Vec<Shard>, HelixErr> {
+        let mut shards = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut url = self.endpoint("eventsub/conduits/shards")?;
+            {
+                let mut query = url.query_pairs_mut();
+                query.append_pair("conduit_id", conduit_id);
+                if let Some(status) = status {
+                    query.append_pair("status", status.as_str());
+                }
+                if let Some(after) = &cursor {
+                    query.append_pair("after", after);
+                }
+            }
+            let page: ShardList = self.call("GET", url)?.into_json()?;
+            shards.extend(page.data);
+            match page.pagination.cursor {
+                Some(next) if !next.is_empty() => cursor = Some(next),
+                _ => break Ok(shards),
+            }
+        }
+    }
+
+    /// Points shards of a conduit at new transports, such as the WebSocket session of a shard
+    /// that reconnected.
+    pub fn update_shards(
+        &self,
+        conduit_id: &str,
+        shards: &[ShardUpdate],
+    ) -> Result<ShardUpdateReport, HelixErr> {
+        Ok(self
+            .send(
+                "PATCH",
+                self.endpoint("eventsub/conduits/shards")?,
+                &serde_json::json!({ "conduit_id": conduit_id, "shards": shards }),
+            )?
+            .into_json()?)
+    }
+
+    fn subscriptions_url(&self) -> Result<Url, HelixErr> {
+        self.endpoint("eventsub/subscriptions")
+    }
+
+    fn endpoint(&self, path: &str) -> Result<Url, HelixErr> {
+        let base = self.base_url.as_str().trim_end_matches('/');
+        Ok(Url::parse(&format!("{}/{}", base, path))?)
+    }
+
+    fn call(&self, method: &str, url: Url) -> Result<ureq::Response, HelixErr> {
+        self.send(method, url, &())
+    }
+
+    /// Sends the request with the current token, and once more with a refreshed token if that
+    /// one was rejected. Unit bodies aren't sent at all.
+    fn send<B: Serialize>(
+        &self,
+        method: &str,
+        url: Url,
+        body: &B,
+    ) -> Result<ureq::Response, HelixErr> {
+        let body = serde_json::to_value(body).map_err(std::io::Error::from)?;
+        let attempt = |token: &str| {
+            let request = self
+                .agent
+                .request_url(method, &url)
+                .set("Client-Id", &self.client_id)
+                .set("Authorization", &format!("Bearer {}", token));
+            match &body {
+                Value::Null => request.call(),
+                body => request.send_json(body),
+            }
+        };
+        let token = self.tokens.access_token()?;
+        match attempt(&token) {
+            Err(ureq::Error::Status(401, _)) => Ok(attempt(&self.tokens.refresh(&token)?)?),
+            response => Ok(response?),
+        }
+    }
+
+    fn record_costs(&self, list: &SubscriptionList) {
+        self.budget().record(list);
+    }
+
+    fn budget(&self) -> MutexGuard<'_, Budget> {
+        self.budget.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl SubscriptionCreator for HelixClient {
+    fn create_subscription(
+        &self,
+        subscription: &DesiredSubscription,
+        session_id: &str,
+    ) -> Result<(), SubscriptionErr> {
+        let request = CreateSubscriptionRequest::websocket(subscription, session_id);
+        HelixClient::create_subscription(self, &request)?;
+        Ok(())
+    }
+
+    fn costs(&self) -> Option<SubscriptionCosts> {
+        HelixClient::costs(self)
+    }
+
+    fn observe(&self, session_id: &str, message: &TwitchMessage) {
+        HelixClient::observe(self, session_id, message);
+    }
+}
+
+impl ShardAssigner for HelixClient {
+    fn assign_shard(
+        &self,
+        conduit_id: &str,
+        shard_id: &str,
+        session_id: &str,
+    ) -> Result<(), SubscriptionErr> {
+        let report =
+            self.update_shards(conduit_id, &[ShardUpdate::websocket(shard_id, session_id)])?;
+        match report.errors.into_iter().next() {
+            Some(error) => Err(SubscriptionErr::Rejected(format!(
+                "{}: {}",
+                error.code, error.message
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A minimal HTTP server answering requests with canned responses, in order, while recording the
+/// requests it received.
+#[cfg(test)]
+pub(crate) mod mock_api {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+
+    #[derive(Debug)]
+    pub struct RecordedRequest {
+        pub method: String,
+        pub path: String,
+        pub headers: Vec<(String, String)>,
+        pub body: String,
+    }
+
+    impl RecordedRequest {
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        }
+    }
+
+    /// Serves `responses` as `(status, body)` pairs, and returns the server's base url along
+    /// with a receiver for the recorded requests.
+    pub fn serve(responses: Vec<(u16, String)>) -> (String, Receiver<RecordedRequest>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for (status, body) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let mut parts = line.split_whitespace();
+                let method = parts.next().unwrap_or_default().to_owned();
+                let path = parts.next().unwrap_or_default().to_owned();
+                let mut headers = vec![];
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    match line.trim_end().split_once(": ") {
+                        Some((key, value)) => headers.push((key.to_owned(), value.to_owned())),
+                        None => break,
+                    }
+                }
+                let length = headers
+                    .iter()
+                    .find(|(key, _)| key.eq_ignore_ascii_case("content-length"))
+                    .map_or(0, |(_, value)| value.parse().unwrap());
+                let mut body_bytes = vec![0; length];
+                reader.read_exact(&mut body_bytes).unwrap();
+                let _ = tx.send(RecordedRequest {
+                    method,
+                    path,
+                    headers,
+                    body: String::from_utf8(body_bytes).unwrap(),
+                });
+                let mut stream = reader.into_inner();
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+        (url, rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn subscription_json(id: &str, status: &str) -> Value {
+        json!({
+            "id": id,
+            "status": status,
+            "type": "channel.follow",
+            "version": "2",
+            "condition": {"broadcaster_user_id": "1", "moderator_user_id": "1"},
+            "created_at": "2023-01-01T00:00:00Z",
+            "transport": {"method": "websocket", "session_id": "session"},
+            "cost": 0
+        })
+    }
+
+    #[test]
+    fn create_subscription_records_costs() {
+        let body = json!({
+            "data": [subscription_json("a", "enabled")],
+            "total": 1, "total_cost": 1, "max_total_cost": 10
+        });
+        let (url, requests) = mock_api::serve(vec![(202, body.to_string())]);
+        let client = HelixClient::with_base_url("id", "token", Url::parse(&url).unwrap()).unwrap();
+        let desired = DesiredSubscription::new(
+            "channel.follow",
+            "2",
+            json!({"broadcaster_user_id": "1", "moderator_user_id": "1"}),
+        );
+        let created = client
+            .create_subscription(&CreateSubscriptionRequest::websocket(&desired, "session"))
+            .unwrap();
+        assert_eq!(created.to_desired(), desired);
+        assert_eq!(client.costs().unwrap().remaining(), 9);
+
+        let request = requests.recv().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/eventsub/subscriptions");
+        assert_eq!(request.header("Authorization"), Some("Bearer token"));
+        assert_eq!(request.header("Client-Id"), Some("id"));
+        let body: Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(
+            body["transport"],
+            json!({"method": "websocket", "session_id": "session"})
+        );
+
+        // Helix would answer the same subscription with 409 Conflict, so it isn't sent
+        let again =
+            client.create_subscription(&CreateSubscriptionRequest::websocket(&desired, "session"));
+        assert!(matches!(
+            again,
+            Err(HelixErr::Budget(BudgetErr::Duplicate(id))) if id == "a"
+        ));
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn free_subscriptions_are_created_at_the_cost_limit() {
+        let at_limit = json!({"data": [], "total": 10, "total_cost": 10, "max_total_cost": 10});
+        let created = json!({
+            "data": [subscription_json("a", "enabled")],
+            "total": 11, "total_cost": 10, "max_total_cost": 10
+        });
+        let (url, requests) = mock_api::serve(vec![
+            (200, at_limit.to_string()),
+            (202, created.to_string()),
+        ]);
+        let client = HelixClient::with_base_url("id", "token", Url::parse(&url).unwrap()).unwrap();
+        client
+            .list_subscriptions_page(&SubscriptionFilter::default(), None)
+            .unwrap();
+        assert_eq!(client.costs().unwrap().remaining(), 0);
+
+        let desired = DesiredSubscription::new(
+            "channel.follow",
+            "2",
+            json!({"broadcaster_user_id": "1", "moderator_user_id": "1"}),
+        );
+        client
+            .create_subscription(&CreateSubscriptionRequest::websocket(&desired, "session"))
+            .unwrap();
+        let methods: Vec<String> = requests
+            .iter()
+            .take(2)
+            .map(|request| request.method)
+            .collect();
+        assert_eq!(methods, ["GET", "POST"]);
+    }
+
+    #[test]
+    fn list_subscriptions_follows_pagination() {
+        let first = json!({
+            "data": [subscription_json("a", "websocket_disconnected")],
+            "total": 2, "total_cost": 0, "max_total_cost": 10,
+            "pagination": {"cursor": "next"}
+        });
+        let second = json!({
+            "data": [subscription_json("b", "websocket_disconnected")],
+            "total": 2, "total_cost": 0, "max_total_cost": 10,
+            "pagination": {}
+        });
+        let (url, requests) =
+            mock_api::serve(vec![(200, first.to_string()), (200, second.to_string())]);
+        let client = HelixClient::with_base_url("id", "token", Url::parse(&url).unwrap()).unwrap();
+        let filter = SubscriptionFilter {
+            status: Some(SubscriptionStatus::WebsocketDisconnected),
+            ..Default::default()
+        };
+        let ids: Vec<String> = client
+            .list_subscriptions(&filter)
+            .unwrap()
+            .into_iter()
+            .map(|subscription| subscription.id)
+            .collect();
+        assert_eq!(ids, vec!["a", "b"]);
+        assert_eq!(
+            requests.recv().unwrap().path,
+            "/eventsub/subscriptions?status=websocket_disconnected"
+        );
+        assert_eq!(
+            requests.recv().unwrap().path,
+            "/eventsub/subscriptions?status=websocket_disconnected&after=next"
+        );
+
+        let unknown = SubscriptionFilter {
+            status: Some(SubscriptionStatus::Unknown),
+            ..Default::default()
+        };
+        assert!(matches!(
+            client.list_subscriptions(&unknown),
+            Err(HelixErr::UnknownStatusFilter)
+        ));
+    }
+
+    #[test]
+    fn shard_errors_reject_the_assignment() {
+        let body = json!({
+            "data": [],
+            "errors": [{"id": "3", "message": "The shard id is outside the conduit's range", "code": "invalid_parameter"}]
+        });
+        let (url, requests) = mock_api::serve(vec![(202, body.to_string())]);
+        let client = HelixClient::with_base_url("id", "token", Url::parse(&url).unwrap()).unwrap();
+        match client.assign_shard("conduit", "3", "session") {
+            Err(SubscriptionErr::Rejected(message)) => {
+                assert!(message.starts_with("invalid_parameter"))
+            }
+            other => panic!("expected the shard to be rejected, got {:?}", other),
+        }
+
+        let request = requests.recv().unwrap();
+        assert_eq!(request.method, "PATCH");
+        assert_eq!(request.path, "/eventsub/conduits/shards");
+        let body: Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "conduit_id": "conduit",
+                "shards": [{"id": "3", "transport": {"method": "websocket", "session_id": "session"}}]
+            })
+        );
+    }
+
+    #[test]
+    fn errors_carry_helix_message() {
+        let body =
+            json!({"error": "Conflict", "status": 409, "message": "subscription already exists"});
+        let (url, _requests) = mock_api::serve(vec![(409, body.to_string())]);
+        let client = HelixClient::with_base_url("id", "token", Url::parse(&url).unwrap()).unwrap();
+        match client.delete_subscription("a") {
+            Err(HelixErr::Status { status, message }) => {
+                assert_eq!(status, 409);
+                assert_eq!(message, "subscription already exists");
+            }
+            other => panic!("expected status error, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use std::time::{Duration, Instant};
+
+/// How often blocking reads return control to the message loop, so the watchdog can check on
+/// the connection even while nothing arrives.
+pub const READ_TICK: Duration = Duration::from_millis(250);
+
+/// Tracks when the last message arrived, to notice a dead connection once the keepalive time
+/// Twitch announced in the `Welcome` message has passed without any message. Every message counts
+/// towards this, not only `Keepalive` messages.
+///
+/// The watchdog doesn't depend on the socket's read timeout, so it works the same for every kind
+/// of stream. The message loop merely wakes up every `READ_TICK` to check it.
+#[derive(Debug, Clone)]
+pub struct KeepaliveWatchdog {
+    last_message_at: Instant,
+    keepalive: Option<Duration>,
+    /// Extra time allowed on top of the keepalive time, before the connection is given up on.
+    pub grace: Duration,
+}
+
+impl KeepaliveWatchdog {
+    pub fn new(grace: Duration) -> KeepaliveWatchdog {
+        KeepaliveWatchdog {
+            last_message_at: Instant::now(),
+            keepalive: None,
+            grace,
+        }
+    }
+
+    /// Records that a message arrived at `now`.
+    pub fn feed(&mut self, now: Instant) {
+        self.last_message_at = now;
+    }
+
+    pub fn set_keepalive(&mut self, keepalive: Duration) {
+        self.keepalive = Some(keepalive);
+    }
+
+    pub fn keepalive(&self) -> Option<Duration> {
+        self.keepalive
+    }
+
+    pub fn last_message_at(&self) -> Instant {
+        self.last_message_at
+    }
+
+    /// How long the connection has been silent by `now`, if that is longer than the keepalive
+    /// time plus the grace period. Nothing is enforced until the keepalive time is known.
+    pub fn missed(&self, now: Instant) -> Option<Duration> {
+        let silent_for = now.saturating_duration_since(self.last_message_at);
+        match self.keepalive {
+            Some(keepalive) if silent_for > keepalive + self.grace => Some(silent_for),
+            _ => None,
+        }
+    }
+}
+
+impl Default for KeepaliveWatchdog {
+    fn default() -> Self {
+        KeepaliveWatchdog::new(Duration::from_secs(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_enforced_once_keepalive_is_known() {
+        let mut watchdog = KeepaliveWatchdog::new(Duration::ZERO);
+        let later = watchdog.last_message_at() + Duration::from_secs(60);
+        assert_eq!(watchdog.missed(later), None);
+
+        watchdog.set_keepalive(Duration::from_secs(10));
+        assert_eq!(watchdog.missed(later), Some(Duration::from_secs(60)));
+
+        watchdog.feed(later);
+        assert_eq!(watchdog.missed(later), None);
+    }
+}
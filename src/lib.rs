@@ -1,44 +1,87 @@
-#![allow(clippy::uninlined_format_args)]
+#![allow(
+    clippy::uninlined_format_args,
+    clippy::result_large_err,
+    clippy::large_enum_variant
+)]
 
-use std::sync::{mpsc::Sender, Arc, Mutex};
+use std::io;
+use std::net::TcpStream;
+use std::sync::{
+    mpsc::{self, Sender},
+    Arc, Mutex,
+};
 use std::thread;
 use std::time::Duration;
 pub use tungstenite::protocol::frame::{coding::CloseCode, CloseFrame};
 use url::Url;
 
+use crate::close::{CloseReason, Retry};
 use crate::error::*;
+use crate::events::ClientEvent;
+use crate::keepalive::READ_TICK;
+use crate::migration::Migration;
+use crate::source::{Connector, WebSocketConnector};
 use crate::types::{EventResult, Session, Socket, TwitchMessage};
+use tungstenite::error::ProtocolError;
+use tungstenite::{HandshakeError, Message};
 
 pub use serde_json::from_str as parse_message;
 
+pub mod accounts;
+pub mod auth;
+pub mod budget;
+pub mod clock;
+pub mod close;
+pub mod conduit;
+pub mod dedup;
 pub mod error;
+pub mod events;
 pub mod handlers;
+pub mod helix;
+pub mod keepalive;
+pub mod migration;
+#[cfg(any(test, feature = "testing"))]
+pub mod mock;
+pub mod pool;
+pub mod reconcile;
+pub mod recording;
+pub mod scopes;
+pub mod source;
+pub mod status;
+pub mod subscriptions;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod types;
+#[cfg(feature = "webhook")]
+pub mod webhook;
 
 pub const EVENTSUB_URL: &str = "wss://eventsub-beta.wss.twitch.tv/ws";
 
+/// How long opening a connection may take, including the TLS and WebSocket handshakes.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The longest wait between two reconnection attempts, before jitter is added.
+pub const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
 /// Creates the loop that handles Twitch's messages as they come in, passing them through to the
 /// caller via the `message_forwarder`. This is a blocking function, which should be called in a
 /// background thread.
 ///
 ///
 /// ```
-/// # // TODO: Run test server instead of connecting to Twitch's EventSub server for tests
-/// use eventsub_websocket::{create_message_processor, get_default_url, get_session};
+/// use eventsub_websocket::{create_message_processor, get_session};
 /// use eventsub_websocket::types::TwitchMessage;
 /// use std::sync::{mpsc, Arc};
 /// use std::thread;
+/// # use eventsub_websocket::mock::{self, MockServer, Step};
+/// # let server = MockServer::start(vec![vec![Step::Send(mock::welcome("session", 10))]]);
 ///
 /// let (message_forwarder, message_receiver) = mpsc::channel();
-/// let url = get_default_url().unwrap();
+/// // Connects to a local mock server, `get_default_url()` returns Twitch's url instead
+/// let url = server.url();
 /// let session = get_session(url).unwrap();
 /// let message_processor = thread::spawn(move || {
-///     create_message_processor(
-///         Arc::clone(&session),
-///         &message_forwarder,
-///         false,
-///         false
-///     )
+///     create_message_processor(Arc::clone(&session), &message_forwarder)
 /// });
 ///
 /// assert!(matches!(message_receiver.recv().unwrap(), TwitchMessage::Welcome(_)));
@@ -46,47 +89,40 @@ pub const EVENTSUB_URL: &str = "wss://eventsub-beta.wss.twitch.tv/ws";
 pub fn create_message_processor(
     eventsub_session: Arc<Mutex<Session>>,
     message_forwarder: &Sender<TwitchMessage>,
-    reconnect_to_twitch: bool,
-    close_old_connection: bool,
 ) -> std::result::Result<(), EventSubErr> {
-    // The close message needs to be sent if the connection is supposed to be replaced, but after
-    // sending the close message, other messages can still come in before Twitch sends a close
-    // message in return.
-    let mut sent_close_message = false;
+    let stop = eventsub_session.lock()?.stop_handle();
     loop {
-        if close_old_connection && !sent_close_message {
-            eventsub_session.lock()?.socket.close(Some(CloseFrame {
-                code: CloseCode::Normal,
-                reason: "Received reconnect message.".into(),
-            }))?;
-            sent_close_message = true;
+        if stop.is_stopped() {
+            // Nothing is read anymore, so the reply to the close frame isn't waited for
+            let _ = eventsub_session.lock()?.socket.close(None);
+            break;
         }
-
-        let msg = {
+        let next = {
             let session = &mut eventsub_session.lock()?;
-            let reconnect_url = session.eventsub_url.clone();
-            let socket = &mut session.socket;
-            if !socket.can_read() && close_old_connection {
-                break;
-            }
-            match socket.read_message() {
-                Ok(msg) => msg,
-                Err(err) => match err {
-                    tungstenite::Error::ConnectionClosed => return Err(err.into()),
-                    tungstenite::Error::Io(err) => {
-                        println!("Connection lost\n\t{}\n\tReconnecting...", err);
-                        attempt_reconnection(socket, reconnect_url)?;
-                        continue;
-                    }
-                    _ => {
-                        return Err(err.into());
-                    }
+            match session.backlog.pop_front() {
+                Some(msg) => Next::Message {
+                    msg,
+                    from_new_connection: false,
                 },
+                None => read_message(session)?,
             }
         };
+        let (msg, from_new_connection) = match next {
+            Next::Message {
+                msg,
+                from_new_connection,
+            } => (msg, from_new_connection),
+            Next::Continue => continue,
+            // The session is unlocked while waiting, so it stays available to other threads
+            Next::Reconnect { first_delay } => {
+                reconnect(&eventsub_session, first_delay)?;
+                continue;
+            }
+            Next::Stop => break,
+        };
 
         let msg_raw = msg.to_text()?.to_owned();
-        let msg: TwitchMessage = match serde_json::from_str(&msg_raw) {
+        let twitch_msg: TwitchMessage = match serde_json::from_str(&msg_raw) {
             Ok(msg) => msg,
             Err(_) => {
                 if !msg_raw.is_empty() {
@@ -97,169 +133,412 @@ pub fn create_message_processor(
             }
         };
 
+        {
+            let session = &mut eventsub_session.lock()?;
+            let received_at = session.clock.wall();
+            session.metrics.message_received(&twitch_msg, received_at);
+            if session
+                .handled_messsage_ids
+                .is_stale(twitch_msg.timestamp(), received_at)
+            {
+                session.emit(ClientEvent::StaleMessage {
+                    message_id: twitch_msg.id(),
+                });
+                continue;
+            }
+        }
+
+        if from_new_connection && !matches!(twitch_msg, TwitchMessage::Welcome(_)) {
+            // Anything the new connection sends after its `Welcome` has to wait until the old
+            // connection is drained, to keep the messages in order.
+            if let Some(migration) = &mut eventsub_session.lock()?.migration {
+                migration.buffer(msg);
+            }
+            continue;
+        }
+
         if eventsub_session
             .lock()?
             .handled_messsage_ids
-            .contains(&msg.id())
+            .contains(&twitch_msg.id())
         {
-            println!("Duplicate message: {:#?}", msg);
+            println!("Duplicate message: {:#?}", twitch_msg);
             continue;
         }
 
-        let message_is_welcome: bool = matches!(msg, TwitchMessage::Welcome(_));
+        eventsub_session.lock()?.observe(&twitch_msg);
+        twitch_msg.handle(Some(Arc::clone(&eventsub_session)))?;
 
-        if let Err(err) = msg.handle(Some(Arc::clone(&eventsub_session)), message_forwarder) {
-            match err {
-                HandlerErr::Welcome(WelcomeHandlerErr::NoKeepalive(_)) => {
-                    // A Welcome message in response to a reconnection attempt is not supposed
-                    // to carry a new keepalive time, but the keepalive time should *not* be
-                    // missing for an initial Welcome message.
-                    if !reconnect_to_twitch {
-                        return Err(err.into());
-                    }
+        {
+            let session = &mut eventsub_session.lock()?;
+            let now = session.clock.now();
+            session.handled_messsage_ids.insert(twitch_msg.id(), now);
+        }
+        message_forwarder.send(twitch_msg)?;
+    }
+    Ok(())
+}
+
+/// The result of reading from the session's connections.
+enum Next {
+    /// A message was read, which came from the new connection if a migration is under way.
+    Message {
+        msg: Message,
+        from_new_connection: bool,
+    },
+    /// Nothing was read, e.g. because the connection was replaced.
+    Continue,
+    /// The connection was lost, and has to be replaced after waiting for `first_delay`.
+    Reconnect { first_delay: Duration },
+    /// The connection was closed by the client.
+    Stop,
+}
+
+/// Reads the next message from the connection the `Migration` is at, or the session's socket if
+/// there is none. Lost and closed connections are replaced, as far as their `CloseReason` allows,
+/// and so are connections that went silent for longer than the keepalive time.
+fn read_message(session: &mut Session) -> std::result::Result<Next, EventSubErr> {
+    if let Some(read) = session.migration.as_mut().and_then(Migration::read_welcome) {
+        let overdue = session
+            .migration
+            .as_ref()
+            .is_some_and(|migration| migration.welcome_overdue(session.clock.now()));
+        let error = match read {
+            Ok(msg) => {
+                if let Some(migration) = &session.migration {
+                    let connection = migration.connection();
+                    session.record(connection, &msg);
                 }
-                _ => return Err(err.into()),
+                session.watchdog.feed(session.clock.now());
+                return Ok(Next::Message {
+                    msg,
+                    from_new_connection: true,
+                });
             }
+            Err(tungstenite::Error::Io(err)) if is_tick(&err) && !overdue => {
+                return Ok(Next::Continue)
+            }
+            Err(tungstenite::Error::Io(err)) if is_tick(&err) => {
+                "no welcome message received in time".to_owned()
+            }
+            Err(err) => err.to_string(),
         };
+        // The old connection keeps working until Twitch closes it for not having migrated in
+        // time, which then triggers a regular reconnect.
+        session.migration = None;
+        session.emit(ClientEvent::Disconnected {
+            reason: format!("couldn't migrate to the reconnect url: {}", error),
+        });
+        return Ok(Next::Continue);
+    }
 
-        eventsub_session.lock()?.handled_messsage_ids.push(msg.id());
-        message_forwarder.send(msg)?;
+    let now = session.clock.now();
+    if let Some(payload) = session.metrics.ping_due(now) {
+        session
+            .socket
+            .write_message(Message::Ping(payload.into_bytes()))?;
+    }
 
-        if message_is_welcome && reconnect_to_twitch {
-            // If `reconnect_to_twitch` was set, only the `Welcome` message needs special handling.
-            // Afterwards, handling will be handed over to the original loop, by replacing the
-            // socket of the original session.
-            break;
-        };
+    let draining = matches!(session.migration, Some(Migration::DrainingOld { .. }));
+    // Once a close frame has been sent, the next one received is just the reply to it.
+    let closed_by_us = !session.socket.can_write();
+    let read = session.socket.read_message();
+    if let Ok(msg) = &read {
+        session.record(session.connection_id, msg);
+    }
+    match read {
+        Err(tungstenite::Error::Io(err)) if is_tick(&err) => {
+            match session.watchdog.missed(session.clock.now()) {
+                Some(_) if draining => {
+                    session.complete_migration();
+                    Ok(Next::Continue)
+                }
+                Some(silent_for) => {
+                    session.emit(ClientEvent::KeepaliveMissed { silent_for });
+                    Ok(Next::Reconnect {
+                        first_delay: Duration::ZERO,
+                    })
+                }
+                None => Ok(Next::Continue),
+            }
+        }
+        Ok(Message::Close(_))
+        | Err(
+            tungstenite::Error::ConnectionClosed
+            | tungstenite::Error::Io(_)
+            | tungstenite::Error::Protocol(ProtocolError::ResetWithoutClosingHandshake),
+        ) if draining => {
+            session.complete_migration();
+            Ok(Next::Continue)
+        }
+        Ok(Message::Close(frame)) => {
+            // 1005 is reserved for close frames which carry no status code
+            let reason = CloseReason::from(frame.map_or(1005, |frame| frame.code.into()));
+            session.emit(ClientEvent::Closed {
+                code: reason.code(),
+                reason,
+            });
+            if closed_by_us {
+                return Ok(Next::Stop);
+            }
+            let first_delay = match reason.retry() {
+                Retry::Never => return Err(EventSubErr::Closed(reason)),
+                Retry::Immediately => Duration::ZERO,
+                Retry::WithBackoff => Duration::from_secs(1),
+            };
+            Ok(Next::Reconnect { first_delay })
+        }
+        Ok(Message::Ping(_)) => {
+            let now = session.clock.now();
+            session.watchdog.feed(now);
+            session.metrics.ping_received(now);
+            Ok(Next::Continue)
+        }
+        Ok(Message::Pong(payload)) => {
+            let now = session.clock.now();
+            session.watchdog.feed(now);
+            session.metrics.pong_received(&payload, now);
+            Ok(Next::Continue)
+        }
+        Ok(msg) => {
+            session.watchdog.feed(session.clock.now());
+            Ok(Next::Message {
+                msg,
+                from_new_connection: false,
+            })
+        }
+        // A connection dropped without a close frame, possibly in the middle of a message
+        Err(
+            err @ (tungstenite::Error::Io(_)
+            | tungstenite::Error::Protocol(ProtocolError::ResetWithoutClosingHandshake)),
+        ) => {
+            session.emit(ClientEvent::Disconnected {
+                reason: err.to_string(),
+            });
+            Ok(Next::Reconnect {
+                first_delay: Duration::ZERO,
+            })
+        }
+        Err(tungstenite::Error::ConnectionClosed) => Ok(Next::Stop),
+        Err(err) => Err(err.into()),
     }
-    Ok(())
+}
+
+/// Whether the `err` only means that nothing arrived within the `READ_TICK`.
+fn is_tick(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
 }
 
 pub fn get_default_url() -> Result<Url, EventSubErr> {
     Url::parse(EVENTSUB_URL).map_err(|err| err.into())
 }
 
-fn attempt_reconnection(
-    socket: &mut Socket,
-    reconnect_url: Url,
+/// Replaces the `session`'s socket with a fresh connection to its url, after waiting for
+/// `first_delay`, and retrying with an exponential backoff of up to `MAX_RECONNECT_DELAY`, plus
+/// jitter. The session is only locked in between waiting and connecting. The next `Welcome`
+/// message will then be reported as a reconnect. Once the session's `max_reconnect_attempts`
+/// have failed, `ReconnectFailed` is emitted and returned. Stopping the session cuts the waiting
+/// short, and leaves the old socket in place.
+fn reconnect(
+    session: &Arc<Mutex<Session>>,
+    first_delay: Duration,
 ) -> std::result::Result<(), EventSubErr> {
-    let mut reconnect_wait_time_seconds = 1;
+    let (clock, connector, url, max_attempts, stop) = {
+        let session = session.lock()?;
+        (
+            Arc::clone(&session.clock),
+            Arc::clone(&session.connector),
+            session.eventsub_url.clone(),
+            session.max_reconnect_attempts,
+            session.stop_handle(),
+        )
+    };
+    let mut reconnect_wait_time = first_delay;
+    let mut attempt = 1;
     loop {
-        match get_session(reconnect_url.clone()) {
-            Ok(new_session) => {
-                let new_socket = &mut new_session.lock()?.socket;
-                std::mem::swap(socket, new_socket);
-                println!("Reconnected!");
+        if max_attempts.is_some_and(|max| attempt > max) {
+            let attempts = attempt - 1;
+            session
+                .lock()?
+                .emit(ClientEvent::ReconnectFailed { attempts });
+            break Err(EventSubErr::ReconnectFailed(attempts));
+        }
+        let delay = with_jitter(reconnect_wait_time);
+        session
+            .lock()?
+            .emit(ClientEvent::Reconnecting { attempt, delay });
+        let resume_at = clock.now() + delay;
+        while !stop.is_stopped() && clock.now() < resume_at {
+            clock.sleep((resume_at - clock.now()).min(READ_TICK));
+        }
+        if stop.is_stopped() {
+            break Ok(());
+        }
+        match connector.connect(&url) {
+            Ok(socket) => {
+                let mut session = session.lock()?;
+                session.socket = socket;
+                session.connection_id = session.next_connection_id();
+                session.reconnecting = true;
+                // Give the new connection the full keepalive time to send its `Welcome`
+                let now = session.clock.now();
+                session.watchdog.feed(now);
                 break Ok(());
             }
-            Err(err) => {
-                println!(
-                    "Failed to connect:\n\t{}\n\tRetrying in {}s...",
-                    err, reconnect_wait_time_seconds
-                );
-                thread::sleep(Duration::from_secs(reconnect_wait_time_seconds));
-                reconnect_wait_time_seconds *= 2;
+            Err(_) => {
+                reconnect_wait_time = (reconnect_wait_time * 2)
+                    .max(Duration::from_secs(1))
+                    .min(MAX_RECONNECT_DELAY);
+                attempt += 1;
             }
         }
     }
 }
 
+/// Adds up to a tenth of the `delay` on top, so clients which lost their connections at the same
+/// time don't all retry at once.
+fn with_jitter(delay: Duration) -> Duration {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    let random = RandomState::new().build_hasher().finish();
+    delay + (delay / 10).mul_f64((random % 1000) as f64 / 1000.0)
+}
+
 pub fn event_handler(
     url: Url,
     tx: Sender<TwitchMessage>,
 ) -> std::result::Result<EventResult, EventSubErr> {
     let session = get_session(url)?;
+    let (event_forwarder, events) = mpsc::channel();
+    session.lock()?.event_forwarder = Some(event_forwarder);
     let session_clone = Arc::clone(&session);
     let listener =
         thread::Builder::new()
             .name("listener".into())
             .spawn(move || -> Result<(), String> {
-                create_message_processor(session_clone, &tx, false, false)?;
+                create_message_processor(session_clone, &tx)?;
                 Ok(())
             })?;
-    Ok(EventResult { listener, session })
+    Ok(EventResult {
+        listener,
+        session,
+        events,
+    })
 }
 
 pub fn get_session(url: Url) -> Result<Arc<Mutex<Session>>, EventSubErr> {
-    let (socket, _) = tungstenite::connect(&url)?;
-    Ok(Arc::new(Mutex::new(Session::new(socket, url))))
+    get_session_with(url, Arc::new(WebSocketConnector))
+}
+
+/// Opens a session through the `connector`, which is used for every later connection as well.
+pub fn get_session_with(
+    url: Url,
+    connector: Arc<dyn Connector>,
+) -> Result<Arc<Mutex<Session>>, EventSubErr> {
+    let socket = connector.connect(&url)?;
+    Ok(Arc::new(Mutex::new(Session::with_connector(
+        socket, url, connector,
+    ))))
+}
+
+/// Opens a WebSocket connection to the `url`, whose reads return every `READ_TICK` so the
+/// `KeepaliveWatchdog` can be checked. The timeout is set on the TCP stream before it is wrapped,
+/// so this works the same no matter whether TLS is used.
+pub(crate) fn connect(url: &Url) -> std::result::Result<Socket, tungstenite::Error> {
+    let stream = TcpStream::connect(&*url.socket_addrs(|| None)?)?;
+    // The handshake itself needs to block, since it can't be resumed after a timeout
+    stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+    let ticking_stream = stream.try_clone()?;
+    let (socket, _) = tungstenite::client_tls(url.as_str(), stream).map_err(|err| match err {
+        HandshakeError::Failure(err) => err,
+        HandshakeError::Interrupted(_) => io::Error::from(io::ErrorKind::TimedOut).into(),
+    })?;
+    ticking_stream.set_read_timeout(Some(READ_TICK))?;
+    Ok(socket)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::process::{Child, Command};
+    use crate::clock::{Clock, ManualClock, SystemClock};
+    use crate::dedup::MESSAGE_TTL;
+    use crate::migration::ReconnectUrlPolicy;
+    use crate::mock::{self, MockServer, Step};
     use std::sync::mpsc::{self, Receiver, Sender};
     use std::thread;
 
-    static COMMAND: &str = "./scripts/test_server.sh";
-
-    fn start_server(reconnect: bool, port: u32) -> Child {
-        let mut command = Command::new(COMMAND);
-        command.arg("--port").arg(&format!("{}", port));
-        if reconnect {
-            command.arg("--reconnect").arg("1");
-        }
-        command.spawn().expect("failed to start server")
-    }
-
-    #[test]
-    fn connect_to_mock() {
-        let mut handle = start_server(false, 8080);
-        thread::sleep(std::time::Duration::from_secs(1));
-        let session = get_session(Url::parse("ws://localhost:8080/eventsub").unwrap()).unwrap();
+    fn close(session: &Arc<Mutex<Session>>, reason: &'static str) {
         session
             .lock()
             .unwrap()
             .socket
             .close(Some(CloseFrame {
                 code: CloseCode::Normal,
-                reason: "Closing after connect test.".into(),
+                reason: reason.into(),
             }))
             .unwrap();
-        handle.kill().unwrap();
+    }
+
+    #[test]
+    fn connect_to_mock() {
+        let server = MockServer::start(vec![vec![Step::Send(mock::welcome("session", 10))]]);
+        let session = get_session(server.url()).unwrap();
+        close(&session, "Closing after connect test.");
+        while server.received(0).is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(matches!(server.received(0)[0], Message::Close(_)));
     }
 
     #[test]
     fn handle_welcome_message() {
-        let mut handle = start_server(false, 8082);
-        thread::sleep(std::time::Duration::from_secs(1));
+        let server = MockServer::start(vec![vec![Step::Send(mock::welcome("session", 10))]]);
         let (tx, rx): (Sender<TwitchMessage>, Receiver<TwitchMessage>) = mpsc::channel();
-        let res = event_handler(Url::parse("ws://localhost:8082/eventsub").unwrap(), tx).unwrap();
+        let res = event_handler(server.url(), tx).unwrap();
         loop {
             let msg: TwitchMessage = rx.recv().map_err(|err| format!("{}", err)).unwrap();
-            match msg {
-                TwitchMessage::Welcome(_) => {
-                    res.session
-                        .lock()
-                        .unwrap()
-                        .socket
-                        .close(Some(CloseFrame {
-                            code: CloseCode::Normal,
-                            reason: "Closing after Welcome test.".into(),
-                        }))
-                        .unwrap();
-                    break;
-                }
-                _ => {}
+            if let TwitchMessage::Welcome(msg) = msg {
+                assert_eq!(
+                    res.events.recv().unwrap(),
+                    ClientEvent::Connected {
+                        session_id: msg.payload.session.id
+                    }
+                );
+                close(&res.session, "Closing after Welcome test.");
+                break;
             }
         }
-        handle.kill().unwrap();
+        assert!(res.listener.join().unwrap().is_ok());
     }
 
     #[test]
     fn handle_reconnect_message() {
-        let mut handle = start_server(true, 8084);
-        thread::sleep(std::time::Duration::from_secs(1));
+        let server = MockServer::start(vec![
+            vec![
+                Step::Send(mock::welcome("session", 10)),
+                Step::Reconnect,
+                Step::WaitFor("welcomed"),
+                Step::Close(1000),
+            ],
+            vec![
+                Step::Send(mock::welcome("session", None)),
+                Step::Signal("welcomed"),
+                Step::Send(mock::keepalive()),
+            ],
+        ]);
         let mut welcome_count = 0;
         let (tx, rx): (Sender<TwitchMessage>, Receiver<TwitchMessage>) = mpsc::channel();
-        let session = get_session(Url::parse("ws://localhost:8084/eventsub").unwrap()).unwrap();
+        let session = get_session(server.url()).unwrap();
+        session.lock().unwrap().reconnect_policy = ReconnectUrlPolicy::with_loopback();
         let tx_clone = tx.clone();
         let move_sess = Arc::clone(&session);
         thread::Builder::new()
             .name("listener".into())
             .spawn(move || -> Result<(), String> {
-                create_message_processor(move_sess, &tx_clone, false, false)?;
+                create_message_processor(move_sess, &tx_clone)?;
                 Ok(())
             })
             .unwrap();
@@ -269,24 +548,513 @@ mod tests {
                 TwitchMessage::Welcome(_) => {
                     welcome_count += 1;
                 }
-                TwitchMessage::Keepalive(_) => {
-                    if welcome_count >= 2 {
-                        // Verify that the new connection is still healthy
-                        session
-                            .lock()
-                            .unwrap()
-                            .socket
-                            .close(Some(CloseFrame {
-                                code: CloseCode::Normal,
-                                reason: "Closing after reconnect test.".into(),
-                            }))
-                            .unwrap();
-                        break;
-                    }
+                TwitchMessage::Keepalive(_) if welcome_count >= 2 => {
+                    // Verify that the new connection is still healthy
+                    close(&session, "Closing after reconnect test.");
+                    break;
                 }
                 _ => {}
             }
         }
-        handle.kill().unwrap();
+    }
+
+    #[test]
+    fn migration_keeps_order_across_connections() {
+        let (w1, k1, k2, k3) = (
+            mock::welcome("session", 10),
+            mock::keepalive(),
+            mock::keepalive(),
+            mock::keepalive(),
+        );
+        let (w2, k4, k5) = (
+            mock::welcome("session", None),
+            mock::keepalive(),
+            mock::keepalive(),
+        );
+        let server = MockServer::start(vec![
+            vec![
+                Step::Send(w1.clone()),
+                Step::Send(k1.clone()),
+                Step::Reconnect,
+                // Sent before the new connection is welcomed, so it must not be overtaken
+                Step::Send(k2.clone()),
+                Step::WaitFor("welcomed"),
+                Step::Send(k3.clone()),
+                Step::Close(1000),
+            ],
+            vec![
+                Step::Send(w2.clone()),
+                Step::Signal("welcomed"),
+                Step::Send(k4.clone()),
+                // Replayed on both connections, but only to be delivered once
+                Step::Send(k3.clone()),
+                Step::Send(k5.clone()),
+            ],
+        ]);
+
+        let (tx, rx) = mpsc::channel();
+        let session = get_session(server.url()).unwrap();
+        let (event_tx, event_rx) = mpsc::channel();
+        session.lock().unwrap().event_forwarder = Some(event_tx);
+        session.lock().unwrap().reconnect_policy = ReconnectUrlPolicy::with_loopback();
+        let listener_session = Arc::clone(&session);
+        thread::spawn(move || create_message_processor(listener_session, &tx));
+
+        let messages: Vec<TwitchMessage> = rx.iter().take(8).collect();
+        assert!(matches!(messages[2], TwitchMessage::Reconnect(_)));
+        let ids: Vec<String> = messages
+            .iter()
+            .filter(|msg| !matches!(msg, TwitchMessage::Reconnect(_)))
+            .map(|msg| msg.id())
+            .collect();
+        let expected: Vec<String> = [w1, k1, w2, k2, k3, k4, k5]
+            .iter()
+            .map(|frame| mock::message_id(frame))
+            .collect();
+        assert_eq!(ids, expected);
+        let events: Vec<ClientEvent> = event_rx.try_iter().collect();
+        assert_eq!(
+            events,
+            vec![
+                ClientEvent::Connected {
+                    session_id: "session".to_owned()
+                },
+                ClientEvent::Migrated
+            ]
+        );
+        assert!(session.lock().unwrap().migration.is_none());
+    }
+
+    /// A message loop running on a connection to a `MockServer`.
+    struct Running {
+        session: Arc<Mutex<Session>>,
+        messages: Receiver<TwitchMessage>,
+        events: Receiver<ClientEvent>,
+        listener: thread::JoinHandle<std::result::Result<(), EventSubErr>>,
+    }
+
+    fn run(server: &MockServer, clock: Arc<dyn Clock>) -> Running {
+        let (tx, messages) = mpsc::channel();
+        let session = get_session(server.url()).unwrap();
+        let (event_tx, events) = mpsc::channel();
+        session.lock().unwrap().event_forwarder = Some(event_tx);
+        session.lock().unwrap().set_clock(clock);
+        session.lock().unwrap().reconnect_policy = ReconnectUrlPolicy::with_loopback();
+        let listener_session = Arc::clone(&session);
+        let listener = thread::spawn(move || create_message_processor(listener_session, &tx));
+        Running {
+            session,
+            messages,
+            events,
+            listener,
+        }
+    }
+
+    #[test]
+    fn silent_connection_is_replaced() {
+        let clock = Arc::new(ManualClock::new());
+        let an_hour_later = clock.wall() + chrono::Duration::hours(1);
+        let server = MockServer::start(vec![
+            vec![Step::Send(mock::welcome("session", 600))],
+            vec![Step::Send(mock::sent_at(
+                &mock::welcome("session", 600),
+                an_hour_later,
+            ))],
+        ]);
+        let running = run(&server, clock.clone());
+        assert!(matches!(
+            running.messages.recv().unwrap(),
+            TwitchMessage::Welcome(_)
+        ));
+        clock.advance(Duration::from_secs(3600));
+        assert!(matches!(
+            running.messages.recv().unwrap(),
+            TwitchMessage::Welcome(_)
+        ));
+        assert_eq!(server.connections(), 2);
+        let events: Vec<ClientEvent> = running.events.try_iter().collect();
+        assert!(
+            matches!(events[1], ClientEvent::KeepaliveMissed { silent_for }
+            if silent_for >= Duration::from_secs(3600))
+        );
+        assert!(matches!(
+            events[3],
+            ClientEvent::Reconnected {
+                subscriptions_lost: false,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn reconnecting_backs_off_up_to_a_cap_and_gives_up() {
+        let server = MockServer::start(vec![vec![
+            Step::Send(mock::welcome("session", 10)),
+            Step::DropMidFrame(mock::keepalive()),
+        ]]);
+        let clock = Arc::new(ManualClock::new());
+        let (tx, _messages) = mpsc::channel();
+        let session = get_session(server.url()).unwrap();
+        let (event_tx, events) = mpsc::channel();
+        {
+            let mut session = session.lock().unwrap();
+            session.event_forwarder = Some(event_tx);
+            session.set_clock(clock.clone());
+            session.max_reconnect_attempts = Some(8);
+        }
+        let listener_session = Arc::clone(&session);
+        let listener = thread::spawn(move || create_message_processor(listener_session, &tx));
+        assert!(matches!(
+            listener.join().unwrap(),
+            Err(EventSubErr::ReconnectFailed(8))
+        ));
+
+        let events: Vec<ClientEvent> = events.try_iter().collect();
+        let delays: Vec<Duration> = events
+            .iter()
+            .filter_map(|event| match event {
+                ClientEvent::Reconnecting { delay, .. } => Some(*delay),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(delays.len(), 8);
+        assert!(delays[1] >= Duration::from_secs(1) && delays[1] < Duration::from_secs(2));
+        assert!(delays[7] >= MAX_RECONNECT_DELAY && delays[7] <= MAX_RECONNECT_DELAY * 11 / 10);
+        assert_eq!(
+            events.last(),
+            Some(&ClientEvent::ReconnectFailed { attempts: 8 })
+        );
+    }
+
+    #[test]
+    fn stale_messages_are_dropped_and_ids_expire() {
+        let clock = Arc::new(ManualClock::new());
+        let later = clock.wall() + chrono::Duration::from_std(MESSAGE_TTL).unwrap();
+        let (welcome, first, second) = (
+            mock::welcome("session", 600),
+            mock::notification("channel.follow", serde_json::json!({"user_id": "1"})),
+            mock::notification("channel.follow", serde_json::json!({"user_id": "2"})),
+        );
+        let second = mock::sent_at(&second, later);
+        let server = MockServer::start(vec![vec![
+            Step::Send(welcome.clone()),
+            Step::Send(first.clone()),
+            Step::WaitFor("later"),
+            // Its ID has expired by now, so it's only dropped for being too old
+            Step::Send(first.clone()),
+            Step::Send(second.clone()),
+        ]]);
+        let running = run(&server, clock.clone());
+        running.session.lock().unwrap().watchdog.grace = Duration::from_secs(3600);
+
+        let ids: Vec<String> = running
+            .messages
+            .iter()
+            .take(2)
+            .map(|msg| msg.id())
+            .collect();
+        clock.advance(MESSAGE_TTL + Duration::from_secs(60));
+        server.signal("later");
+        let ids: Vec<String> = ids
+            .into_iter()
+            .chain(running.messages.recv().map(|msg| msg.id()))
+            .collect();
+        let expected: Vec<String> = [welcome, first.clone(), second]
+            .iter()
+            .map(|frame| mock::message_id(frame))
+            .collect();
+        assert_eq!(ids, expected);
+        assert!(running.events.try_iter().any(|event| event
+            == ClientEvent::StaleMessage {
+                message_id: mock::message_id(&first)
+            }));
+        assert_eq!(running.session.lock().unwrap().status().handled_messages, 1);
+    }
+
+    #[test]
+    fn connection_dropped_mid_frame_is_replaced() {
+        let server = MockServer::start(vec![
+            vec![
+                Step::Send(mock::welcome("session", 10)),
+                Step::DropMidFrame(mock::keepalive()),
+            ],
+            vec![Step::Send(mock::welcome("session", 10))],
+        ]);
+        let running = run(&server, Arc::new(SystemClock));
+        let (rx, events) = (running.messages, running.events);
+
+        let messages: Vec<TwitchMessage> = rx.iter().take(2).collect();
+        assert!(messages
+            .iter()
+            .all(|msg| matches!(msg, TwitchMessage::Welcome(_))));
+        let events: Vec<ClientEvent> = events.try_iter().collect();
+        assert!(matches!(events[1], ClientEvent::Disconnected { .. }));
+        assert!(matches!(
+            events[2],
+            ClientEvent::Reconnecting { attempt: 1, .. }
+        ));
+        assert!(matches!(events[3], ClientEvent::Reconnected { .. }));
+    }
+
+    #[test]
+    fn unreachable_reconnect_url_keeps_old_connection() {
+        // Nothing listens on the port of a listener that was dropped right away
+        let unreachable = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("ws://{}/ws", listener.local_addr().unwrap())
+        };
+        let server = MockServer::start(vec![
+            vec![
+                Step::Send(mock::welcome("session", 10)),
+                Step::ReconnectTo(unreachable),
+                Step::Send(mock::keepalive()),
+                Step::Close(4004),
+            ],
+            vec![Step::Send(mock::welcome("session", 10))],
+        ]);
+        let running = run(&server, Arc::new(SystemClock));
+        let (rx, events) = (running.messages, running.events);
+
+        let messages: Vec<TwitchMessage> = rx.iter().take(4).collect();
+        assert!(matches!(messages[1], TwitchMessage::Reconnect(_)));
+        assert!(matches!(messages[2], TwitchMessage::Keepalive(_)));
+        assert!(matches!(messages[3], TwitchMessage::Welcome(_)));
+        let events: Vec<ClientEvent> = events.try_iter().collect();
+        assert!(matches!(events[1], ClientEvent::Disconnected { .. }));
+        assert_eq!(
+            events[2],
+            ClientEvent::Closed {
+                code: 4004,
+                reason: CloseReason::ReconnectGraceTimeExpired
+            }
+        );
+        assert!(matches!(events[4], ClientEvent::Reconnected { .. }));
+    }
+
+    #[test]
+    fn rejected_reconnect_url_keeps_old_connection() {
+        let server = MockServer::start(vec![vec![
+            Step::Send(mock::welcome("session", 10)),
+            Step::Reconnect,
+            Step::Send(mock::keepalive()),
+            Step::Close(4003),
+        ]]);
+        // Plain `ws` on loopback is only accepted once opted into, which this doesn't
+        let session = get_session(server.url()).unwrap();
+        let (event_tx, events) = mpsc::channel();
+        session.lock().unwrap().event_forwarder = Some(event_tx);
+        let (tx, rx) = mpsc::channel();
+        let listener = thread::spawn(move || create_message_processor(session, &tx));
+
+        let messages: Vec<TwitchMessage> = rx.iter().collect();
+        assert!(matches!(messages[1], TwitchMessage::Reconnect(_)));
+        assert!(matches!(messages[2], TwitchMessage::Keepalive(_)));
+        assert!(matches!(
+            listener.join().unwrap(),
+            Err(EventSubErr::Closed(CloseReason::ConnectionUnused))
+        ));
+        let events: Vec<ClientEvent> = events.try_iter().collect();
+        assert!(matches!(
+            &events[1],
+            ClientEvent::Disconnected { reason } if reason.contains("scheme")
+        ));
+    }
+
+    #[test]
+    fn duplicates_and_malformed_messages_are_skipped() {
+        let (welcome, first, second) = (
+            mock::welcome("session", 10),
+            mock::notification("channel.follow", serde_json::json!({"user_id": "1"})),
+            mock::notification("channel.follow", serde_json::json!({"user_id": "2"})),
+        );
+        let server = MockServer::start(vec![vec![
+            Step::Send(welcome.clone()),
+            Step::Send(first.clone()),
+            Step::Duplicate,
+            Step::Send(mock::malformed()),
+            Step::Send(second.clone()),
+            Step::Send(first.clone()),
+        ]]);
+        let rx = run(&server, Arc::new(SystemClock)).messages;
+
+        let ids: Vec<String> = rx.iter().take(3).map(|msg| msg.id()).collect();
+        let expected: Vec<String> = [welcome, first, second]
+            .iter()
+            .map(|frame| mock::message_id(frame))
+            .collect();
+        assert_eq!(ids, expected);
+        thread::sleep(READ_TICK);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn close_codes_decide_whether_to_reconnect() {
+        for code in 4000..=4007 {
+            let reason = CloseReason::from(code);
+            let server = MockServer::start(vec![
+                vec![Step::Send(mock::welcome("session", 10)), Step::Close(code)],
+                vec![Step::Send(mock::welcome("session", 10))],
+            ]);
+            let clock = Arc::new(ManualClock::new());
+            let running = run(&server, clock.clone());
+            let (rx, events) = (running.messages, running.events);
+
+            if reason.retry() == Retry::Never {
+                assert!(matches!(
+                    running.listener.join().unwrap(),
+                    Err(EventSubErr::Closed(closed)) if closed == reason
+                ));
+                assert_eq!(server.connections(), 1);
+            } else {
+                assert_eq!(rx.iter().take(2).count(), 2, "close code {}", code);
+                assert_eq!(server.connections(), 2);
+            }
+            if reason.retry() == Retry::WithBackoff {
+                assert!(clock.elapsed() >= Duration::from_secs(1));
+            }
+            assert!(events
+                .try_iter()
+                .any(|event| event == ClientEvent::Closed { code, reason }));
+        }
+    }
+
+    #[test]
+    fn delayed_welcome_during_migration_keeps_order() {
+        let (w1, k1, k2, w2, k3) = (
+            mock::welcome("session", 10),
+            mock::keepalive(),
+            mock::keepalive(),
+            mock::welcome("session", None),
+            mock::keepalive(),
+        );
+        let server = MockServer::start(vec![
+            vec![
+                Step::Send(w1.clone()),
+                Step::Reconnect,
+                Step::Send(k1.clone()),
+                Step::Wait(READ_TICK),
+                Step::Send(k2.clone()),
+                Step::WaitFor("welcomed"),
+                Step::Close(1000),
+            ],
+            vec![
+                // Late, but well within the `MIGRATION_WELCOME_TIMEOUT`
+                Step::Wait(READ_TICK * 3),
+                Step::Send(w2.clone()),
+                Step::Signal("welcomed"),
+                Step::Send(k3.clone()),
+            ],
+        ]);
+        let running = run(&server, Arc::new(SystemClock));
+        let (rx, events) = (running.messages, running.events);
+
+        let ids: Vec<String> = rx
+            .iter()
+            .take(6)
+            .filter(|msg| !matches!(msg, TwitchMessage::Reconnect(_)))
+            .map(|msg| msg.id())
+            .collect();
+        // The old connection is only read again once the new one has been welcomed
+        let expected: Vec<String> = [w1, w2, k1, k2, k3]
+            .iter()
+            .map(|frame| mock::message_id(frame))
+            .collect();
+        assert_eq!(ids, expected);
+        assert!(events
+            .try_iter()
+            .any(|event| event == ClientEvent::Migrated));
+    }
+
+    struct Accepting;
+
+    impl crate::subscriptions::SubscriptionCreator for Accepting {
+        fn create_subscription(
+            &self,
+            _subscription: &crate::subscriptions::DesiredSubscription,
+            _session_id: &str,
+        ) -> std::result::Result<(), SubscriptionErr> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn revoked_subscriptions_are_forgotten_unless_recoverable() {
+        use crate::helix::SubscriptionStatus;
+        use crate::subscriptions::{DesiredSubscription, SubscriptionManager};
+
+        let server = MockServer::start(vec![vec![
+            Step::Send(mock::welcome("session", 10)),
+            Step::Send(mock::revocation("stream.online", "authorization_revoked")),
+            Step::Send(mock::revocation("stream.offline", "beta_maintenance")),
+            Step::Close(4003),
+        ]]);
+        let condition = serde_json::json!({"broadcaster_user_id": "12826"});
+        let online = DesiredSubscription::new("stream.online", "1", condition.clone());
+        let offline = DesiredSubscription::new("stream.offline", "1", condition);
+        let mut manager = SubscriptionManager::new(Accepting);
+        manager.remember(online.clone());
+        manager.remember(offline.clone());
+
+        let session = get_session(server.url()).unwrap();
+        let (event_tx, events) = mpsc::channel();
+        let revoked = Arc::new(Mutex::new(vec![]));
+        {
+            let mut session = session.lock().unwrap();
+            session.subscriptions = Some(manager);
+            session.resubscribe_revoked = true;
+            session.event_forwarder = Some(event_tx);
+            let revoked = Arc::clone(&revoked);
+            session.on_revoked = Some(Box::new(move |msg| {
+                revoked.lock().unwrap().push(msg.reason);
+            }));
+        }
+        let (tx, rx) = mpsc::channel();
+        let listener_session = Arc::clone(&session);
+        let listener = thread::spawn(move || create_message_processor(listener_session, &tx));
+        assert_eq!(rx.iter().count(), 3);
+        assert!(listener.join().unwrap().is_err());
+
+        assert_eq!(
+            *revoked.lock().unwrap(),
+            [
+                SubscriptionStatus::AuthorizationRevoked,
+                SubscriptionStatus::BetaMaintenance
+            ]
+        );
+        let events: Vec<ClientEvent> = events
+            .try_iter()
+            .filter(|event| matches!(event, ClientEvent::Revoked(_)))
+            .collect();
+        assert!(matches!(&events[0], ClientEvent::Revoked(revoked)
+            if revoked.subscription == online && revoked.resubscribed.is_none()));
+        assert!(matches!(&events[1], ClientEvent::Revoked(revoked)
+            if revoked.subscription == offline && revoked.resubscribed == Some(Ok(()))));
+        let session = session.lock().unwrap();
+        assert_eq!(session.subscriptions.as_ref().unwrap().desired(), [offline]);
+    }
+
+    #[test]
+    fn messages_keep_the_budget_up_to_date() {
+        use crate::helix::HelixClient;
+
+        let server = MockServer::start(vec![vec![
+            Step::Send(mock::welcome("session", 10)),
+            Step::Send(mock::notification("stream.online", serde_json::json!({}))),
+            Step::Close(4003),
+        ]]);
+        let url = Url::parse("http://127.0.0.1:1").unwrap();
+        let helix = Arc::new(HelixClient::with_base_url("id", "token", url).unwrap());
+        let session = get_session(server.url()).unwrap();
+        {
+            let helix = Arc::clone(&helix);
+            session.lock().unwrap().on_message = Some(Box::new(move |session_id, message| {
+                helix.observe(session_id, message)
+            }));
+        }
+        let (tx, rx) = mpsc::channel();
+        let listener = thread::spawn(move || create_message_processor(session, &tx));
+        assert_eq!(rx.iter().count(), 2);
+        assert!(listener.join().unwrap().is_err());
+        assert_eq!(helix.budget_view().sessions["session"], 1);
     }
 }
@@ -1,15 +1,125 @@
 #![allow(clippy::uninlined_format_args)]
 
+use eventsub_websocket::auth::flows::{random_state, AuthorizationCodeFlow, DeviceFlow};
+use eventsub_websocket::auth::store::TokenStore;
+use eventsub_websocket::auth::{
+    OAuthEndpoints, RefreshingToken, TokenProvider, UserToken, OAUTH_URL,
+};
+use eventsub_websocket::helix::{HelixClient, HELIX_URL};
+use eventsub_websocket::subscriptions::{DesiredSubscription, SubscriptionManager};
 use eventsub_websocket::types::TwitchMessage;
-use eventsub_websocket::{event_handler, get_default_url};
-use std::sync::mpsc;
+use eventsub_websocket::{create_message_processor, get_default_url, get_session};
+use serde_json::json;
+use std::env;
+use std::error::Error;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use url::Url;
+
+/// Prints the messages of an EventSub session. When `TWITCH_CLIENT_ID` is set, this logs in
+/// through the device code flow, or the authorization code flow if `TWITCH_CLIENT_SECRET` is set
+/// as well, and subscribes to the user's channel going online and offline. The token is stored,
+/// and reused until `--login` is passed, or it can't be refreshed anymore. `TWITCH_SCOPES`,
+/// `TWITCH_REDIRECT_URI`, `TWITCH_OAUTH_URL` and `TWITCH_HELIX_URL` configure the login and the
+/// subscriptions further.
+fn main() -> Result<(), Box<dyn Error>> {
+    // Twitch closes sessions that don't subscribe in time, so this logs in before connecting
+    let subscriptions = match env::var("TWITCH_CLIENT_ID") {
+        Ok(client_id) => Some(subscriptions(&client_id)?),
+        Err(_) => None,
+    };
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
     let (tx, rx) = mpsc::channel();
-    let default_url = get_default_url()?;
-    event_handler(default_url, tx)?;
+    let (event_forwarder, events) = mpsc::channel();
+    let session = get_session(get_default_url()?)?;
+    {
+        let mut session = session.lock().map_err(|err| err.to_string())?;
+        session.subscriptions = subscriptions;
+        session.event_forwarder = Some(event_forwarder);
+    }
+    thread::spawn(move || create_message_processor(session, &tx).map_err(String::from));
+    thread::spawn(move || {
+        for event in events {
+            println!("Connection event: {:?}", event);
+        }
+    });
     loop {
         let msg: TwitchMessage = rx.recv().map_err(|err| format!("{}", err))?;
         println!("Handling message locally: {:#?}", msg);
     }
 }
+
+/// Logs in, and subscribes to the user's channel going online and offline through Helix.
+fn subscriptions(client_id: &str) -> Result<SubscriptionManager, Box<dyn Error>> {
+    let tokens = login(client_id)?;
+    let info = tokens.validate()?;
+    let user_id = info.user_id.ok_or("the token doesn't belong to a user")?;
+    println!(
+        "Logged in as {} with scopes {:?}",
+        info.login.unwrap_or_default(),
+        info.scopes
+    );
+    let helix_url = Url::parse(&env::var("TWITCH_HELIX_URL").unwrap_or(HELIX_URL.into()))?;
+    let helix = HelixClient::with_token_provider(client_id, Arc::new(tokens), helix_url)?;
+    let mut manager = SubscriptionManager::new(helix);
+    for r#type in ["stream.online", "stream.offline"] {
+        let condition = json!({ "broadcaster_user_id": user_id });
+        manager.remember(DesiredSubscription::new(r#type, "1", condition));
+    }
+    Ok(manager)
+}
+
+fn login(client_id: &str) -> Result<RefreshingToken, Box<dyn Error>> {
+    let store = TokenStore::default_location().ok_or("no directory to store the token in")?;
+    let endpoints = OAuthEndpoints::at(&env::var("TWITCH_OAUTH_URL").unwrap_or(OAUTH_URL.into()))?;
+    let client_secret = env::var("TWITCH_CLIENT_SECRET");
+    // Refreshing the token saves the new one
+    let refreshing = |token: &UserToken| {
+        RefreshingToken::from_user_token(
+            client_id,
+            client_secret.as_deref().unwrap_or_default(),
+            token,
+        )
+        .map(|tokens| tokens.endpoints(endpoints.clone()).store(store.clone()))
+    };
+    if !env::args().any(|arg| arg == "--login") {
+        if let Some(token) = store.load()? {
+            // Validates the stored token, and refreshes it if it expired
+            let tokens = refreshing(&token)?;
+            match tokens.access_token() {
+                Ok(_) => return Ok(tokens),
+                Err(err) => println!("Logging in again, the stored token can't be used: {}", err),
+            }
+        }
+    }
+    let scopes = env::var("TWITCH_SCOPES").unwrap_or_default();
+    let scopes: Vec<&str> = scopes.split_whitespace().collect();
+
+    let token = match &client_secret {
+        Ok(client_secret) => {
+            let redirect_uri = env::var("TWITCH_REDIRECT_URI")
+                .unwrap_or_else(|_| "http://localhost:3000".to_owned());
+            let flow = AuthorizationCodeFlow::new(
+                client_id,
+                client_secret,
+                Url::parse(&redirect_uri)?,
+                &scopes,
+            )?
+            .endpoints(endpoints.clone());
+            let state = random_state()?;
+            println!("Open {} to log in", flow.authorize_url(&state));
+            flow.listen(&state)?
+        }
+        Err(_) => {
+            let flow = DeviceFlow::new(client_id, &scopes)?.endpoints(endpoints.clone());
+            let code = flow.start()?;
+            println!(
+                "Open {} and enter the code {} to log in",
+                code.verification_uri, code.user_code
+            );
+            flow.poll(&code)?
+        }
+    };
+    store.save(&token)?;
+    Ok(refreshing(&token)?)
+}
@@ -0,0 +1,243 @@
+use crate::error::ReconnectHandlerErr;
+use crate::events::ClientEvent;
+use crate::source::MessageSource;
+use crate::types::Session;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tungstenite::Message;
+use url::{Host, Url};
+
+/// How long to wait for the `Welcome` message on the connection opened after a `Reconnect`.
+/// Twitch closes the old connection if the new one isn't used within 30 seconds.
+pub const MIGRATION_WELCOME_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The state of moving a session to the url Twitch sent in a `Reconnect` message. The migration
+/// runs inside the regular message loop, in these steps:
+///
+/// 1. The `Reconnect` handler opens a connection to the new url.
+/// 2. The loop reads from the new connection until its `Welcome` message arrives. Messages which
+///    keep arriving on the old connection meanwhile stay queued on its socket.
+/// 3. The loop drains the old connection until Twitch closes it, so everything sent on it is
+///    delivered before anything sent on the new connection.
+/// 4. The new connection replaces the old one, and the messages it buffered are processed first.
+///
+/// Both connections share the `Session`, so duplicates sent on both are only forwarded once.
+#[derive(Debug)]
+pub enum Migration {
+    /// The new connection is open, and its `Welcome` message has not arrived yet.
+    AwaitingWelcome {
+        socket: Box<dyn MessageSource>,
+        connection: u64,
+        buffered: VecDeque<Message>,
+        since: Instant,
+    },
+    /// The new connection was welcomed, and the old one is read until it is closed.
+    DrainingOld {
+        socket: Box<dyn MessageSource>,
+        connection: u64,
+        buffered: VecDeque<Message>,
+    },
+}
+
+impl Migration {
+    /// Starts waiting for the `Welcome` message of the new connection, opened at `now`. The
+    /// `connection` ID identifies it in recordings.
+    pub fn begin(socket: Box<dyn MessageSource>, connection: u64, now: Instant) -> Migration {
+        Migration::AwaitingWelcome {
+            socket,
+            connection,
+            buffered: VecDeque::new(),
+            since: now,
+        }
+    }
+
+    /// Moves on to draining the old connection, once the new one has been welcomed. Does nothing
+    /// if this already happened.
+    pub fn welcomed(self) -> Migration {
+        match self {
+            Migration::AwaitingWelcome {
+                socket,
+                connection,
+                buffered,
+                ..
+            } => Migration::DrainingOld {
+                socket,
+                connection,
+                buffered,
+            },
+            draining => draining,
+        }
+    }
+
+    /// Holds back a message received on the new connection until the old one is drained.
+    pub fn buffer(&mut self, msg: Message) {
+        match self {
+            Migration::AwaitingWelcome { buffered, .. }
+            | Migration::DrainingOld { buffered, .. } => buffered.push_back(msg),
+        }
+    }
+
+    /// The ID of the new connection.
+    pub fn connection(&self) -> u64 {
+        match self {
+            Migration::AwaitingWelcome { connection, .. }
+            | Migration::DrainingOld { connection, .. } => *connection,
+        }
+    }
+
+    pub fn is_awaiting_welcome(&self) -> bool {
+        matches!(self, Migration::AwaitingWelcome { .. })
+    }
+
+    /// Reads from the new connection, while its `Welcome` message is awaited.
+    pub(crate) fn read_welcome(&mut self) -> Option<tungstenite::Result<Message>> {
+        match self {
+            Migration::AwaitingWelcome { socket, .. } => Some(socket.read_message()),
+            Migration::DrainingOld { .. } => None,
+        }
+    }
+
+    /// Whether the new connection failed to send its `Welcome` message in time by `now`.
+    pub fn welcome_overdue(&self, now: Instant) -> bool {
+        match self {
+            Migration::AwaitingWelcome { since, .. } => {
+                now.saturating_duration_since(*since) > MIGRATION_WELCOME_TIMEOUT
+            }
+            Migration::DrainingOld { .. } => false,
+        }
+    }
+}
+
+impl Session {
+    /// Replaces the drained old connection with the new one, and queues up the messages the new
+    /// connection buffered in the meantime.
+    pub(crate) fn complete_migration(&mut self) {
+        let (socket, connection, buffered) = match self.migration.take() {
+            Some(Migration::DrainingOld {
+                socket,
+                connection,
+                buffered,
+            }) => (socket, connection, buffered),
+            other => {
+                self.migration = other;
+                return;
+            }
+        };
+        // Flush the reply to Twitch's close frame, if there is one. The old connection is
+        // dropped either way.
+        let _ = self.socket.write_pending();
+        self.socket = socket;
+        self.connection_id = connection;
+        self.backlog.extend(buffered);
+        self.emit(ClientEvent::Migrated);
+    }
+}
+
+/// Decides which urls a `Reconnect` message is allowed to send the client to. Twitch only ever
+/// sends its own EventSub hosts, so anything else is rejected as a defense in depth.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReconnectUrlPolicy {
+    /// The hosts a reconnect url may point to. Subdomains are not included implicitly.
+    pub allowed_hosts: Vec<String>,
+    /// Whether unencrypted `ws` urls are accepted for loopback hosts, as used by test servers.
+    /// All other hosts always require `wss`.
+    pub allow_plain_loopback: bool,
+}
+
+impl ReconnectUrlPolicy {
+    /// Only accepts Twitch's EventSub hosts over `wss`.
+    pub fn twitch_only() -> ReconnectUrlPolicy {
+        ReconnectUrlPolicy {
+            allowed_hosts: vec![
+                "eventsub.wss.twitch.tv".to_owned(),
+                "eventsub-beta.wss.twitch.tv".to_owned(),
+            ],
+            allow_plain_loopback: false,
+        }
+    }
+
+    /// Accepts Twitch's EventSub hosts over `wss`, and local test servers, such as the `mock`
+    /// server, over `ws` as well.
+    pub fn with_loopback() -> ReconnectUrlPolicy {
+        let mut policy = ReconnectUrlPolicy::twitch_only();
+        policy.allowed_hosts.extend(
+            ["localhost", "127.0.0.1", "::1"]
+                .iter()
+                .map(|host| host.to_string()),
+        );
+        policy.allow_plain_loopback = true;
+        policy
+    }
+
+    /// Parses the `url`, and checks it against the allowed hosts and schemes.
+    pub fn validate(&self, url: &str) -> Result<Url, ReconnectHandlerErr> {
+        let parsed = Url::parse(url)?;
+        let loopback = match parsed.host() {
+            Some(Host::Domain(domain)) => domain == "localhost",
+            Some(Host::Ipv4(ip)) => ip.is_loopback(),
+            Some(Host::Ipv6(ip)) => ip.is_loopback(),
+            None => false,
+        };
+        let scheme_allowed = match parsed.scheme() {
+            "wss" => true,
+            "ws" => loopback && self.allow_plain_loopback,
+            _ => false,
+        };
+        if !scheme_allowed {
+            return Err(ReconnectHandlerErr::RejectedUrl(format!(
+                "scheme of {} is not allowed",
+                url
+            )));
+        }
+        // The host string of IPv6 addresses is wrapped in brackets, which the allow-list omits
+        let host = parsed.host_str().unwrap_or_default();
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if !self.allowed_hosts.iter().any(|allowed| allowed == host) {
+            return Err(ReconnectHandlerErr::RejectedUrl(format!(
+                "host of {} is not allowed",
+                url
+            )));
+        }
+        Ok(parsed)
+    }
+}
+
+impl Default for ReconnectUrlPolicy {
+    /// The same as `twitch_only`.
+    fn default() -> Self {
+        ReconnectUrlPolicy::twitch_only()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loopback_policy_accepts_twitch_and_loopback() {
+        let policy = ReconnectUrlPolicy::with_loopback();
+        assert!(policy
+            .validate("wss://eventsub.wss.twitch.tv/ws?challenge=abc")
+            .is_ok());
+        assert!(policy.validate("ws://127.0.0.1:8080/ws").is_ok());
+        assert!(policy.validate("ws://[::1]:8080/ws").is_ok());
+        assert!(policy.validate("ws://eventsub.wss.twitch.tv/ws").is_err());
+        assert!(policy
+            .validate("wss://eventsub.wss.twitch.tv.evil.com/ws")
+            .is_err());
+        assert!(policy
+            .validate("https://eventsub.wss.twitch.tv/ws")
+            .is_err());
+    }
+
+    #[test]
+    fn default_policy_rejects_loopback() {
+        let policy = ReconnectUrlPolicy::default();
+        assert_eq!(policy, ReconnectUrlPolicy::twitch_only());
+        assert!(matches!(
+            policy.validate("ws://localhost:8080/ws"),
+            Err(ReconnectHandlerErr::RejectedUrl(_))
+        ));
+        assert!(policy.validate("wss://localhost:8080/ws").is_err());
+    }
+}
@@ -0,0 +1,326 @@
+//! An in-process stand-in for Twitch's EventSub WebSocket server, to test clients without the
+//! Twitch CLI or a network connection. Every connection the server accepts plays the next script
+//! of `Step`s, and records the frames the client sent.
+//!
+//! Besides regular traffic, the steps can inject faults: connections dropped in the middle of a
+//! frame, stalls past the keepalive time, reconnects to unreachable urls, duplicated and malformed
+//! messages, close codes and late `Welcome` messages. Scripts only advance on their own steps and
+//! on `Signal`s from other connections, so every scenario plays out the same way each time.
+//!
+//! ```
+//! use eventsub_websocket::mock::{self, MockServer, Step};
+//! use eventsub_websocket::{get_session, types::TwitchMessage};
+//!
+//! let server = MockServer::start(vec![vec![
+//!     Step::Send(mock::welcome("session", 10)),
+//!     Step::Close(4000),
+//! ]]);
+//! let session = get_session(server.url()).unwrap();
+//! ```
+
+use crate::parse_message;
+use crate::testing::fixtures::{Fixtures, User};
+use crate::types::TwitchMessage;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::io::Write;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::{Message, WebSocket};
+use url::Url;
+
+/// How often the server checks for frames from the client while it isn't sending anything.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A single action of a connection's script.
+#[derive(Debug, Clone)]
+pub enum Step {
+    /// Sends a text frame, such as one built by the functions of this module.
+    Send(String),
+    /// Sends the last frame sent by `Send` again, as Twitch may do around reconnects.
+    Duplicate,
+    /// Sends the first half of a text frame, and then drops the TCP connection.
+    DropMidFrame(String),
+    /// Sends a `Reconnect` message pointing back to this server, whose next connection then plays
+    /// the next script.
+    Reconnect,
+    /// Sends a `Reconnect` message pointing to any url, such as one nothing listens on.
+    ReconnectTo(String),
+    /// Closes the connection with the given close code, and waits for the client's reply.
+    Close(u16),
+    /// Waits, while still recording what the client sends. Stalls the connection past the
+    /// keepalive time, or delays the next `Welcome` message.
+    Wait(Duration),
+    /// Marks a point in the script other connections can wait for.
+    Signal(&'static str),
+    /// Waits until another connection, or the test through `MockServer::signal`, has reached
+    /// the given `Signal`.
+    WaitFor(&'static str),
+}
+
+/// The server, which runs until every script has been played and its client disconnected.
+#[derive(Debug)]
+pub struct MockServer {
+    url: Url,
+    received: Arc<Mutex<Vec<Vec<Message>>>>,
+    signals: Arc<Signals>,
+}
+
+#[derive(Debug, Default)]
+struct Signals {
+    reached: Mutex<HashSet<&'static str>>,
+    changed: Condvar,
+}
+
+impl Signals {
+    fn reach(&self, name: &'static str) {
+        self.reached.lock().unwrap().insert(name);
+        self.changed.notify_all();
+    }
+}
+
+impl MockServer {
+    /// Binds to an ephemeral port on the loopback interface, and plays the `scripts` to the
+    /// connections it accepts, in order.
+    pub fn start(scripts: Vec<Vec<Step>>) -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("couldn't bind mock server");
+        let url = Url::parse(&format!("ws://{}/ws", listener.local_addr().unwrap())).unwrap();
+        let received = Arc::new(Mutex::new(vec![]));
+        let signals = Arc::new(Signals::default());
+
+        let server_url = url.clone();
+        let server_received = Arc::clone(&received);
+        let server_signals = Arc::clone(&signals);
+        thread::spawn(move || {
+            for (connection, script) in scripts.into_iter().enumerate() {
+                let stream = match listener.accept() {
+                    Ok((stream, _)) => stream,
+                    Err(_) => return,
+                };
+                server_received.lock().unwrap().push(vec![]);
+                let mut player = Player {
+                    url: server_url.clone(),
+                    connection,
+                    received: Arc::clone(&server_received),
+                    signals: Arc::clone(&server_signals),
+                };
+                thread::spawn(move || player.play(stream, script));
+            }
+        });
+
+        MockServer {
+            url,
+            received,
+            signals,
+        }
+    }
+
+    pub fn url(&self) -> Url {
+        self.url.clone()
+    }
+
+    /// The frames the client sent on the given connection so far, counted from 0.
+    pub fn received(&self, connection: usize) -> Vec<Message> {
+        self.received
+            .lock()
+            .unwrap()
+            .get(connection)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Reaches a `Signal` from outside the scripts, e.g. once a test has advanced its clock.
+    pub fn signal(&self, name: &'static str) {
+        self.signals.reach(name);
+    }
+
+    /// The number of connections accepted so far.
+    pub fn connections(&self) -> usize {
+        self.received.lock().unwrap().len()
+    }
+}
+
+struct Player {
+    url: Url,
+    connection: usize,
+    received: Arc<Mutex<Vec<Vec<Message>>>>,
+    signals: Arc<Signals>,
+}
+
+impl Player {
+    fn play(&mut self, stream: TcpStream, script: Vec<Step>) {
+        let mut socket = match tungstenite::accept(stream) {
+            Ok(socket) => socket,
+            Err(_) => return,
+        };
+        let _ = socket.get_ref().set_read_timeout(Some(POLL_INTERVAL));
+        let mut last_sent = None;
+        for step in script {
+            let result = match step {
+                Step::Send(text) => {
+                    last_sent = Some(text.clone());
+                    socket.write_message(Message::Text(text))
+                }
+                Step::Duplicate => match &last_sent {
+                    Some(text) => socket.write_message(Message::Text(text.clone())),
+                    None => Ok(()),
+                },
+                Step::DropMidFrame(text) => {
+                    let frame = text_frame(&text);
+                    let stream = socket.get_mut();
+                    let _ = stream.write_all(&frame[..frame.len() - text.len() / 2]);
+                    let _ = stream.shutdown(Shutdown::Both);
+                    return;
+                }
+                Step::Reconnect => {
+                    socket.write_message(Message::Text(reconnect(self.url.as_str())))
+                }
+                Step::ReconnectTo(url) => socket.write_message(Message::Text(reconnect(&url))),
+                Step::Close(code) => socket.close(Some(CloseFrame {
+                    code: CloseCode::from(code),
+                    reason: "Closed by mock server".into(),
+                })),
+                Step::Wait(duration) => {
+                    self.record_until(&mut socket, Some(Instant::now() + duration));
+                    Ok(())
+                }
+                Step::Signal(name) => {
+                    self.signals.reach(name);
+                    Ok(())
+                }
+                Step::WaitFor(name) => {
+                    let mut reached = self.signals.reached.lock().unwrap();
+                    while !reached.contains(name) {
+                        reached = self.signals.changed.wait(reached).unwrap();
+                    }
+                    Ok(())
+                }
+            };
+            if result.is_err() {
+                return;
+            }
+        }
+        self.record_until(&mut socket, None);
+    }
+
+    /// Records the client's frames until the `deadline`, or until the connection is closed.
+    fn record_until(&mut self, socket: &mut WebSocket<TcpStream>, deadline: Option<Instant>) {
+        while deadline.map_or(true, |deadline| Instant::now() < deadline) {
+            match socket.read_message() {
+                Ok(msg) => self.received.lock().unwrap()[self.connection].push(msg),
+                Err(tungstenite::Error::Io(err))
+                    if matches!(
+                        err.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) => {}
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+/// Encodes an unmasked text frame, as a server sends it.
+fn text_frame(text: &str) -> Vec<u8> {
+    let mut frame = vec![0x81];
+    match text.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend((len as u64).to_be_bytes());
+        }
+    }
+    frame.extend(text.as_bytes());
+    frame
+}
+
+static MESSAGE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// The ID of the one subscription all notifications and revocations belong to.
+const SUBSCRIPTION_ID: &str = "f1c2a387-161a-49f9-a165-0f21d7a4e1c4";
+
+/// Builds messages for the session, sent at the current time, in a channel that stays the same
+/// across calls. Every call starts from another seed, so each message gets a new ID.
+fn fixtures(session_id: &str) -> Fixtures {
+    let mut fixtures = Fixtures::seeded(MESSAGE_COUNTER.fetch_add(1, Ordering::Relaxed));
+    fixtures.broadcaster = User {
+        id: "12826".to_owned(),
+        login: "twitchdev".to_owned(),
+        name: "TwitchDev".to_owned(),
+    };
+    fixtures.session_id = session_id.to_owned();
+    fixtures
+}
+
+fn frame(message: &TwitchMessage) -> String {
+    serde_json::to_string(message).expect("messages always serialize")
+}
+
+/// A `Welcome` message. The `keepalive` is only left out after a `Reconnect`.
+pub fn welcome(session_id: &str, keepalive: impl Into<Option<u64>>) -> String {
+    frame(&fixtures(session_id).welcome(keepalive))
+}
+
+pub fn keepalive() -> String {
+    frame(&fixtures("session").keepalive())
+}
+
+pub fn reconnect(url: &str) -> String {
+    frame(&fixtures("session").reconnect(url))
+}
+
+/// A `Notification` of the given subscription type, carrying the `event`.
+pub fn notification(r#type: &str, event: Value) -> String {
+    let mut fixtures = fixtures("session");
+    let condition = json!({"broadcaster_user_id": fixtures.broadcaster.id});
+    let mut message = fixtures.notification_with(r#type, condition, event);
+    if let TwitchMessage::Notification(notification) = &mut message {
+        notification.payload.subscription.id = SUBSCRIPTION_ID.to_owned();
+    }
+    frame(&message)
+}
+
+/// A `Revocation` of a subscription of the given type, with the reason as its `status`. Only
+/// types `Fixtures` supports can be revoked.
+pub fn revocation(r#type: &str, status: &str) -> String {
+    let mut message = fixtures("session")
+        .revocation(r#type, status)
+        .expect("not a subscription type the fixtures support");
+    if let TwitchMessage::Revocation(revocation) = &mut message {
+        revocation.payload.subscription.id = SUBSCRIPTION_ID.to_owned();
+    }
+    frame(&message)
+}
+
+/// A frame that is cut off in the middle of its JSON, so it can't be parsed.
+pub fn malformed() -> String {
+    let frame = keepalive();
+    frame[..frame.len() / 2].to_owned()
+}
+
+/// Changes the `message_timestamp` of a frame built by this module, e.g. to match a
+/// `ManualClock`.
+pub fn sent_at(frame: &str, time: DateTime<Utc>) -> String {
+    let mut frame: Value =
+        serde_json::from_str(frame).expect("not a message built by the mock module");
+    frame["metadata"]["message_timestamp"] =
+        time.to_rfc3339_opts(SecondsFormat::Nanos, true).into();
+    frame.to_string()
+}
+
+/// The message ID of a frame built by this module, to compare with the messages a client
+/// forwarded.
+pub fn message_id(frame: &str) -> String {
+    parse_message::<TwitchMessage>(frame)
+        .expect("not a message built by the mock module")
+        .id()
+}
@@ -0,0 +1,678 @@
+//! Spreads subscriptions across several EventSub sessions. Twitch allows each WebSocket
+//! connection only `SUBSCRIPTIONS_PER_CONNECTION` enabled subscriptions, and each client ID and
+//! user only `MAX_CONNECTIONS` connections, so the `SessionPool` opens connections as they are
+//! needed and places every subscription on one with room.
+//!
+//! The subscriptions placed on a connection are recreated whenever it reconnects on its own. Only
+//! once a connection is lost for good are its subscriptions moved to the other connections.
+
+use crate::clock::{Clock, SystemClock};
+use crate::dedup::HandledMessages;
+use crate::error::{EventSubErr, PoolErr};
+use crate::events::ClientEvent;
+use crate::source::{Connector, WebSocketConnector};
+use crate::subscriptions::{DesiredSubscription, SubscribeReport, SubscriptionCreator};
+use crate::types::{StopHandle, TwitchMessage};
+use std::fmt;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use url::Url;
+
+/// The number of enabled subscriptions Twitch allows on a single WebSocket connection.
+pub const SUBSCRIPTIONS_PER_CONNECTION: usize = 300;
+/// The number of WebSocket connections Twitch allows per client ID and user.
+pub const MAX_CONNECTIONS: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolLimits {
+    pub subscriptions_per_connection: usize,
+    pub max_connections: usize,
+}
+
+impl Default for PoolLimits {
+    fn default() -> Self {
+        PoolLimits {
+            subscriptions_per_connection: SUBSCRIPTIONS_PER_CONNECTION,
+            max_connections: MAX_CONNECTIONS,
+        }
+    }
+}
+
+/// A set of EventSub sessions, whose messages are merged into one stream, without duplicates.
+#[derive(Debug)]
+pub struct SessionPool {
+    inner: Arc<Mutex<PoolInner>>,
+}
+
+struct PoolInner {
+    url: Url,
+    connector: Arc<dyn Connector>,
+    creator: Arc<dyn SubscriptionCreator + Sync>,
+    limits: PoolLimits,
+    connections: Vec<PooledConnection>,
+    /// Receives the messages of every connection, to be deduplicated and merged.
+    forwarder: Sender<TwitchMessage>,
+    /// Times the deduplication of messages, and is given to every connection opened.
+    clock: Arc<dyn Clock>,
+    /// Receives the `ClientEvent`s of every connection opened, and those of the pool, if set.
+    events: Option<Sender<ClientEvent>>,
+    closing: bool,
+}
+
+/// A connection of the pool, along with the subscriptions placed on it. These are kept apart
+/// from the `Session`, which stays locked by its message loop most of the time.
+struct PooledConnection {
+    /// Set once the connection is open. Until then, the connection already counts towards the
+    /// limit, and subscriptions placed on it wait for its `Welcome`.
+    stop: Option<StopHandle>,
+    placed: Arc<Mutex<Placed>>,
+}
+
+/// Subscriptions are only recorded once they were created, and Helix is called without holding
+/// this lock, so slots for the subscriptions being created are `reserved` in the meantime.
+#[derive(Default)]
+struct Placed {
+    /// Set once the connection was welcomed, and updated whenever it reconnects.
+    session_id: Option<String>,
+    /// The subscriptions created on the current session.
+    subscriptions: Vec<DesiredSubscription>,
+    /// Subscriptions to create once the connection is welcomed.
+    pending: Vec<DesiredSubscription>,
+    reserved: usize,
+}
+
+impl Placed {
+    fn taken(&self) -> usize {
+        self.subscriptions.len() + self.pending.len() + self.reserved
+    }
+}
+
+impl fmt::Debug for PoolInner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoolInner")
+            .field("url", &self.url)
+            .field("limits", &self.limits)
+            .field("connections", &self.connections.len())
+            .field("closing", &self.closing)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SessionPool {
+    /// Creates an empty pool, which connects to the `url` once the first subscription is placed.
+    /// The messages of all its connections are sent through the `message_forwarder`.
+    pub fn new(
+        url: Url,
+        creator: impl SubscriptionCreator + Sync + 'static,
+        message_forwarder: Sender<TwitchMessage>,
+    ) -> SessionPool {
+        SessionPool::with_connector(
+            url,
+            Arc::new(WebSocketConnector),
+            creator,
+            message_forwarder,
+        )
+    }
+
+    pub fn with_connector(
+        url: Url,
+        connector: Arc<dyn Connector>,
+        creator: impl SubscriptionCreator + Sync + 'static,
+        message_forwarder: Sender<TwitchMessage>,
+    ) -> SessionPool {
+        let (forwarder, merged) = mpsc::channel::<TwitchMessage>();
+        let inner = Arc::new(Mutex::new(PoolInner {
+            url,
+            connector,
+            creator: Arc::new(creator),
+            limits: PoolLimits::default(),
+            connections: vec![],
+            forwarder,
+            clock: Arc::new(SystemClock),
+            events: None,
+            closing: false,
+        }));
+        let pool = Arc::downgrade(&inner);
+        // Connections forward into a single channel, so a message which arrives on two
+        // connections, e.g. while a subscription was moved, is only passed on once
+        thread::spawn(move || {
+            let mut handled = HandledMessages::default();
+            for msg in merged {
+                let Some(pool) = pool.upgrade() else {
+                    break;
+                };
+                let now = lock(&pool).clock.now();
+                handled.expire(now);
+                if handled.contains(&msg.id()) {
+                    continue;
+                }
+                handled.insert(msg.id(), now);
+                if message_forwarder.send(msg).is_err() {
+                    break;
+                }
+            }
+        });
+        SessionPool { inner }
+    }
+
+    /// Replaces the `SystemClock`, e.g. with a `ManualClock` in tests. Connections which are
+    /// already open keep their clock.
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        lock(&self.inner).clock = clock;
+    }
+
+    pub fn set_limits(&self, limits: PoolLimits) {
+        lock(&self.inner).limits = limits;
+    }
+
+    /// Passes the `ClientEvent`s of every connection opened from now on to the `forwarder`, along
+    /// with the pool's own: the outcome of creating a connection's subscriptions once it is
+    /// welcomed, connections which were lost for good, and subscriptions which couldn't be moved
+    /// off them.
+    pub fn set_event_forwarder(&self, forwarder: Sender<ClientEvent>) {
+        lock(&self.inner).events = Some(forwarder);
+    }
+
+    /// Places the `subscription` on the first connection with room, opening a new connection if
+    /// there is none. On a connection which wasn't welcomed yet, the subscription is created as
+    /// soon as it is, and reported in a `ClientEvent::Subscribed`.
+    pub fn subscribe(&self, subscription: DesiredSubscription) -> Result<(), PoolErr> {
+        let creator = Arc::clone(&lock(&self.inner).creator);
+        if creator.costs().is_some_and(|costs| costs.remaining() == 0) {
+            return Err(PoolErr::BudgetExhausted);
+        }
+        place(&self.inner, subscription)
+    }
+
+    pub fn connections(&self) -> usize {
+        lock(&self.inner).connections.len()
+    }
+
+    /// The subscriptions created on each connection. Those waiting for their connection to be
+    /// welcomed are not included.
+    pub fn placements(&self) -> Vec<Vec<DesiredSubscription>> {
+        lock(&self.inner)
+            .connections
+            .iter()
+            .map(|connection| lock_placed(&connection.placed).subscriptions.clone())
+            .collect()
+    }
+
+    /// Closes every connection, without moving their subscriptions anywhere.
+    pub fn close(&self) -> Result<(), EventSubErr> {
+        let mut inner = lock(&self.inner);
+        inner.closing = true;
+        // Connections which are still being opened are closed once they are
+        for connection in inner.connections.drain(..) {
+            if let Some(stop) = connection.stop {
+                stop.stop();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where a subscription goes, as decided while the pool was locked.
+enum Placement {
+    /// Create it on the welcomed session, in the slot reserved for it.
+    Create {
+        placed: Arc<Mutex<Placed>>,
+        session_id: String,
+    },
+    /// It was left for the connection's `Welcome`.
+    Pending,
+    /// It was left for the `Welcome` of a new connection, which is opened in the reserved slot.
+    Open { placed: Arc<Mutex<Placed>> },
+}
+
+/// Places the `subscription` on a connection with room, or a new one. The subscription is only
+/// recorded once it was created, and new connections are only opened, after the pool is
+/// unlocked.
+fn place(pool: &Arc<Mutex<PoolInner>>, subscription: DesiredSubscription) -> Result<(), PoolErr> {
+    let (placement, creator) = {
+        let mut inner = lock(pool);
+        let mut placement = None;
+        for connection in &inner.connections {
+            let mut placed = lock_placed(&connection.placed);
+            if placed.taken() >= inner.limits.subscriptions_per_connection {
+                continue;
+            }
+            placement = Some(match placed.session_id.clone() {
+                Some(session_id) => {
+                    placed.reserved += 1;
+                    Placement::Create {
+                        placed: Arc::clone(&connection.placed),
+                        session_id,
+                    }
+                }
+                None => {
+                    placed.pending.push(subscription.clone());
+                    Placement::Pending
+                }
+            });
+            break;
+        }
+        let placement = match placement {
+            Some(placement) => placement,
+            None if inner.connections.len() >= inner.limits.max_connections => {
+                return Err(PoolErr::Full)
+            }
+            None => {
+                let placed = Arc::new(Mutex::new(Placed {
+                    pending: vec![subscription.clone()],
+                    ..Default::default()
+                }));
+                inner.connections.push(PooledConnection {
+                    stop: None,
+                    placed: Arc::clone(&placed),
+                });
+                Placement::Open { placed }
+            }
+        };
+        (placement, Arc::clone(&inner.creator))
+    };
+    let (placed, session_id) = match placement {
+        Placement::Create { placed, session_id } => (placed, session_id),
+        Placement::Pending => return Ok(()),
+        Placement::Open { placed } => return open(pool, placed, &subscription),
+    };
+    let created = creator.create_subscription(&subscription, &session_id);
+    let mut placed = lock_placed(&placed);
+    placed.reserved -= 1;
+    created?;
+    placed.subscriptions.push(subscription);
+    Ok(())
+}
+
+/// Opens the connection reserved for the `subscription`, which is created once the connection is
+/// welcomed, along with any placed on it in the meantime. The connection's message loop moves its
+/// subscriptions elsewhere once it ends. If it can't be opened, the others are moved right away.
+fn open(
+    pool: &Arc<Mutex<PoolInner>>,
+    placed: Arc<Mutex<Placed>>,
+    subscription: &DesiredSubscription,
+) -> Result<(), PoolErr> {
+    let (url, connector, creator, events, clock, forwarder) = {
+        let inner = lock(pool);
+        (
+            inner.url.clone(),
+            Arc::clone(&inner.connector),
+            Arc::clone(&inner.creator),
+            inner.events.clone(),
+            Arc::clone(&inner.clock),
+            inner.forwarder.clone(),
+        )
+    };
+    let unreserve = |err: EventSubErr| {
+        lock_placed(&placed)
+            .pending
+            .retain(|pending| pending != subscription);
+        rebalance(&Arc::downgrade(pool), &placed);
+        PoolErr::Connection(err)
+    };
+    let session = match crate::get_session_with(url, connector) {
+        Ok(session) => session,
+        Err(err) => return Err(unreserve(err)),
+    };
+    let welcomed = Arc::clone(&placed);
+    let welcome_events = events.clone();
+    let observer = Arc::clone(&creator);
+    // Twitch drops a session's subscriptions whenever it issues a new one, so they are created
+    // again for every new session ID
+    let welcome = move |session_id: &str| {
+        let (to_create, reconnected) = {
+            let mut placed = lock_placed(&welcomed);
+            let reconnected = placed.session_id.replace(session_id.to_owned()).is_some();
+            let mut to_create = std::mem::take(&mut placed.subscriptions);
+            to_create.append(&mut placed.pending);
+            placed.reserved += to_create.len();
+            (to_create, reconnected)
+        };
+        let mut report = SubscribeReport {
+            session_id: session_id.to_owned(),
+            ..Default::default()
+        };
+        for subscription in &to_create {
+            match creator.create_subscription(subscription, session_id) {
+                Ok(()) => report.created.push(subscription.clone()),
+                Err(err) => report.failed.push((subscription.clone(), err.to_string())),
+            }
+        }
+        {
+            let mut placed = lock_placed(&welcomed);
+            placed.reserved -= to_create.len();
+            placed.subscriptions.extend(report.created.iter().cloned());
+        }
+        emit(
+            &welcome_events,
+            if reconnected {
+                ClientEvent::Resubscribed(report)
+            } else {
+                ClientEvent::Subscribed(report)
+            },
+        );
+        Ok(())
+    };
+    let revoked = Arc::clone(&placed);
+    let stop = {
+        let mut session = session.lock()?;
+        session.set_clock(clock);
+        session.event_forwarder = events.clone();
+        session.on_welcome = Some(Box::new(welcome));
+        session.on_message = Some(Box::new(move |session_id, message| {
+            observer.observe(session_id, message)
+        }));
+        session.on_revoked = Some(Box::new(move |revoked_subscription| {
+            let mut placed = lock_placed(&revoked);
+            let is_revoked = |subscription: &DesiredSubscription| {
+                *subscription == revoked_subscription.subscription
+            };
+            placed
+                .subscriptions
+                .retain(|subscription| !is_revoked(subscription));
+            placed
+                .pending
+                .retain(|subscription| !is_revoked(subscription));
+        }));
+        session.stop_handle()
+    };
+
+    {
+        let mut inner = lock(pool);
+        let reserved = inner
+            .connections
+            .iter_mut()
+            .find(|connection| Arc::ptr_eq(&connection.placed, &placed));
+        match reserved {
+            Some(connection) => connection.stop = Some(stop),
+            // The pool was closed while connecting
+            None => {
+                let _ = session.lock()?.socket.close(None);
+                return Ok(());
+            }
+        }
+    }
+
+    let listener_session = Arc::clone(&session);
+    let lost = Arc::clone(&placed);
+    let weak_pool = Arc::downgrade(pool);
+    let listener = thread::Builder::new()
+        .name("pool-listener".into())
+        .spawn(move || {
+            if let Err(err) = crate::create_message_processor(listener_session, &forwarder) {
+                emit(
+                    &events,
+                    ClientEvent::Disconnected {
+                        reason: format!("pooled connection was lost: {}", err),
+                    },
+                );
+            }
+            rebalance(&weak_pool, &lost);
+        });
+    if let Err(err) = listener {
+        let _ = session.lock()?.socket.close(None);
+        return Err(unreserve(err.into()));
+    }
+    Ok(())
+}
+
+/// Moves the subscriptions of a connection that was lost to the remaining connections. Those
+/// which can't be moved are reported as `ClientEvent::Unplaced`.
+fn rebalance(pool: &Weak<Mutex<PoolInner>>, lost: &Arc<Mutex<Placed>>) {
+    let Some(pool) = pool.upgrade() else {
+        return;
+    };
+    let events = {
+        let mut inner = lock(&pool);
+        if inner.closing {
+            return;
+        }
+        inner
+            .connections
+            .retain(|connection| !Arc::ptr_eq(&connection.placed, lost));
+        inner.events.clone()
+    };
+    let orphaned = {
+        let mut placed = lock_placed(lost);
+        let mut orphaned = std::mem::take(&mut placed.subscriptions);
+        orphaned.append(&mut placed.pending);
+        orphaned
+    };
+    for subscription in orphaned {
+        if let Err(err) = place(&pool, subscription.clone()) {
+            emit(
+                &events,
+                ClientEvent::Unplaced {
+                    subscription,
+                    reason: err.to_string(),
+                },
+            );
+        }
+    }
+}
+
+fn emit(events: &Option<Sender<ClientEvent>>, event: ClientEvent) {
+    if let Some(events) = events {
+        let _ = events.send(event);
+    }
+}
+
+fn lock(inner: &Mutex<PoolInner>) -> MutexGuard<'_, PoolInner> {
+    inner.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+fn lock_placed(placed: &Mutex<Placed>) -> MutexGuard<'_, Placed> {
+    placed.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::SubscriptionErr;
+    use crate::events::ClientEvent;
+    use crate::mock::{self, MockServer, Step};
+    use serde_json::json;
+    use std::sync::mpsc::Receiver;
+    use std::time::Duration;
+
+    struct FakeCreator {
+        created: Mutex<Sender<(String, String)>>,
+    }
+
+    impl SubscriptionCreator for FakeCreator {
+        fn create_subscription(
+            &self,
+            subscription: &DesiredSubscription,
+            session_id: &str,
+        ) -> Result<(), SubscriptionErr> {
+            if subscription.r#type == "channel.ban" {
+                return Err(SubscriptionErr::Rejected("missing scope".to_owned()));
+            }
+            let created = (subscription.r#type.clone(), session_id.to_owned());
+            let _ = self.created.lock().unwrap().send(created);
+            Ok(())
+        }
+    }
+
+    fn subscription(r#type: &str) -> DesiredSubscription {
+        DesiredSubscription::new(r#type, "1", json!({"broadcaster_user_id": "1"}))
+    }
+
+    fn next(created: &Receiver<(String, String)>) -> (String, String) {
+        created.recv_timeout(Duration::from_secs(5)).unwrap()
+    }
+
+    #[test]
+    fn subscriptions_spill_over_and_move_off_lost_connections() {
+        let server = MockServer::start(vec![
+            vec![Step::Send(mock::welcome("first", 10))],
+            vec![
+                Step::Send(mock::welcome("second", 10)),
+                Step::WaitFor("lose"),
+                Step::Close(4003),
+            ],
+            vec![Step::Send(mock::welcome("third", 10))],
+        ]);
+        let (created_tx, created) = mpsc::channel();
+        let creator = FakeCreator {
+            created: Mutex::new(created_tx),
+        };
+        let (tx, messages) = mpsc::channel();
+        let pool = SessionPool::new(server.url(), creator, tx);
+        pool.set_limits(PoolLimits {
+            subscriptions_per_connection: 2,
+            max_connections: 2,
+        });
+
+        for r#type in [
+            "stream.online",
+            "stream.offline",
+            "channel.update",
+            "channel.raid",
+        ] {
+            pool.subscribe(subscription(r#type)).unwrap();
+        }
+        assert!(matches!(
+            pool.subscribe(subscription("channel.ban")),
+            Err(PoolErr::Full)
+        ));
+        assert_eq!(pool.connections(), 2);
+
+        let mut placed: Vec<_> = (0..4).map(|_| next(&created)).collect();
+        placed.sort();
+        assert_eq!(
+            placed,
+            [
+                ("channel.raid", "second"),
+                ("channel.update", "second"),
+                ("stream.offline", "first"),
+                ("stream.online", "first")
+            ]
+            .map(|(r#type, session)| (r#type.to_owned(), session.to_owned()))
+        );
+        let welcomes: Vec<TwitchMessage> = messages.iter().take(2).collect();
+        assert!(welcomes
+            .iter()
+            .all(|msg| matches!(msg, TwitchMessage::Welcome(_))));
+
+        server.signal("lose");
+        let mut moved = vec![next(&created), next(&created)];
+        moved.sort();
+        assert_eq!(
+            moved,
+            [("channel.raid", "third"), ("channel.update", "third")]
+                .map(|(r#type, session)| (r#type.to_owned(), session.to_owned()))
+        );
+        assert_eq!(pool.connections(), 2);
+        pool.close().unwrap();
+    }
+
+    #[test]
+    fn only_created_subscriptions_take_up_room() {
+        let server = MockServer::start(vec![vec![Step::Send(mock::welcome("session", 10))]]);
+        let (created_tx, _created) = mpsc::channel();
+        let creator = FakeCreator {
+            created: Mutex::new(created_tx),
+        };
+        let (tx, _messages) = mpsc::channel();
+        let pool = SessionPool::new(server.url(), creator, tx);
+        let (events_tx, events) = mpsc::channel();
+        pool.set_event_forwarder(events_tx);
+        pool.set_limits(PoolLimits {
+            subscriptions_per_connection: 2,
+            max_connections: 1,
+        });
+
+        pool.subscribe(subscription("channel.ban")).unwrap();
+        let report = loop {
+            match events.recv_timeout(Duration::from_secs(5)).unwrap() {
+                ClientEvent::Subscribed(report) => break report,
+                _ => continue,
+            }
+        };
+        assert!(report.created.is_empty());
+        assert_eq!(report.failed[0].0, subscription("channel.ban"));
+
+        assert!(matches!(
+            pool.subscribe(subscription("channel.ban")),
+            Err(PoolErr::Subscription(_))
+        ));
+        pool.subscribe(subscription("stream.online")).unwrap();
+        pool.subscribe(subscription("stream.offline")).unwrap();
+        assert_eq!(
+            pool.placements(),
+            [[
+                subscription("stream.online"),
+                subscription("stream.offline")
+            ]]
+        );
+        assert!(matches!(
+            pool.subscribe(subscription("channel.update")),
+            Err(PoolErr::Full)
+        ));
+        pool.close().unwrap();
+    }
+
+    /// Connects only once it is let through.
+    #[derive(Debug)]
+    struct GatedConnector {
+        gate: Mutex<Receiver<()>>,
+    }
+
+    impl Connector for GatedConnector {
+        fn connect(&self, url: &Url) -> tungstenite::Result<Box<dyn crate::source::MessageSource>> {
+            let _ = self.gate.lock().unwrap().recv();
+            WebSocketConnector.connect(url)
+        }
+    }
+
+    #[test]
+    fn pool_stays_available_while_connecting() {
+        let server = MockServer::start(vec![vec![Step::Send(mock::welcome("session", 10))]]);
+        let (created_tx, created) = mpsc::channel();
+        let creator = FakeCreator {
+            created: Mutex::new(created_tx),
+        };
+        let (gate_tx, gate) = mpsc::channel();
+        let connector = Arc::new(GatedConnector {
+            gate: Mutex::new(gate),
+        });
+        let (tx, _messages) = mpsc::channel();
+        let pool = Arc::new(SessionPool::with_connector(
+            server.url(),
+            connector,
+            creator,
+            tx,
+        ));
+        pool.set_limits(PoolLimits {
+            subscriptions_per_connection: 2,
+            max_connections: 1,
+        });
+
+        let opening = {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || pool.subscribe(subscription("stream.online")))
+        };
+        while pool.connections() == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        // The connection being opened has room, and counts towards the limit
+        pool.subscribe(subscription("stream.offline")).unwrap();
+        assert!(matches!(
+            pool.subscribe(subscription("channel.update")),
+            Err(PoolErr::Full)
+        ));
+
+        gate_tx.send(()).unwrap();
+        opening.join().unwrap().unwrap();
+        let mut placed = vec![next(&created), next(&created)];
+        placed.sort();
+        assert_eq!(
+            placed,
+            [("stream.offline", "session"), ("stream.online", "session")]
+                .map(|(r#type, session)| (r#type.to_owned(), session.to_owned()))
+        );
+        pool.close().unwrap();
+    }
+}
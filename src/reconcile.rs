@@ -0,0 +1,441 @@
+//! Keeps the subscriptions on Twitch in line with a declared set of desired subscriptions. Rather
+//! than creating and deleting subscriptions one by one, the application declares what it wants,
+//! in code or in a JSON file, and the `Reconciler` compares that with what Helix lists:
+//!
+//! - Desired subscriptions which aren't enabled on the current session are created.
+//! - Subscriptions which stopped delivering, because their WebSocket disconnected or their
+//!   authorization was revoked, are deleted.
+//! - Enabled subscriptions on the current session which are no longer desired are deleted.
+//!
+//! Subscriptions of other transports and other live sessions are left alone. Once attached to a
+//! `Session`, this runs after every new session, and again after every `Revocation`.
+
+use crate::error::HelixErr;
+use crate::helix::{
+    CreateSubscriptionRequest, HelixClient, Subscription, SubscriptionFilter, SubscriptionStatus,
+};
+use crate::subscriptions::DesiredSubscription;
+use crate::types::Session;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// How the subscriptions on Twitch differed from the desired ones, and what was done about it.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Drift {
+    pub session_id: String,
+    /// Desired subscriptions which were missing, and have been created.
+    pub created: Vec<DesiredSubscription>,
+    /// Missing subscriptions which couldn't be created, along with the reason.
+    pub failed: Vec<(DesiredSubscription, String)>,
+    /// Stale or undesired subscriptions, which have been deleted.
+    pub deleted: Vec<Subscription>,
+    /// Subscriptions which should have been deleted, but couldn't be, along with the reason.
+    pub undeleted: Vec<(Subscription, String)>,
+    /// Why the subscriptions couldn't be listed, in which case nothing else was done.
+    pub unlisted: Option<String>,
+}
+
+impl Drift {
+    /// Whether Twitch already held exactly the desired subscriptions.
+    pub fn is_empty(&self) -> bool {
+        self.created.is_empty()
+            && self.failed.is_empty()
+            && self.deleted.is_empty()
+            && self.undeleted.is_empty()
+            && self.unlisted.is_none()
+    }
+}
+
+/// Reads a JSON array of desired subscriptions, e.g.
+/// `[{"type": "stream.online", "version": "1", "condition": {"broadcaster_user_id": "1"}}]`.
+pub fn read_desired(path: impl AsRef<Path>) -> io::Result<Vec<DesiredSubscription>> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+/// Whether a subscription with this status will never deliver again, and only takes up cost.
+pub fn is_stale(status: SubscriptionStatus) -> bool {
+    use SubscriptionStatus::*;
+    matches!(
+        status,
+        AuthorizationRevoked
+            | ModeratorRemoved
+            | UserRemoved
+            | VersionRemoved
+            | WebsocketDisconnected
+            | WebsocketFailedPingPong
+            | WebsocketReceivedInboundTraffic
+            | WebsocketConnectionUnused
+            | WebsocketInternalError
+            | WebsocketNetworkTimeout
+            | WebsocketNetworkError
+    )
+}
+
+#[derive(Debug)]
+pub struct Reconciler {
+    helix: Arc<HelixClient>,
+    desired: Mutex<Vec<DesiredSubscription>>,
+    session_id: Mutex<Option<String>>,
+    reports: Sender<Drift>,
+}
+
+impl Reconciler {
+    /// Creates a reconciler for the `desired` subscriptions, along with a receiver for the drift
+    /// found by every run.
+    pub fn new(
+        helix: Arc<HelixClient>,
+        desired: Vec<DesiredSubscription>,
+    ) -> (Arc<Reconciler>, Receiver<Drift>) {
+        let (reports, rx) = mpsc::channel();
+        let reconciler = Reconciler {
+            helix,
+            desired: Mutex::new(vec![]),
+            session_id: Mutex::new(None),
+            reports,
+        };
+        reconciler.declare(desired);
+        (Arc::new(reconciler), rx)
+    }
+
+    /// Replaces the desired subscriptions. They take effect with the next run.
+    pub fn declare(&self, desired: Vec<DesiredSubscription>) {
+        let mut deduped: Vec<DesiredSubscription> = vec![];
+        for subscription in desired {
+            if !deduped.contains(&subscription) {
+                deduped.push(subscription);
+            }
+        }
+        *lock(&self.desired) = deduped;
+    }
+
+    pub fn desired(&self) -> Vec<DesiredSubscription> {
+        lock(&self.desired).clone()
+    }
+
+    /// The ID of the session subscriptions are created on, once one was welcomed.
+    pub fn session_id(&self) -> Option<String> {
+        lock(&self.session_id).clone()
+    }
+
+    /// Reconciles from within the `session`'s `on_welcome` and `on_revoked` callbacks, after any
+    /// callbacks which were already set, so the desired subscriptions are created on every new
+    /// session, and revoked subscriptions are cleaned up, and recreated if the authorization
+    /// allows it. A revoked subscription the session already tried to recreate, see
+    /// `Session::resubscribe_revoked`, isn't created a second time. Reconciling is best-effort, so
+    /// a run that fails to list the subscriptions is left to the next one, after reporting it in
+    /// the `Drift`, and never ends the session.
+    /// Every message is passed to `HelixClient::observe`, to keep the budget up to date.
+    pub fn attach(self: &Arc<Self>, session: &Arc<Mutex<Session>>) {
+        let mut session = session.lock().unwrap_or_else(|err| err.into_inner());
+        let reconciler = Arc::clone(self);
+        let mut previous = session.on_welcome.take();
+        session.on_welcome = Some(Box::new(move |session_id| {
+            if let Some(previous) = &mut previous {
+                previous(session_id)?;
+            }
+            *lock(&reconciler.session_id) = Some(session_id.to_owned());
+            // Failing to list is already reported as drift
+            let _ = reconciler.reconcile(session_id);
+            Ok(())
+        }));
+        let helix = Arc::clone(&self.helix);
+        let mut previous = session.on_message.take();
+        session.on_message = Some(Box::new(move |session_id, message| {
+            if let Some(previous) = &mut previous {
+                previous(session_id, message);
+            }
+            helix.observe(session_id, message);
+        }));
+        let reconciler = Arc::clone(self);
+        let mut previous = session.on_revoked.take();
+        session.on_revoked = Some(Box::new(move |revoked| {
+            if let Some(previous) = &mut previous {
+                previous(revoked);
+            }
+            let Some(session_id) = reconciler.session_id() else {
+                return;
+            };
+            let recreated = revoked
+                .resubscribed
+                .is_some()
+                .then_some(&revoked.subscription);
+            // Failing to list is already reported as drift
+            let _ = reconciler.reconcile_except(&session_id, recreated);
+        }));
+    }
+
+    /// Brings the subscriptions on Twitch in line with the desired ones, for the session with
+    /// `session_id`. Missing subscriptions are created before anything is deleted, to meet the
+    /// subscribe deadline of new sessions. Only failing to list the subscriptions is an error;
+    /// everything else is collected in the returned `Drift`, which is also sent to the receiver,
+    /// along with the error if there is one.
+    pub fn reconcile(&self, session_id: &str) -> Result<Drift, HelixErr> {
+        self.reconcile_except(session_id, None)
+    }
+
+    /// Like `reconcile`, but leaves creating the `skipped` subscription to whoever already did.
+    fn reconcile_except(
+        &self,
+        session_id: &str,
+        skipped: Option<&DesiredSubscription>,
+    ) -> Result<Drift, HelixErr> {
+        let mut drift = Drift {
+            session_id: session_id.to_owned(),
+            ..Default::default()
+        };
+        let existing = match self
+            .helix
+            .list_subscriptions(&SubscriptionFilter::default())
+        {
+            Ok(existing) => existing,
+            Err(err) => {
+                drift.unlisted = Some(err.to_string());
+                let _ = self.reports.send(drift);
+                return Err(err);
+            }
+        };
+        let desired = self.desired();
+        let on_session = |subscription: &&Subscription| {
+            subscription.transport.method == "websocket"
+                && subscription.transport.session_id.as_deref() == Some(session_id)
+                && subscription.status == SubscriptionStatus::Enabled
+        };
+        let enabled: Vec<DesiredSubscription> = existing
+            .iter()
+            .filter(on_session)
+            .map(Subscription::to_desired)
+            .collect();
+
+        let missing = desired
+            .iter()
+            .filter(|desired| !enabled.contains(desired) && skipped != Some(*desired));
+        for subscription in missing {
+            let request = CreateSubscriptionRequest::websocket(subscription, session_id);
+            match self.helix.create_subscription(&request) {
+                Ok(_) => drift.created.push(subscription.clone()),
+                Err(err) => drift.failed.push((subscription.clone(), err.to_string())),
+            }
+        }
+        let undesired = existing
+            .iter()
+            .filter(on_session)
+            .filter(|subscription| !desired.contains(&subscription.to_desired()));
+        let stale = existing.iter().filter(|subscription| {
+            subscription.transport.method == "websocket" && is_stale(subscription.status)
+        });
+        for subscription in undesired.chain(stale) {
+            match self.helix.delete_subscription(&subscription.id) {
+                Ok(()) => drift.deleted.push(subscription.clone()),
+                Err(err) => drift
+                    .undeleted
+                    .push((subscription.clone(), err.to_string())),
+            }
+        }
+        let _ = self.reports.send(drift.clone());
+        Ok(drift)
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::SubscriptionErr;
+    use crate::helix::mock_api;
+    use crate::mock::{self, MockServer, Step};
+    use crate::subscriptions::{SubscriptionCreator, SubscriptionManager};
+    use serde_json::{json, Value};
+    use std::thread;
+    use url::Url;
+
+    fn subscription(id: &str, r#type: &str, status: &str, session_id: &str) -> Value {
+        json!({
+            "id": id,
+            "status": status,
+            "type": r#type,
+            "version": "1",
+            "condition": {"broadcaster_user_id": "1"},
+            "created_at": "2023-01-01T00:00:00Z",
+            "transport": {"method": "websocket", "session_id": session_id},
+            "cost": 0
+        })
+    }
+
+    fn list(data: Vec<Value>) -> (u16, String) {
+        let body = json!({"data": data, "total": 0, "total_cost": 0, "max_total_cost": 10});
+        (200, body.to_string())
+    }
+
+    #[test]
+    fn reconciles_after_welcome_and_revocation() {
+        let (helix_url, requests) = mock_api::serve(vec![
+            list(vec![subscription(
+                "old",
+                "stream.online",
+                "websocket_disconnected",
+                "gone",
+            )]),
+            list(vec![subscription(
+                "online",
+                "stream.online",
+                "enabled",
+                "session",
+            )]),
+            list(vec![subscription(
+                "offline",
+                "stream.offline",
+                "enabled",
+                "session",
+            )]),
+            (204, String::new()),
+            list(vec![
+                subscription(
+                    "online",
+                    "stream.online",
+                    "authorization_revoked",
+                    "session",
+                ),
+                subscription("offline", "stream.offline", "enabled", "session"),
+            ]),
+            (403, json!({"message": "missing authorization"}).to_string()),
+            (204, String::new()),
+        ]);
+        let helix =
+            HelixClient::with_base_url("id", "token", Url::parse(&helix_url).unwrap()).unwrap();
+        let condition = json!({"broadcaster_user_id": "1"});
+        let online = DesiredSubscription::new("stream.online", "1", condition.clone());
+        let offline = DesiredSubscription::new("stream.offline", "1", condition);
+        let (reconciler, reports) =
+            Reconciler::new(Arc::new(helix), vec![online.clone(), offline.clone()]);
+
+        let server = MockServer::start(vec![vec![
+            Step::Send(mock::welcome("session", 10)),
+            Step::Send(mock::revocation("stream.online", "authorization_revoked")),
+            Step::Close(4003),
+        ]]);
+        let session = crate::get_session(server.url()).unwrap();
+        reconciler.attach(&session);
+        let (tx, messages) = mpsc::channel();
+        let listener = thread::spawn(move || crate::create_message_processor(session, &tx));
+        assert_eq!(messages.iter().count(), 2);
+        let _ = listener.join();
+
+        let welcomed = reports.recv().unwrap();
+        assert_eq!(welcomed.session_id, "session");
+        assert_eq!(welcomed.created, vec![online.clone(), offline]);
+        assert_eq!(welcomed.deleted[0].id, "old");
+
+        let revoked = reports.recv().unwrap();
+        assert_eq!(revoked.failed.len(), 1);
+        assert_eq!(revoked.failed[0].0, online);
+        assert_eq!(revoked.deleted[0].id, "online");
+        assert!(reports.try_recv().is_err());
+
+        let methods: Vec<String> = requests.iter().map(|request| request.method).collect();
+        assert_eq!(
+            methods,
+            ["GET", "POST", "POST", "DELETE", "GET", "POST", "DELETE"]
+        );
+    }
+
+    struct Accepting;
+
+    impl SubscriptionCreator for Accepting {
+        fn create_subscription(
+            &self,
+            _subscription: &DesiredSubscription,
+            _session_id: &str,
+        ) -> Result<(), SubscriptionErr> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn resubscribed_revocations_are_not_created_twice() {
+        // In the channel of the mock's revocations
+        let online_with = |status| {
+            let mut online = subscription("online", "stream.online", status, "session");
+            online["condition"]["broadcaster_user_id"] = "12826".into();
+            online
+        };
+        let (helix_url, requests) = mock_api::serve(vec![
+            list(vec![online_with("enabled")]),
+            list(vec![online_with("websocket_network_error")]),
+            (204, String::new()),
+            (500, json!({"message": "internal error"}).to_string()),
+        ]);
+        let helix =
+            HelixClient::with_base_url("id", "token", Url::parse(&helix_url).unwrap()).unwrap();
+        let online = DesiredSubscription::new(
+            "stream.online",
+            "1",
+            json!({"broadcaster_user_id": "12826"}),
+        );
+        let (reconciler, reports) = Reconciler::new(Arc::new(helix), vec![online.clone()]);
+
+        let server = MockServer::start(vec![vec![
+            Step::Send(mock::welcome("session", 10)),
+            Step::Send(mock::revocation("stream.online", "websocket_network_error")),
+            Step::Send(mock::revocation("stream.online", "websocket_network_error")),
+            Step::Close(4003),
+        ]]);
+        let session = crate::get_session(server.url()).unwrap();
+        {
+            let mut session = session.lock().unwrap();
+            let mut manager = SubscriptionManager::new(Accepting);
+            manager.remember(online);
+            session.subscriptions = Some(manager);
+            session.resubscribe_revoked = true;
+        }
+        reconciler.attach(&session);
+        let (tx, messages) = mpsc::channel();
+        let listener = thread::spawn(move || crate::create_message_processor(session, &tx));
+        assert_eq!(messages.iter().count(), 3);
+        let _ = listener.join();
+
+        assert!(reports.recv().unwrap().is_empty());
+        let revoked = reports.recv().unwrap();
+        assert!(revoked.created.is_empty() && revoked.failed.is_empty());
+        assert_eq!(revoked.deleted[0].id, "online");
+        let unlisted = reports.recv().unwrap();
+        assert!(unlisted.unlisted.unwrap().contains("internal error"));
+
+        let methods: Vec<String> = requests.iter().map(|request| request.method).collect();
+        assert_eq!(methods, ["GET", "GET", "DELETE", "GET"]);
+    }
+
+    #[test]
+    fn failed_listing_after_welcome_keeps_the_session() {
+        let (helix_url, _requests) = mock_api::serve(vec![(
+            500,
+            json!({"message": "internal error"}).to_string(),
+        )]);
+        let helix =
+            HelixClient::with_base_url("id", "token", Url::parse(&helix_url).unwrap()).unwrap();
+        let online = DesiredSubscription::new("stream.online", "1", json!({}));
+        let (reconciler, reports) = Reconciler::new(Arc::new(helix), vec![online]);
+
+        let server = MockServer::start(vec![vec![
+            Step::Send(mock::welcome("session", 10)),
+            Step::Send(mock::notification("stream.online", json!({}))),
+            Step::Close(4003),
+        ]]);
+        let session = crate::get_session(server.url()).unwrap();
+        reconciler.attach(&session);
+        let (tx, messages) = mpsc::channel();
+        let listener = thread::spawn(move || crate::create_message_processor(session, &tx));
+        // The notification is still delivered after the failed run
+        assert_eq!(messages.iter().count(), 2);
+        let _ = listener.join();
+
+        let unlisted = reports.recv().unwrap();
+        assert_eq!(unlisted.session_id, "session");
+        assert!(unlisted.unlisted.unwrap().contains("internal error"));
+    }
+}
@@ -0,0 +1,505 @@
+//! Records the raw frames of a session, to reproduce incidents locally. A `Recorder` set on the
+//! `Session` writes every text and close frame to a JSONL file, along with the time it arrived and
+//! the connection it arrived on. A `ReplaySource` then acts as the `MessageSource` of a session,
+//! so the frames run through the same parsing, deduplication and handlers as they did live.
+//!
+//! ```no_run
+//! use eventsub_websocket::recording::{Recorder, ReplaySource, ReplaySpeed};
+//! use eventsub_websocket::{create_message_processor, get_default_url, get_session};
+//! use std::sync::{mpsc, Arc};
+//!
+//! // While running against Twitch
+//! let session = get_session(get_default_url().unwrap()).unwrap();
+//! session.lock().unwrap().recorder = Some(Recorder::create("session.jsonl").unwrap());
+//!
+//! // Later, to reproduce what happened
+//! let session = ReplaySource::open("session.jsonl")
+//!     .unwrap()
+//!     .speed(ReplaySpeed::Scaled(10.0))
+//!     .unwrap()
+//!     .session()
+//!     .unwrap();
+//! let (tx, rx) = mpsc::channel();
+//! create_message_processor(Arc::clone(&session), &tx).unwrap();
+//! ```
+
+use crate::clock::Clock;
+use crate::error::{EventSubErr, ReplayErr};
+use crate::get_session_with;
+use crate::keepalive::READ_TICK;
+use crate::source::{Connector, MessageSource};
+use crate::types::Session;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::Message;
+use url::Url;
+
+/// One line of a recording.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RecordedFrame {
+    /// When the frame arrived, according to the session's clock.
+    pub received_at: DateTime<Utc>,
+    /// The connection the frame arrived on. The session's first connection is 0, and every
+    /// connection it opens afterwards, for reconnects and migrations, gets the next ID.
+    pub connection: u64,
+    #[serde(flatten)]
+    pub frame: RecordedData,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RecordedData {
+    Text { text: String },
+    Close { code: u16 },
+}
+
+/// Writes a recording, one JSON object per line. Each frame is flushed right away, so a recording
+/// survives the process crashing.
+pub struct Recorder {
+    writer: Box<dyn Write + Send>,
+}
+
+impl Recorder {
+    pub fn new(writer: impl Write + Send + 'static) -> Recorder {
+        Recorder {
+            writer: Box::new(writer),
+        }
+    }
+
+    /// Records to a new file at the `path`, replacing any existing one.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Recorder> {
+        Ok(Recorder::new(BufWriter::new(File::create(path)?)))
+    }
+
+    /// Writes a frame received on the `connection`. Frames other than text and close frames are
+    /// skipped.
+    pub fn record(
+        &mut self,
+        connection: u64,
+        received_at: DateTime<Utc>,
+        msg: &Message,
+    ) -> io::Result<()> {
+        let frame = match msg {
+            Message::Text(text) => RecordedData::Text { text: text.clone() },
+            Message::Close(frame) => RecordedData::Close {
+                code: frame.as_ref().map_or(1005, |frame| frame.code.into()),
+            },
+            _ => return Ok(()),
+        };
+        let line = serde_json::to_string(&RecordedFrame {
+            received_at,
+            connection,
+            frame,
+        })?;
+        writeln!(self.writer, "{}", line)?;
+        self.writer.flush()
+    }
+}
+
+impl fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recorder").finish_non_exhaustive()
+    }
+}
+
+/// How fast a recording is replayed, relative to how the frames originally arrived.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaySpeed {
+    Original,
+    /// Replays this many times faster, e.g. `0.5` for half the speed. The factor has to be finite
+    /// and above 0.
+    Scaled(f64),
+    /// Sends every frame as soon as the client is connected.
+    AsFastAsPossible,
+}
+
+impl ReplaySpeed {
+    /// The factor real time is multiplied with, or `None` if time doesn't pass during a replay.
+    fn factor(self) -> Option<f64> {
+        match self {
+            ReplaySpeed::Original => Some(1.0),
+            ReplaySpeed::Scaled(factor) => Some(factor),
+            ReplaySpeed::AsFastAsPossible => None,
+        }
+    }
+}
+
+/// A recording, ready to be served to a client.
+#[derive(Debug, Clone)]
+pub struct ReplaySource {
+    frames: Vec<RecordedFrame>,
+    speed: ReplaySpeed,
+}
+
+impl ReplaySource {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<ReplaySource> {
+        ReplaySource::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    pub fn from_reader(reader: impl BufRead) -> io::Result<ReplaySource> {
+        let mut frames = vec![];
+        for line in reader.lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                frames.push(serde_json::from_str(&line)?);
+            }
+        }
+        Ok(ReplaySource {
+            frames,
+            speed: ReplaySpeed::Original,
+        })
+    }
+
+    pub fn speed(mut self, speed: ReplaySpeed) -> Result<ReplaySource, ReplayErr> {
+        if let ReplaySpeed::Scaled(factor) = speed {
+            if !factor.is_finite() || factor <= 0.0 {
+                return Err(ReplayErr::InvalidSpeed(factor));
+            }
+        }
+        self.speed = speed;
+        Ok(self)
+    }
+
+    pub fn frames(&self) -> &[RecordedFrame] {
+        &self.frames
+    }
+
+    /// Opens a session replaying the recording, with a clock that follows the recorded times.
+    pub fn session(self) -> Result<Arc<Mutex<Session>>, EventSubErr> {
+        let connector = Arc::new(self.connector());
+        let clock = connector.clock();
+        let url = Url::parse(REPLAY_URL)?;
+        let session = get_session_with(url, connector)?;
+        session.lock()?.set_clock(clock);
+        Ok(session)
+    }
+
+    /// Replays the recording as the connections of a session. Every connection the session opens
+    /// reads the frames of the next connection in the recording, at the same offsets from the
+    /// first frame as they were recorded at. A connection which was lost without a close frame
+    /// is reported as reset after its last frame, so the session reconnects, and the recording
+    /// ends with the last connection being closed normally.
+    pub fn connector(self) -> ReplayConnector {
+        let first_received_at = self
+            .frames
+            .first()
+            .map_or_else(Utc::now, |frame| frame.received_at);
+        let clock = Arc::new(ReplayClock {
+            speed: self.speed,
+            progress: Mutex::new((first_received_at, Instant::now())),
+            started: Mutex::new(None),
+            first_received_at,
+            origin: Instant::now(),
+        });
+        let mut connections: BTreeMap<u64, VecDeque<RecordedFrame>> = BTreeMap::new();
+        for frame in self.frames {
+            connections
+                .entry(frame.connection)
+                .or_default()
+                .push_back(frame);
+        }
+        ReplayConnector {
+            connections: Mutex::new(connections.into_values().collect()),
+            clock,
+        }
+    }
+}
+
+/// The url of replayed sessions, which the `ReplayConnector` ignores.
+const REPLAY_URL: &str = "replay://recording";
+
+/// Opens the recorded connections of a `ReplaySource`, in order.
+#[derive(Debug)]
+pub struct ReplayConnector {
+    connections: Mutex<VecDeque<VecDeque<RecordedFrame>>>,
+    clock: Arc<ReplayClock>,
+}
+
+impl ReplayConnector {
+    /// The clock to set on the replaying `Session`. It follows the recorded times, so keepalive
+    /// and freshness checks see the same gaps and timestamps as they did live.
+    pub fn clock(&self) -> Arc<ReplayClock> {
+        Arc::clone(&self.clock)
+    }
+}
+
+impl Connector for ReplayConnector {
+    fn connect(&self, _url: &Url) -> tungstenite::Result<Box<dyn MessageSource>> {
+        let mut connections = self.connections.lock().unwrap();
+        let mut frames = connections.pop_front().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "the recording has no more connections",
+            )
+        })?;
+        let last = connections.is_empty();
+        if last
+            && matches!(frames.back(), Some(frame) if matches!(frame.frame, RecordedData::Close { .. }))
+        {
+            // The end of the recording closes the session instead
+            frames.pop_back();
+        }
+        Ok(Box::new(ReplayStream {
+            frames,
+            clock: Arc::clone(&self.clock),
+            last,
+            closing: false,
+        }))
+    }
+}
+
+/// One recorded connection, as it is replayed.
+#[derive(Debug)]
+struct ReplayStream {
+    frames: VecDeque<RecordedFrame>,
+    clock: Arc<ReplayClock>,
+    last: bool,
+    closing: bool,
+}
+
+impl MessageSource for ReplayStream {
+    fn read_message(&mut self) -> tungstenite::Result<Message> {
+        let received_at = match self.frames.front() {
+            Some(frame) => frame.received_at,
+            None if self.last => return Err(tungstenite::Error::ConnectionClosed),
+            None => return Err(io::Error::from(io::ErrorKind::ConnectionReset).into()),
+        };
+        if let Some(due) = self.clock.due(received_at) {
+            let wait = due.saturating_duration_since(Instant::now());
+            thread::sleep(wait.min(READ_TICK));
+            if wait > READ_TICK {
+                return Err(io::Error::from(io::ErrorKind::WouldBlock).into());
+            }
+        }
+        let frame = self.frames.pop_front().expect("checked above");
+        self.clock.sent(frame.received_at);
+        Ok(match frame.frame {
+            RecordedData::Text { text } => Message::Text(text),
+            RecordedData::Close { code } => Message::Close(Some(CloseFrame {
+                code: CloseCode::from(code),
+                reason: "".into(),
+            })),
+        })
+    }
+
+    fn write_message(&mut self, _msg: Message) -> tungstenite::Result<()> {
+        Ok(())
+    }
+
+    fn close(&mut self, _frame: Option<CloseFrame<'static>>) -> tungstenite::Result<()> {
+        self.closing = true;
+        Ok(())
+    }
+
+    fn write_pending(&mut self) -> tungstenite::Result<()> {
+        Ok(())
+    }
+
+    fn can_write(&self) -> bool {
+        !self.closing
+    }
+}
+
+/// The time within a replayed recording: the time of the last frame sent, plus the time passed
+/// since then, scaled by the replay speed.
+#[derive(Debug)]
+pub struct ReplayClock {
+    speed: ReplaySpeed,
+    /// The recorded time of the last frame sent, and when it was sent.
+    progress: Mutex<(DateTime<Utc>, Instant)>,
+    /// When the first connection was accepted, which the frames' offsets are relative to.
+    started: Mutex<Option<Instant>>,
+    first_received_at: DateTime<Utc>,
+    /// The monotonic time of the first frame.
+    origin: Instant,
+}
+
+impl ReplayClock {
+    fn since_last_frame(&self) -> (DateTime<Utc>, Duration) {
+        let (received_at, sent_at) = *self.progress.lock().unwrap();
+        let passed = match self.speed.factor() {
+            Some(factor) => sent_at.elapsed().mul_f64(factor),
+            None => Duration::ZERO,
+        };
+        (received_at, passed)
+    }
+
+    /// When the frame recorded at `received_at` is due, in real time.
+    fn due(&self, received_at: DateTime<Utc>) -> Option<Instant> {
+        let factor = self.speed.factor()?;
+        let started = *self
+            .started
+            .lock()
+            .unwrap()
+            .get_or_insert_with(Instant::now);
+        let offset = (received_at - self.first_received_at)
+            .to_std()
+            .unwrap_or_default();
+        Some(started + offset.div_f64(factor))
+    }
+
+    fn sent(&self, received_at: DateTime<Utc>) {
+        let mut progress = self.progress.lock().unwrap();
+        if received_at > progress.0 {
+            *progress = (received_at, Instant::now());
+        }
+    }
+}
+
+impl Clock for ReplayClock {
+    fn now(&self) -> Instant {
+        let (received_at, passed) = self.since_last_frame();
+        let recorded = (received_at - self.first_received_at)
+            .to_std()
+            .unwrap_or_default();
+        self.origin + recorded + passed
+    }
+
+    fn wall(&self) -> DateTime<Utc> {
+        let (received_at, passed) = self.since_last_frame();
+        received_at + chrono::Duration::from_std(passed).unwrap_or_default()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        if let Some(factor) = self.speed.factor() {
+            thread::sleep(duration.div_f64(factor));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::ClientEvent;
+    use crate::migration::ReconnectUrlPolicy;
+    use crate::mock::{self, MockServer, Step};
+    use crate::{create_message_processor, get_session};
+    use std::sync::mpsc;
+
+    /// A writer whose contents stay readable after it was handed to a `Recorder`.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn replay_reproduces_a_migration() {
+        let server = MockServer::start(vec![
+            vec![
+                Step::Send(mock::welcome("session", 10)),
+                Step::Send(mock::keepalive()),
+                Step::Reconnect,
+                Step::Send(mock::keepalive()),
+                Step::WaitFor("welcomed"),
+                Step::Close(1000),
+            ],
+            vec![
+                Step::Send(mock::welcome("session", None)),
+                Step::Signal("welcomed"),
+                Step::Send(mock::keepalive()),
+            ],
+        ]);
+        let buffer = SharedBuffer::default();
+        let session = get_session(server.url()).unwrap();
+        session.lock().unwrap().recorder = Some(Recorder::new(buffer.clone()));
+        session.lock().unwrap().reconnect_policy = ReconnectUrlPolicy::with_loopback();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || create_message_processor(session, &tx));
+        let live: Vec<String> = rx.iter().take(6).map(|msg| msg.id()).collect();
+
+        let recording = buffer.0.lock().unwrap().clone();
+        let replay = ReplaySource::from_reader(&recording[..])
+            .unwrap()
+            .speed(ReplaySpeed::AsFastAsPossible)
+            .unwrap();
+        assert_eq!(replay.frames()[0].connection, 0);
+        assert!(replay
+            .frames()
+            .iter()
+            .any(|frame| frame.frame == RecordedData::Close { code: 1000 }));
+        let session = replay.session().unwrap();
+        let (event_tx, events) = mpsc::channel();
+        session.lock().unwrap().event_forwarder = Some(event_tx);
+        session.lock().unwrap().reconnect_policy = ReconnectUrlPolicy::with_loopback();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || create_message_processor(session, &tx));
+        let replayed: Vec<String> = rx.iter().take(6).map(|msg| msg.id()).collect();
+
+        assert_eq!(replayed, live);
+        assert!(events
+            .try_iter()
+            .any(|event| event == ClientEvent::Migrated));
+    }
+
+    #[test]
+    fn replay_speed_has_to_be_positive_and_finite() {
+        for factor in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let replay = ReplaySource::from_reader(&b""[..]).unwrap();
+            assert!(matches!(
+                replay.speed(ReplaySpeed::Scaled(factor)),
+                Err(ReplayErr::InvalidSpeed(_))
+            ));
+        }
+        let replay = ReplaySource::from_reader(&b""[..]).unwrap();
+        assert!(replay.speed(ReplaySpeed::Scaled(0.5)).is_ok());
+    }
+
+    struct FullDisk;
+
+    impl Write for FullDisk {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("no space left on device"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failing_recorder_is_dropped_and_reported() {
+        let server = MockServer::start(vec![vec![
+            Step::Send(mock::welcome("session", 10)),
+            Step::Send(mock::keepalive()),
+            Step::Close(4003),
+        ]]);
+        let session = get_session(server.url()).unwrap();
+        let (event_tx, events) = mpsc::channel();
+        session.lock().unwrap().event_forwarder = Some(event_tx);
+        session.lock().unwrap().recorder = Some(Recorder::new(FullDisk));
+        let (tx, rx) = mpsc::channel();
+        let listener_session = Arc::clone(&session);
+        thread::spawn(move || create_message_processor(listener_session, &tx));
+
+        assert_eq!(rx.iter().count(), 2);
+        assert!(session.lock().unwrap().recorder.is_none());
+        let stopped: Vec<ClientEvent> = events
+            .try_iter()
+            .filter(|event| matches!(event, ClientEvent::RecordingStopped { .. }))
+            .collect();
+        assert_eq!(
+            stopped,
+            [ClientEvent::RecordingStopped {
+                reason: "no space left on device".to_owned()
+            }]
+        );
+    }
+}
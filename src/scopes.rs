@@ -0,0 +1,261 @@
+//! The scopes and condition fields each subscription type needs, to check a subscription before
+//! sending it to Helix. Twitch answers a subscription the token isn't authorized for with a bare
+//! `403 Forbidden`, while `check_subscription` names the scope or the user that is missing.
+//!
+//! Whether the token's user actually is a moderator in the broadcaster's channel can't be told
+//! from the token alone, so for moderator subscriptions only the `moderator_user_id` is checked.
+
+use crate::auth::TokenInfo;
+use crate::error::ScopeErr;
+use serde_json::Value;
+
+/// What a subscription type needs, besides a user access token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Requirement {
+    pub r#type: &'static str,
+    pub version: &'static str,
+    /// The token needs one of these scopes. Empty if no scope is needed.
+    pub scopes: &'static [&'static str],
+    /// The fields the condition must contain.
+    pub condition: &'static [&'static str],
+    /// The condition field that must hold the ID of the token's user, if any.
+    pub authorized_user: Option<&'static str>,
+}
+
+const BROADCASTER: &str = "broadcaster_user_id";
+const MODERATOR: &str = "moderator_user_id";
+const USER: &str = "user_id";
+
+const fn broadcaster(r#type: &'static str, scopes: &'static [&'static str]) -> Requirement {
+    Requirement {
+        r#type,
+        version: "1",
+        scopes,
+        condition: &[BROADCASTER],
+        authorized_user: Some(BROADCASTER),
+    }
+}
+
+const fn moderator(r#type: &'static str, scopes: &'static [&'static str]) -> Requirement {
+    Requirement {
+        r#type,
+        version: "1",
+        scopes,
+        condition: &[BROADCASTER, MODERATOR],
+        authorized_user: Some(MODERATOR),
+    }
+}
+
+const fn chat(r#type: &'static str) -> Requirement {
+    Requirement {
+        r#type,
+        version: "1",
+        scopes: &["user:read:chat"],
+        condition: &[BROADCASTER, USER],
+        authorized_user: Some(USER),
+    }
+}
+
+const fn public(r#type: &'static str, version: &'static str) -> Requirement {
+    Requirement {
+        r#type,
+        version,
+        scopes: &[],
+        condition: &[BROADCASTER],
+        authorized_user: None,
+    }
+}
+
+const SUBSCRIPTIONS: &[&str] = &["channel:read:subscriptions"];
+const VIPS: &[&str] = &["channel:read:vips", "channel:manage:vips"];
+const REDEMPTIONS: &[&str] = &["channel:read:redemptions", "channel:manage:redemptions"];
+const POLLS: &[&str] = &["channel:read:polls", "channel:manage:polls"];
+const PREDICTIONS: &[&str] = &["channel:read:predictions", "channel:manage:predictions"];
+const SHIELD_MODE: &[&str] = &["moderator:read:shield_mode", "moderator:manage:shield_mode"];
+const SHOUTOUTS: &[&str] = &["moderator:read:shoutouts", "moderator:manage:shoutouts"];
+
+/// The requirements of the subscription types available over WebSockets.
+pub const REQUIREMENTS: &[Requirement] = &[
+    public("channel.update", "2"),
+    public("stream.online", "1"),
+    public("stream.offline", "1"),
+    Requirement {
+        r#type: "channel.raid",
+        version: "1",
+        scopes: &[],
+        // Either from_broadcaster_user_id or to_broadcaster_user_id
+        condition: &[],
+        authorized_user: None,
+    },
+    Requirement {
+        r#type: "user.update",
+        version: "1",
+        scopes: &[],
+        condition: &[USER],
+        authorized_user: None,
+    },
+    Requirement {
+        r#type: "user.whisper.message",
+        version: "1",
+        scopes: &["user:read:whispers", "user:manage:whispers"],
+        condition: &[USER],
+        authorized_user: Some(USER),
+    },
+    Requirement {
+        version: "2",
+        ..moderator("channel.follow", &["moderator:read:followers"])
+    },
+    broadcaster("channel.subscribe", SUBSCRIPTIONS),
+    broadcaster("channel.subscription.end", SUBSCRIPTIONS),
+    broadcaster("channel.subscription.gift", SUBSCRIPTIONS),
+    broadcaster("channel.subscription.message", SUBSCRIPTIONS),
+    broadcaster("channel.cheer", &["bits:read"]),
+    broadcaster("channel.ban", &["channel:moderate"]),
+    broadcaster("channel.unban", &["channel:moderate"]),
+    broadcaster("channel.moderator.add", &["moderation:read"]),
+    broadcaster("channel.moderator.remove", &["moderation:read"]),
+    broadcaster("channel.vip.add", VIPS),
+    broadcaster("channel.vip.remove", VIPS),
+    broadcaster(
+        "channel.channel_points_custom_reward_redemption.add",
+        REDEMPTIONS,
+    ),
+    broadcaster(
+        "channel.channel_points_custom_reward_redemption.update",
+        REDEMPTIONS,
+    ),
+    broadcaster("channel.poll.begin", POLLS),
+    broadcaster("channel.poll.progress", POLLS),
+    broadcaster("channel.poll.end", POLLS),
+    broadcaster("channel.prediction.begin", PREDICTIONS),
+    broadcaster("channel.prediction.progress", PREDICTIONS),
+    broadcaster("channel.prediction.lock", PREDICTIONS),
+    broadcaster("channel.prediction.end", PREDICTIONS),
+    broadcaster("channel.hype_train.begin", &["channel:read:hype_train"]),
+    broadcaster("channel.hype_train.progress", &["channel:read:hype_train"]),
+    broadcaster("channel.hype_train.end", &["channel:read:hype_train"]),
+    broadcaster("channel.goal.begin", &["channel:read:goals"]),
+    broadcaster("channel.goal.progress", &["channel:read:goals"]),
+    broadcaster("channel.goal.end", &["channel:read:goals"]),
+    broadcaster("channel.ad_break.begin", &["channel:read:ads"]),
+    moderator("channel.shield_mode.begin", SHIELD_MODE),
+    moderator("channel.shield_mode.end", SHIELD_MODE),
+    moderator("channel.shoutout.create", SHOUTOUTS),
+    moderator("channel.shoutout.receive", SHOUTOUTS),
+    chat("channel.chat.message"),
+    chat("channel.chat.notification"),
+    chat("channel.chat.clear"),
+];
+
+/// The requirements of the subscription type's version, if it is in the table.
+pub fn requirement(r#type: &str, version: &str) -> Option<&'static Requirement> {
+    REQUIREMENTS
+        .iter()
+        .find(|req| req.r#type == r#type && req.version == version)
+}
+
+/// Checks that a subscription can be created with the token described by the `token_info`, and
+/// explains what is missing if not.
+pub fn check_subscription(
+    token_info: &TokenInfo,
+    r#type: &str,
+    version: &str,
+    condition: &Value,
+) -> Result<(), ScopeErr> {
+    let requirement = requirement(r#type, version)
+        .ok_or_else(|| ScopeErr::UnknownType(r#type.to_owned(), version.to_owned()))?;
+    let user_id = token_info.user_id.as_deref().ok_or(ScopeErr::NoUser)?;
+    for field in requirement.condition {
+        if condition.get(field).map_or(true, Value::is_null) {
+            return Err(ScopeErr::MissingCondition(field));
+        }
+    }
+    if !requirement.scopes.is_empty()
+        && !requirement
+            .scopes
+            .iter()
+            .any(|scope| token_info.scopes.iter().any(|granted| granted == scope))
+    {
+        return Err(ScopeErr::MissingScope(requirement.scopes.to_vec()));
+    }
+    if let Some(field) = requirement.authorized_user {
+        let expected = match &condition[field] {
+            Value::String(id) => id.clone(),
+            other => other.to_string(),
+        };
+        if expected != user_id {
+            return Err(ScopeErr::WrongUser {
+                field,
+                expected,
+                actual: user_id.to_owned(),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn token(user_id: &str, scopes: &[&str]) -> TokenInfo {
+        TokenInfo {
+            client_id: "client".to_owned(),
+            login: Some("user".to_owned()),
+            user_id: Some(user_id.to_owned()),
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+            expires_in: 3600,
+        }
+    }
+
+    #[test]
+    fn checks_explain_what_is_missing() {
+        let follow = json!({"broadcaster_user_id": "1", "moderator_user_id": "2"});
+        assert_eq!(
+            check_subscription(&token("2", &[]), "channel.follow", "2", &follow),
+            Err(ScopeErr::MissingScope(vec!["moderator:read:followers"]))
+        );
+        let moderator = token("3", &["moderator:read:followers"]);
+        let err = check_subscription(&moderator, "channel.follow", "2", &follow).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the condition's moderator_user_id is 2, but the token belongs to user 3"
+        );
+        let moderator = token("2", &["moderator:read:followers"]);
+        assert_eq!(
+            check_subscription(&moderator, "channel.follow", "2", &follow),
+            Ok(())
+        );
+        assert_eq!(
+            check_subscription(&moderator, "channel.follow", "2", &json!({})),
+            Err(ScopeErr::MissingCondition("broadcaster_user_id"))
+        );
+    }
+
+    #[test]
+    fn scopes_with_alternatives_accept_either() {
+        let poll = json!({"broadcaster_user_id": "1"});
+        for scope in ["channel:read:polls", "channel:manage:polls"] {
+            assert_eq!(
+                check_subscription(&token("1", &[scope]), "channel.poll.begin", "1", &poll),
+                Ok(())
+            );
+        }
+        assert_eq!(
+            check_subscription(&token("1", &[]), "stream.online", "2", &poll),
+            Err(ScopeErr::UnknownType(
+                "stream.online".to_owned(),
+                "2".to_owned()
+            ))
+        );
+        let app = TokenInfo {
+            user_id: None,
+            ..token("1", &[])
+        };
+        assert_eq!(
+            check_subscription(&app, "stream.online", "1", &poll),
+            Err(ScopeErr::NoUser)
+        );
+    }
+}
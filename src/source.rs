@@ -0,0 +1,164 @@
+//! The transport the message loop reads frames from. `create_message_processor` only needs a
+//! `MessageSource`, so the same parsing, deduplication, handlers and forwarding run over a
+//! WebSocket connection, a replayed recording, or an in-memory channel in tests. New sources, for
+//! reconnects and migrations, are opened by the session's `Connector`.
+
+use crate::keepalive::READ_TICK;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use tungstenite::protocol::CloseFrame;
+use tungstenite::{Message, WebSocket};
+use url::Url;
+
+/// A connection delivering Twitch's frames, with the semantics of a `tungstenite` WebSocket:
+///
+/// - Reads return an `io::Error` of kind `WouldBlock` or `TimedOut` when nothing arrived within
+///   about a `READ_TICK`, so the message loop can check the keepalive watchdog.
+/// - Other `io::Error`s mean the connection was lost, and a new one is opened.
+/// - `tungstenite::Error::ConnectionClosed` means the source was closed normally, and ends the
+///   message loop.
+pub trait MessageSource: fmt::Debug + Send {
+    fn read_message(&mut self) -> tungstenite::Result<Message>;
+    fn write_message(&mut self, msg: Message) -> tungstenite::Result<()>;
+    /// Starts the closing handshake, after which the reply to the close frame is still read.
+    fn close(&mut self, frame: Option<CloseFrame<'static>>) -> tungstenite::Result<()>;
+    /// Flushes writes which couldn't be completed yet.
+    fn write_pending(&mut self) -> tungstenite::Result<()>;
+    /// Whether the source still accepts writes, which is no longer the case once it was closed.
+    fn can_write(&self) -> bool;
+}
+
+impl<S: Read + Write + Send + fmt::Debug> MessageSource for WebSocket<S> {
+    fn read_message(&mut self) -> tungstenite::Result<Message> {
+        WebSocket::read_message(self)
+    }
+
+    fn write_message(&mut self, msg: Message) -> tungstenite::Result<()> {
+        WebSocket::write_message(self, msg)
+    }
+
+    fn close(&mut self, frame: Option<CloseFrame<'static>>) -> tungstenite::Result<()> {
+        WebSocket::close(self, frame)
+    }
+
+    fn write_pending(&mut self) -> tungstenite::Result<()> {
+        WebSocket::write_pending(self)
+    }
+
+    fn can_write(&self) -> bool {
+        WebSocket::can_write(self)
+    }
+}
+
+/// Opens the sources of a session, both for its first connection and for replacing lost
+/// connections and migrating to the url of a `Reconnect` message.
+pub trait Connector: fmt::Debug + Send + Sync {
+    fn connect(&self, url: &Url) -> tungstenite::Result<Box<dyn MessageSource>>;
+}
+
+/// Connects to EventSub WebSocket servers, such as Twitch's.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WebSocketConnector;
+
+impl Connector for WebSocketConnector {
+    fn connect(&self, url: &Url) -> tungstenite::Result<Box<dyn MessageSource>> {
+        Ok(Box::new(crate::connect(url)?))
+    }
+}
+
+/// An in-memory source, fed through the `ChannelHandle` returned by `channel`.
+#[derive(Debug)]
+pub struct ChannelSource {
+    incoming: Receiver<Message>,
+    outgoing: Sender<Message>,
+    closing: bool,
+}
+
+/// The other end of a `ChannelSource`.
+#[derive(Debug)]
+pub struct ChannelHandle {
+    /// Delivers frames to the source. Dropping it closes the source.
+    pub sender: Sender<Message>,
+    /// Receives the frames written to the source, such as pongs and close frames.
+    pub written: Receiver<Message>,
+}
+
+/// Creates a connected pair of an in-memory source and its handle.
+pub fn channel() -> (ChannelSource, ChannelHandle) {
+    let (sender, incoming) = mpsc::channel();
+    let (outgoing, written) = mpsc::channel();
+    let source = ChannelSource {
+        incoming,
+        outgoing,
+        closing: false,
+    };
+    (source, ChannelHandle { sender, written })
+}
+
+impl MessageSource for ChannelSource {
+    fn read_message(&mut self) -> tungstenite::Result<Message> {
+        match self.incoming.recv_timeout(READ_TICK) {
+            Ok(msg) => Ok(msg),
+            Err(RecvTimeoutError::Timeout) => {
+                Err(io::Error::from(io::ErrorKind::WouldBlock).into())
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(tungstenite::Error::ConnectionClosed),
+        }
+    }
+
+    fn write_message(&mut self, msg: Message) -> tungstenite::Result<()> {
+        if self.closing {
+            return Err(tungstenite::Error::AlreadyClosed);
+        }
+        // A dropped handle just isn't interested in what is written
+        let _ = self.outgoing.send(msg);
+        Ok(())
+    }
+
+    fn close(&mut self, frame: Option<CloseFrame<'static>>) -> tungstenite::Result<()> {
+        if !self.closing {
+            let _ = self.outgoing.send(Message::Close(frame));
+            self.closing = true;
+        }
+        Ok(())
+    }
+
+    fn write_pending(&mut self) -> tungstenite::Result<()> {
+        Ok(())
+    }
+
+    fn can_write(&self) -> bool {
+        !self.closing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_message_processor;
+    use crate::mock;
+    use crate::types::{Session, TwitchMessage};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn pipeline_runs_over_a_channel() {
+        let (source, handle) = channel();
+        let url = Url::parse("memory://test").unwrap();
+        let session = Arc::new(Mutex::new(Session::new(source, url)));
+        let (tx, rx) = mpsc::channel();
+        let listener = std::thread::spawn(move || create_message_processor(session, &tx));
+
+        let keepalive = mock::keepalive();
+        for frame in [mock::welcome("session", 10), keepalive.clone(), keepalive] {
+            handle.sender.send(Message::Text(frame)).unwrap();
+        }
+        handle.sender.send(Message::Ping(b"ping".to_vec())).unwrap();
+        drop(handle.sender);
+
+        assert!(listener.join().unwrap().is_ok());
+        let messages: Vec<TwitchMessage> = rx.try_iter().collect();
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(messages[0], TwitchMessage::Welcome(_)));
+    }
+}
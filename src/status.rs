@@ -0,0 +1,160 @@
+use crate::types::{Session, TwitchMessage};
+use chrono::{DateTime, Utc};
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// Weight of the newest sample in the average delivery latency.
+const LATENCY_SMOOTHING: f64 = 0.2;
+
+/// Measurements of the connection's health, updated by the message loop as frames arrive.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionMetrics {
+    /// Sends a ping at this interval, to measure the round trip time. Twitch only allows pongs
+    /// to be sent, and may close the connection with code 4001 for pings, so this is off by
+    /// default.
+    pub ping_interval: Option<Duration>,
+    last_ping_received_at: Option<Instant>,
+    last_ping_sent: Option<(String, Instant)>,
+    ping_latency: Option<Duration>,
+    delivery_latency: Option<Duration>,
+    average_delivery_latency: Option<Duration>,
+    pings_sent: u64,
+}
+
+impl ConnectionMetrics {
+    /// Records a ping from Twitch, received at `now`. The pong is sent by the socket itself.
+    pub fn ping_received(&mut self, now: Instant) {
+        self.last_ping_received_at = Some(now);
+    }
+
+    /// Returns the payload of the next ping to send, if the `ping_interval` has passed since the
+    /// last one by `now`.
+    pub(crate) fn ping_due(&mut self, now: Instant) -> Option<String> {
+        let interval = self.ping_interval?;
+        if let Some((_, sent_at)) = &self.last_ping_sent {
+            if now.saturating_duration_since(*sent_at) < interval {
+                return None;
+            }
+        }
+        self.pings_sent += 1;
+        let payload = self.pings_sent.to_string();
+        self.last_ping_sent = Some((payload.clone(), now));
+        Some(payload)
+    }
+
+    /// Records the round trip time, if the pong received at `now` answers the last ping that was
+    /// sent.
+    pub fn pong_received(&mut self, payload: &[u8], now: Instant) {
+        if let Some((sent, sent_at)) = &self.last_ping_sent {
+            if sent.as_bytes() == payload {
+                self.ping_latency = Some(now.saturating_duration_since(*sent_at));
+            }
+        }
+    }
+
+    /// Records how long the message took from Twitch's `message_timestamp` to arriving here at
+    /// `received_at`. Messages with a timestamp in the future, due to clock skew, count as
+    /// instant.
+    pub fn message_received(&mut self, msg: &TwitchMessage, received_at: DateTime<Utc>) {
+        let sent_at = match DateTime::parse_from_rfc3339(msg.timestamp()) {
+            Ok(sent_at) => sent_at.with_timezone(&Utc),
+            Err(_) => return,
+        };
+        let latency = (received_at - sent_at).to_std().unwrap_or_default();
+        self.delivery_latency = Some(latency);
+        self.average_delivery_latency = Some(match self.average_delivery_latency {
+            Some(average) => {
+                average.mul_f64(1.0 - LATENCY_SMOOTHING) + latency.mul_f64(LATENCY_SMOOTHING)
+            }
+            None => latency,
+        });
+    }
+}
+
+/// A snapshot of the state of a `Session`, for monitoring and alerting.
+#[derive(Debug, Clone)]
+pub struct ConnectionStatus {
+    pub session_id: String,
+    pub url: Url,
+    /// Whether the session is currently being moved to a reconnect url.
+    pub migrating: bool,
+    pub keepalive: Option<Duration>,
+    pub since_last_message: Duration,
+    /// Time since Twitch last sent a ping, if it has sent any.
+    pub since_last_ping: Option<Duration>,
+    /// Round trip time of the last ping sent by the client, if `ping_interval` is set.
+    pub ping_latency: Option<Duration>,
+    /// Time between Twitch's `message_timestamp` and receiving the last message.
+    pub delivery_latency: Option<Duration>,
+    pub average_delivery_latency: Option<Duration>,
+    pub handled_messages: usize,
+}
+
+impl Session {
+    pub fn status(&self) -> ConnectionStatus {
+        let now = self.clock.now();
+        ConnectionStatus {
+            session_id: self.id.clone(),
+            url: self.eventsub_url.clone(),
+            migrating: self.migration.is_some(),
+            keepalive: self.watchdog.keepalive(),
+            since_last_message: now.saturating_duration_since(self.watchdog.last_message_at()),
+            since_last_ping: self
+                .metrics
+                .last_ping_received_at
+                .map(|at| now.saturating_duration_since(at)),
+            ping_latency: self.metrics.ping_latency,
+            delivery_latency: self.metrics.delivery_latency,
+            average_delivery_latency: self.metrics.average_delivery_latency,
+            handled_messages: self.handled_messsage_ids.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keepalive_sent_at(timestamp: &str) -> TwitchMessage {
+        serde_json::from_value(serde_json::json!({
+            "metadata": {
+                "message_id": "k",
+                "message_type": "session_keepalive",
+                "message_timestamp": timestamp
+            },
+            "payload": {}
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn delivery_latency_is_averaged() {
+        let mut metrics = ConnectionMetrics::default();
+        let now = Utc::now();
+        let two_seconds_ago = (now - chrono::Duration::seconds(2)).to_rfc3339();
+        metrics.message_received(&keepalive_sent_at(&two_seconds_ago), now);
+        let first = metrics.delivery_latency.unwrap();
+        assert_eq!(first, Duration::from_secs(2));
+
+        let in_the_future = (now + chrono::Duration::seconds(2)).to_rfc3339();
+        metrics.message_received(&keepalive_sent_at(&in_the_future), now);
+        assert_eq!(metrics.delivery_latency, Some(Duration::ZERO));
+        assert!(metrics.average_delivery_latency.unwrap() < first);
+    }
+
+    #[test]
+    fn pong_latency_matches_ping_payload() {
+        let mut metrics = ConnectionMetrics::default();
+        let now = Instant::now();
+        assert_eq!(metrics.ping_due(now), None);
+        metrics.ping_interval = Some(Duration::from_secs(60));
+        let payload = metrics.ping_due(now).unwrap();
+        assert_eq!(metrics.ping_due(now + Duration::from_secs(59)), None);
+
+        let later = now + Duration::from_millis(30);
+        metrics.pong_received(b"unrelated", later);
+        assert_eq!(metrics.ping_latency, None);
+        metrics.pong_received(payload.as_bytes(), later);
+        assert_eq!(metrics.ping_latency, Some(Duration::from_millis(30)));
+    }
+}
@@ -0,0 +1,273 @@
+use crate::clock::Clock;
+use crate::error::SubscriptionErr;
+use crate::helix::{SubscriptionCosts, SubscriptionStatus};
+use crate::types::TwitchMessage;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Twitch closes a connection with code 4003 if no subscription is created within this time
+/// after the `Welcome` message.
+pub const SUBSCRIBE_DEADLINE: Duration = Duration::from_secs(10);
+
+/// Called by the `Welcome` handler with the ID of every new session, to create subscriptions
+/// before the `SUBSCRIBE_DEADLINE`. The callback is taken out of the `Session` while it runs, and
+/// the `Session` is unlocked meanwhile, so it stays available to other threads.
+pub type WelcomeCallback = Box<dyn FnMut(&str) -> Result<(), SubscriptionErr> + Send>;
+
+/// Called by the `Revocation` handler for every subscription Twitch revoked, after it was removed
+/// from the `SubscriptionManager`. Like the `WelcomeCallback`, it runs while the `Session` is
+/// unlocked.
+pub type RevocationCallback = Box<dyn FnMut(&Revoked) + Send>;
+
+/// Called by the message loop with every message before it is handled, along with the ID of the
+/// session it arrived on. The `Session` is locked while this runs.
+pub type MessageCallback = Box<dyn FnMut(&str, &TwitchMessage) + Send>;
+
+/// A subscription the application wants to hold on its EventSub session, as described by its
+/// `type`, `version` and `condition`. The transport is always the current WebSocket session.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DesiredSubscription {
+    pub r#type: String,
+    pub version: String,
+    pub condition: Value,
+}
+
+impl DesiredSubscription {
+    pub fn new(r#type: &str, version: &str, condition: Value) -> DesiredSubscription {
+        DesiredSubscription {
+            r#type: r#type.to_owned(),
+            version: version.to_owned(),
+            condition,
+        }
+    }
+}
+
+/// Creates subscriptions bound to a WebSocket session. This is implemented by the Helix client,
+/// but can be replaced, e.g. to route the requests through an application's own API layer.
+pub trait SubscriptionCreator: Send {
+    fn create_subscription(
+        &self,
+        subscription: &DesiredSubscription,
+        session_id: &str,
+    ) -> Result<(), SubscriptionErr>;
+
+    /// The cost accounting as of the last subscription created, if the creator knows it.
+    fn costs(&self) -> Option<SubscriptionCosts> {
+        None
+    }
+
+    /// Called with every message that arrived on the session with `session_id`, before it is
+    /// handled, so the creator can keep its cost accounting up to date.
+    fn observe(&self, _session_id: &str, _message: &TwitchMessage) {}
+}
+
+impl<T: SubscriptionCreator + Sync + ?Sized> SubscriptionCreator for Arc<T> {
+    fn create_subscription(
+        &self,
+        subscription: &DesiredSubscription,
+        session_id: &str,
+    ) -> Result<(), SubscriptionErr> {
+        (**self).create_subscription(subscription, session_id)
+    }
+
+    fn costs(&self) -> Option<SubscriptionCosts> {
+        (**self).costs()
+    }
+
+    fn observe(&self, session_id: &str, message: &TwitchMessage) {
+        (**self).observe(session_id, message)
+    }
+}
+
+/// The outcome of creating all desired subscriptions on a new session.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SubscribeReport {
+    pub session_id: String,
+    pub created: Vec<DesiredSubscription>,
+    /// The subscriptions which could not be created, along with the reason.
+    pub failed: Vec<(DesiredSubscription, String)>,
+}
+
+/// A subscription Twitch revoked, and what was done about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Revoked {
+    pub id: String,
+    pub subscription: DesiredSubscription,
+    /// The status Twitch revoked the subscription with.
+    pub reason: SubscriptionStatus,
+    /// The outcome of creating the subscription again, if that was attempted. This is only done
+    /// for recoverable reasons, and if the `Session` has `resubscribe_revoked` set.
+    pub resubscribed: Option<Result<(), String>>,
+}
+
+/// Remembers the subscriptions the application wants to hold, so they can be created as soon as
+/// the initial `Welcome` message arrives, and recreated whenever Twitch issues a new session after
+/// the connection was lost. (Subscriptions survive a migration after a `Reconnect` message, so no
+/// action is needed in that case.)
+///
+/// Clones share the creator, so the handlers can create subscriptions from a clone without
+/// keeping the `Session` locked.
+#[derive(Clone)]
+pub struct SubscriptionManager {
+    desired: Vec<DesiredSubscription>,
+    creator: Arc<dyn SubscriptionCreator + Sync>,
+}
+
+impl SubscriptionManager {
+    pub fn new(creator: impl SubscriptionCreator + Sync + 'static) -> SubscriptionManager {
+        SubscriptionManager {
+            desired: vec![],
+            creator: Arc::new(creator),
+        }
+    }
+
+    /// Creates the `subscription` on the session with `session_id`, and remembers it on success.
+    pub fn subscribe(
+        &mut self,
+        subscription: DesiredSubscription,
+        session_id: &str,
+    ) -> Result<(), SubscriptionErr> {
+        self.create(&subscription, session_id)?;
+        self.remember(subscription);
+        Ok(())
+    }
+
+    /// Creates the `subscription` on the session with `session_id`, without remembering it.
+    pub fn create(
+        &self,
+        subscription: &DesiredSubscription,
+        session_id: &str,
+    ) -> Result<(), SubscriptionErr> {
+        self.creator.create_subscription(subscription, session_id)
+    }
+
+    /// Remembers the `subscription` without creating it, e.g. before a session exists.
+    pub fn remember(&mut self, subscription: DesiredSubscription) {
+        if !self.desired.contains(&subscription) {
+            self.desired.push(subscription);
+        }
+    }
+
+    /// Stops tracking the `subscription`, so it is not recreated after the next reconnect.
+    pub fn forget(&mut self, subscription: &DesiredSubscription) {
+        self.desired.retain(|desired| desired != subscription);
+    }
+
+    pub fn desired(&self) -> &[DesiredSubscription] {
+        &self.desired
+    }
+
+    /// Passes a message that arrived on the session with `session_id` to the creator, see
+    /// `SubscriptionCreator::observe`.
+    pub fn observe(&self, session_id: &str, message: &TwitchMessage) {
+        self.creator.observe(session_id, message);
+    }
+
+    /// Creates every desired subscription on the session with `session_id`. Failures do not stop
+    /// the remaining subscriptions from being created, and are collected in the report instead.
+    /// Once the `deadline` has passed on the `clock`, the remaining subscriptions are reported as
+    /// failed without contacting Twitch.
+    pub fn subscribe_all(
+        &self,
+        session_id: &str,
+        deadline: Option<Instant>,
+        clock: &dyn Clock,
+    ) -> SubscribeReport {
+        let mut report = SubscribeReport {
+            session_id: session_id.to_owned(),
+            ..Default::default()
+        };
+        for subscription in &self.desired {
+            if deadline.is_some_and(|deadline| clock.now() >= deadline) {
+                report
+                    .failed
+                    .push((subscription.clone(), "subscribe deadline passed".to_owned()));
+                continue;
+            }
+            match self.creator.create_subscription(subscription, session_id) {
+                Ok(()) => report.created.push(subscription.clone()),
+                Err(err) => report.failed.push((subscription.clone(), err.to_string())),
+            }
+        }
+        report
+    }
+}
+
+impl fmt::Debug for SubscriptionManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SubscriptionManager")
+            .field("desired", &self.desired)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    struct FakeCreator {
+        created: Arc<Mutex<Vec<(String, String)>>>,
+    }
+
+    impl SubscriptionCreator for FakeCreator {
+        fn create_subscription(
+            &self,
+            subscription: &DesiredSubscription,
+            session_id: &str,
+        ) -> Result<(), SubscriptionErr> {
+            if subscription.r#type == "channel.ban" {
+                return Err(SubscriptionErr::Rejected("missing scope".to_owned()));
+            }
+            self.created
+                .lock()
+                .unwrap()
+                .push((subscription.r#type.clone(), session_id.to_owned()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn subscribe_all_reports_failures() {
+        let created = Arc::new(Mutex::new(vec![]));
+        let mut manager = SubscriptionManager::new(FakeCreator {
+            created: Arc::clone(&created),
+        });
+        let follow =
+            DesiredSubscription::new("channel.follow", "2", json!({"broadcaster_user_id": "1"}));
+        let ban = DesiredSubscription::new("channel.ban", "1", json!({"broadcaster_user_id": "1"}));
+        manager.subscribe(follow.clone(), "old").unwrap();
+        assert!(manager.subscribe(ban.clone(), "old").is_err());
+        manager.remember(ban.clone());
+
+        let report = manager.subscribe_all("new", None, &SystemClock);
+        assert_eq!(report.created, vec![follow]);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, ban);
+        assert_eq!(
+            *created.lock().unwrap(),
+            vec![
+                ("channel.follow".to_owned(), "old".to_owned()),
+                ("channel.follow".to_owned(), "new".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn subscribe_all_stops_at_deadline() {
+        let created = Arc::new(Mutex::new(vec![]));
+        let mut manager = SubscriptionManager::new(FakeCreator {
+            created: Arc::clone(&created),
+        });
+        manager.remember(DesiredSubscription::new("stream.online", "1", json!({})));
+        let report = manager.subscribe_all("session", Some(Instant::now()), &SystemClock);
+        assert!(report.created.is_empty());
+        assert_eq!(report.failed[0].1, "subscribe deadline passed");
+        assert!(created.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,6 @@
+//! Helpers for testing code that consumes this crate, enabled with the `testing` feature.
+//!
+//! The `fixtures` build the crate's own `TwitchMessage`s directly, to unit-test handlers without
+//! a server. To test against a connection instead, see the `mock` server.
+
+pub mod fixtures;
@@ -0,0 +1,575 @@
+//! Builds realistic messages for every supported subscription type, similar to the Twitch CLI's
+//! `twitch event trigger`. Values are random, but a `Fixtures` created with the same seed always
+//! builds the same messages.
+//!
+//! ```
+//! use eventsub_websocket::testing::fixtures::Fixtures;
+//! use eventsub_websocket::types::TwitchMessage;
+//!
+//! let mut fixtures = Fixtures::seeded(7);
+//! let Some(TwitchMessage::Notification(follow)) = fixtures.notification("channel.follow") else {
+//!     panic!("channel.follow is supported");
+//! };
+//! assert_eq!(follow.payload.subscription.version, "2");
+//! assert!(follow.payload.event["user_login"].is_string());
+//! ```
+
+use crate::types::TwitchMessage;
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use fastrand::Rng;
+use serde_json::{json, Map, Value};
+
+/// The subscription types `Fixtures::notification` can build, with their latest version.
+pub const SUBSCRIPTION_TYPES: &[(&str, &str)] = &[
+    ("channel.update", "2"),
+    ("channel.follow", "2"),
+    ("channel.subscribe", "1"),
+    ("channel.subscription.end", "1"),
+    ("channel.subscription.gift", "1"),
+    ("channel.subscription.message", "1"),
+    ("channel.cheer", "1"),
+    ("channel.raid", "1"),
+    ("channel.ban", "1"),
+    ("channel.unban", "1"),
+    ("channel.moderator.add", "1"),
+    ("channel.moderator.remove", "1"),
+    ("channel.channel_points_custom_reward_redemption.add", "1"),
+    ("channel.poll.begin", "1"),
+    ("channel.poll.end", "1"),
+    ("channel.prediction.begin", "1"),
+    ("channel.prediction.end", "1"),
+    ("channel.hype_train.begin", "1"),
+    ("channel.hype_train.end", "1"),
+    ("channel.shoutout.create", "1"),
+    ("stream.online", "1"),
+    ("stream.offline", "1"),
+    ("user.update", "1"),
+];
+
+const ADJECTIVES: &[&str] = &[
+    "sleepy", "brave", "pixel", "cosmic", "salty", "speedy", "cozy",
+];
+const NOUNS: &[&str] = &[
+    "otter", "wizard", "gamer", "potato", "falcon", "noodle", "knight",
+];
+const TITLES: &[&str] = &[
+    "Chill stream, come hang out",
+    "Any% world record attempts",
+    "First playthrough, no spoilers!",
+    "Subathon day 3",
+];
+const CATEGORIES: &[(&str, &str)] = &[
+    ("509658", "Just Chatting"),
+    ("27471", "Minecraft"),
+    ("21779", "League of Legends"),
+    ("1469308723", "Software and Game Development"),
+];
+const TIERS: &[&str] = &["1000", "2000", "3000"];
+
+/// A Twitch account appearing in the generated events.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub id: String,
+    pub login: String,
+    pub name: String,
+}
+
+impl User {
+    /// The user's `{prefix}_id`, `{prefix}_login` and `{prefix}_name` fields, as used in events.
+    fn fields(&self, prefix: &str) -> Map<String, Value> {
+        let mut fields = Map::new();
+        fields.insert(format!("{}_id", prefix), self.id.clone().into());
+        fields.insert(format!("{}_login", prefix), self.login.clone().into());
+        fields.insert(format!("{}_name", prefix), self.name.clone().into());
+        fields
+    }
+}
+
+/// Generates messages for one broadcaster and session.
+#[derive(Debug, Clone)]
+pub struct Fixtures {
+    rng: Rng,
+    /// The channel all events happen in.
+    pub broadcaster: User,
+    /// Used in the `Welcome` message and the transport of every subscription.
+    pub session_id: String,
+    /// The time messages are sent at. The current time if not set.
+    pub time: Option<DateTime<Utc>>,
+}
+
+impl Fixtures {
+    pub fn new() -> Fixtures {
+        Fixtures::seeded(fastrand::u64(..))
+    }
+
+    pub fn seeded(seed: u64) -> Fixtures {
+        let mut rng = Rng::with_seed(seed);
+        let broadcaster = random_user(&mut rng);
+        let session_id = random_id(&mut rng);
+        Fixtures {
+            rng,
+            broadcaster,
+            session_id,
+            time: None,
+        }
+    }
+
+    /// Sends every message at the given `time`, so the messages only depend on the seed.
+    pub fn at(mut self, time: DateTime<Utc>) -> Fixtures {
+        self.time = Some(time);
+        self
+    }
+
+    pub fn user(&mut self) -> User {
+        random_user(&mut self.rng)
+    }
+
+    /// A `Welcome` message for the `session_id`. The `keepalive` is only left out after a
+    /// `Reconnect`.
+    pub fn welcome(&mut self, keepalive: impl Into<Option<u64>>) -> TwitchMessage {
+        let session = self.session("connected", keepalive.into(), None);
+        parse(json!({
+            "metadata": self.metadata("session_welcome"),
+            "payload": {"session": session},
+        }))
+    }
+
+    pub fn keepalive(&mut self) -> TwitchMessage {
+        parse(json!({"metadata": self.metadata("session_keepalive"), "payload": {}}))
+    }
+
+    pub fn reconnect(&mut self, url: &str) -> TwitchMessage {
+        let session = self.session("reconnecting", None, Some(url));
+        parse(json!({
+            "metadata": self.metadata("session_reconnect"),
+            "payload": {"session": session},
+        }))
+    }
+
+    /// A `Notification` with a random event of the given subscription type, or `None` if the type
+    /// isn't in `SUBSCRIPTION_TYPES`.
+    pub fn notification(&mut self, subscription_type: &str) -> Option<TwitchMessage> {
+        let (condition, event) = self.event(subscription_type)?;
+        Some(self.notification_with(subscription_type, condition, event))
+    }
+
+    /// A `Notification` carrying the given `event`, for types or values `notification` doesn't
+    /// cover.
+    pub fn notification_with(
+        &mut self,
+        subscription_type: &str,
+        condition: Value,
+        event: Value,
+    ) -> TwitchMessage {
+        let subscription = self.subscription(subscription_type, "enabled", condition);
+        parse(json!({
+            "metadata": self.subscription_metadata("notification", subscription_type),
+            "payload": {"subscription": subscription, "event": event},
+        }))
+    }
+
+    /// A `Revocation` of a subscription of the given type, with the reason as its `status`, such
+    /// as `authorization_revoked` or `user_removed`. Returns `None` for unsupported types.
+    pub fn revocation(&mut self, subscription_type: &str, status: &str) -> Option<TwitchMessage> {
+        let (condition, _) = self.event(subscription_type)?;
+        let subscription = self.subscription(subscription_type, status, condition);
+        Some(parse(json!({
+            "metadata": self.subscription_metadata("revocation", subscription_type),
+            "payload": {"subscription": subscription},
+        })))
+    }
+
+    fn now(&self) -> DateTime<Utc> {
+        self.time.unwrap_or_else(Utc::now)
+    }
+
+    fn timestamp(&self, offset: Duration) -> String {
+        (self.now() + offset).to_rfc3339_opts(SecondsFormat::Nanos, true)
+    }
+
+    fn metadata(&mut self, message_type: &str) -> Value {
+        json!({
+            "message_id": random_id(&mut self.rng),
+            "message_type": message_type,
+            "message_timestamp": self.timestamp(Duration::zero()),
+        })
+    }
+
+    fn subscription_metadata(&mut self, message_type: &str, subscription_type: &str) -> Value {
+        let mut metadata = self.metadata(message_type);
+        metadata["subscription_type"] = subscription_type.into();
+        metadata["subscription_version"] = version(subscription_type).into();
+        metadata
+    }
+
+    fn session(&self, status: &str, keepalive: Option<u64>, reconnect_url: Option<&str>) -> Value {
+        json!({
+            "id": self.session_id,
+            "status": status,
+            "connected_at": self.timestamp(Duration::zero()),
+            "keepalive_timeout_seconds": keepalive,
+            "reconnect_url": reconnect_url,
+        })
+    }
+
+    fn subscription(&mut self, subscription_type: &str, status: &str, condition: Value) -> Value {
+        json!({
+            "id": random_id(&mut self.rng),
+            "status": status,
+            "type": subscription_type,
+            "version": version(subscription_type),
+            "cost": 0,
+            "condition": condition,
+            "transport": {"method": "websocket", "session_id": self.session_id},
+            "created_at": self.timestamp(-Duration::hours(1)),
+        })
+    }
+
+    /// The condition and a random event for the subscription type.
+    fn event(&mut self, subscription_type: &str) -> Option<(Value, Value)> {
+        let broadcaster = self.broadcaster.clone();
+        let user = self.user();
+        let moderator = self.user();
+        let by_broadcaster = json!({"broadcaster_user_id": broadcaster.id});
+        let by_moderator = json!({
+            "broadcaster_user_id": broadcaster.id,
+            "moderator_user_id": moderator.id,
+        });
+        let mut event = broadcaster.fields("broadcaster_user");
+
+        let condition = match subscription_type {
+            "channel.update" => {
+                let (category_id, category_name) = self.pick(CATEGORIES);
+                event.extend(object(json!({
+                    "title": self.pick(TITLES),
+                    "language": "en",
+                    "category_id": category_id,
+                    "category_name": category_name,
+                    "content_classification_labels": [],
+                })));
+                by_broadcaster
+            }
+            "channel.follow" => {
+                event.extend(user.fields("user"));
+                event.insert(
+                    "followed_at".into(),
+                    self.timestamp(Duration::zero()).into(),
+                );
+                by_moderator
+            }
+            "channel.subscribe" | "channel.subscription.end" => {
+                event.extend(user.fields("user"));
+                event.insert("tier".into(), self.pick(TIERS).into());
+                event.insert("is_gift".into(), self.rng.bool().into());
+                by_broadcaster
+            }
+            "channel.subscription.gift" => {
+                let total = self.rng.u32(1..=50);
+                event.extend(user.fields("user"));
+                event.extend(object(json!({
+                    "total": total,
+                    "tier": self.pick(TIERS),
+                    "cumulative_total": total + self.rng.u32(0..500),
+                    "is_anonymous": false,
+                })));
+                by_broadcaster
+            }
+            "channel.subscription.message" => {
+                let months = self.rng.u32(1..=48);
+                event.extend(user.fields("user"));
+                event.extend(object(json!({
+                    "tier": self.pick(TIERS),
+                    "message": {"text": "Love the stream!", "emotes": []},
+                    "cumulative_months": months,
+                    "streak_months": self.rng.u32(1..=months),
+                    "duration_months": 1,
+                })));
+                by_broadcaster
+            }
+            "channel.cheer" => {
+                let bits = self.rng.u32(1..=10_000);
+                event.extend(user.fields("user"));
+                event.extend(object(json!({
+                    "is_anonymous": false,
+                    "message": format!("Cheer{} great play!", bits),
+                    "bits": bits,
+                })));
+                by_broadcaster
+            }
+            "channel.raid" => {
+                event = user.fields("from_broadcaster_user");
+                event.extend(broadcaster.fields("to_broadcaster_user"));
+                event.insert("viewers".into(), self.rng.u32(1..=5_000).into());
+                json!({"to_broadcaster_user_id": broadcaster.id})
+            }
+            "channel.ban" => {
+                let is_permanent = self.rng.bool();
+                let ends_at = (!is_permanent).then(|| self.timestamp(Duration::minutes(10)));
+                event.extend(user.fields("user"));
+                event.extend(moderator.fields("moderator_user"));
+                event.extend(object(json!({
+                    "reason": "Spam",
+                    "banned_at": self.timestamp(Duration::zero()),
+                    "ends_at": ends_at,
+                    "is_permanent": is_permanent,
+                })));
+                by_broadcaster
+            }
+            "channel.unban" => {
+                event.extend(user.fields("user"));
+                event.extend(moderator.fields("moderator_user"));
+                by_broadcaster
+            }
+            "channel.moderator.add" | "channel.moderator.remove" => {
+                event.extend(user.fields("user"));
+                by_broadcaster
+            }
+            "channel.channel_points_custom_reward_redemption.add" => {
+                event.extend(user.fields("user"));
+                event.extend(object(json!({
+                    "id": random_id(&mut self.rng),
+                    "user_input": "",
+                    "status": "unfulfilled",
+                    "reward": {
+                        "id": random_id(&mut self.rng),
+                        "title": "Hydrate!",
+                        "cost": self.rng.u32(1..=100) * 100,
+                        "prompt": "Make the streamer drink some water",
+                    },
+                    "redeemed_at": self.timestamp(Duration::zero()),
+                })));
+                by_broadcaster
+            }
+            "channel.poll.begin" | "channel.poll.end" => {
+                let ended = subscription_type == "channel.poll.end";
+                let choices: Vec<Value> = ["Yes", "No"]
+                    .iter()
+                    .map(|title| {
+                        let mut choice = json!({"id": random_id(&mut self.rng), "title": title});
+                        if ended {
+                            let votes = self.rng.u32(0..1_000);
+                            choice["bits_votes"] = 0.into();
+                            choice["channel_points_votes"] = votes.into();
+                            choice["votes"] = votes.into();
+                        }
+                        choice
+                    })
+                    .collect();
+                event.extend(object(json!({
+                    "id": random_id(&mut self.rng),
+                    "title": "Should we play another round?",
+                    "choices": choices,
+                    "bits_voting": {"is_enabled": false, "amount_per_vote": 0},
+                    "channel_points_voting": {"is_enabled": true, "amount_per_vote": 10},
+                    "started_at": self.timestamp(Duration::minutes(-5)),
+                })));
+                if ended {
+                    event.insert("status".into(), "completed".into());
+                    event.insert("ended_at".into(), self.timestamp(Duration::zero()).into());
+                } else {
+                    event.insert(
+                        "ends_at".into(),
+                        self.timestamp(Duration::minutes(5)).into(),
+                    );
+                }
+                by_broadcaster
+            }
+            "channel.prediction.begin" | "channel.prediction.end" => {
+                let ended = subscription_type == "channel.prediction.end";
+                let outcomes: Vec<Value> = [("Win", "blue"), ("Lose", "pink")]
+                    .iter()
+                    .map(|(title, color)| {
+                        let mut outcome = json!({
+                            "id": random_id(&mut self.rng),
+                            "title": title,
+                            "color": color,
+                        });
+                        if ended {
+                            outcome["users"] = self.rng.u32(0..500).into();
+                            outcome["channel_points"] = self.rng.u32(0..100_000).into();
+                        }
+                        outcome
+                    })
+                    .collect();
+                let winner = self.pick(&outcomes)["id"].clone();
+                event.extend(object(json!({
+                    "id": random_id(&mut self.rng),
+                    "title": "Will we beat the boss?",
+                    "outcomes": outcomes,
+                    "started_at": self.timestamp(Duration::minutes(-5)),
+                })));
+                if ended {
+                    event.insert("winning_outcome_id".into(), winner);
+                    event.insert("status".into(), "resolved".into());
+                    event.insert("ended_at".into(), self.timestamp(Duration::zero()).into());
+                } else {
+                    event.insert(
+                        "locks_at".into(),
+                        self.timestamp(Duration::minutes(2)).into(),
+                    );
+                }
+                by_broadcaster
+            }
+            "channel.hype_train.begin" | "channel.hype_train.end" => {
+                let total = self.rng.u32(100..10_000);
+                let mut contribution = user.fields("user");
+                contribution.insert("type".into(), "bits".into());
+                contribution.insert("total".into(), total.into());
+                event.extend(object(json!({
+                    "id": random_id(&mut self.rng),
+                    "level": self.rng.u32(1..=5),
+                    "total": total,
+                    "top_contributions": [contribution],
+                    "started_at": self.timestamp(Duration::minutes(-5)),
+                })));
+                if subscription_type == "channel.hype_train.end" {
+                    event.insert("ended_at".into(), self.timestamp(Duration::zero()).into());
+                    event.insert(
+                        "cooldown_ends_at".into(),
+                        self.timestamp(Duration::hours(1)).into(),
+                    );
+                } else {
+                    event.insert("progress".into(), (total / 2).into());
+                    event.insert("goal".into(), total.into());
+                    event.insert("last_contribution".into(), contribution.into());
+                    event.insert(
+                        "expires_at".into(),
+                        self.timestamp(Duration::minutes(5)).into(),
+                    );
+                }
+                by_broadcaster
+            }
+            "channel.shoutout.create" => {
+                event.extend(user.fields("to_broadcaster_user"));
+                event.extend(moderator.fields("moderator_user"));
+                event.extend(object(json!({
+                    "viewer_count": self.rng.u32(1..=5_000),
+                    "started_at": self.timestamp(Duration::zero()),
+                    "cooldown_ends_at": self.timestamp(Duration::minutes(2)),
+                    "target_cooldown_ends_at": self.timestamp(Duration::hours(1)),
+                })));
+                by_moderator
+            }
+            "stream.online" => {
+                event.extend(object(json!({
+                    "id": self.rng.u64(1_000_000_000..10_000_000_000).to_string(),
+                    "type": "live",
+                    "started_at": self.timestamp(Duration::zero()),
+                })));
+                by_broadcaster
+            }
+            "stream.offline" => by_broadcaster,
+            "user.update" => {
+                event = broadcaster.fields("user");
+                event.extend(object(json!({
+                    "email": format!("{}@example.com", broadcaster.login),
+                    "email_verified": true,
+                    "description": "Streaming most days!",
+                })));
+                json!({"user_id": broadcaster.id})
+            }
+            _ => return None,
+        };
+        Some((condition, Value::Object(event)))
+    }
+
+    fn pick<T: Clone>(&mut self, options: &[T]) -> T {
+        options[self.rng.usize(..options.len())].clone()
+    }
+}
+
+impl Default for Fixtures {
+    fn default() -> Self {
+        Fixtures::new()
+    }
+}
+
+fn parse(message: Value) -> TwitchMessage {
+    serde_json::from_value(message).expect("fixtures always match the message types")
+}
+
+fn version(subscription_type: &str) -> &'static str {
+    SUBSCRIPTION_TYPES
+        .iter()
+        .find(|(supported, _)| *supported == subscription_type)
+        .map_or("1", |(_, version)| version)
+}
+
+fn object(value: Value) -> Map<String, Value> {
+    match value {
+        Value::Object(map) => map,
+        _ => Map::new(),
+    }
+}
+
+fn random_user(rng: &mut Rng) -> User {
+    let login = format!(
+        "{}{}{}",
+        ADJECTIVES[rng.usize(..ADJECTIVES.len())],
+        NOUNS[rng.usize(..NOUNS.len())],
+        rng.u32(1..1000)
+    );
+    let mut name = login.clone();
+    name[..1].make_ascii_uppercase();
+    User {
+        id: rng.u32(10_000..1_000_000_000).to_string(),
+        login,
+        name,
+    }
+}
+
+/// A random ID in the format of a UUID, as Twitch uses for messages and subscriptions.
+fn random_id(rng: &mut Rng) -> String {
+    let hex: String = (0..32)
+        .map(|_| char::from_digit(rng.u32(..16), 16).unwrap())
+        .collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_subscription_type_builds_a_notification() {
+        let mut fixtures = Fixtures::new();
+        for (subscription_type, version) in SUBSCRIPTION_TYPES {
+            match fixtures.notification(subscription_type) {
+                Some(TwitchMessage::Notification(msg)) => {
+                    assert_eq!(msg.metadata.subscription_type, *subscription_type);
+                    assert_eq!(msg.payload.subscription.version, *version);
+                    assert!(msg.payload.event.is_object());
+                }
+                other => panic!("{}: {:?}", subscription_type, other),
+            }
+            assert!(matches!(
+                fixtures.revocation(subscription_type, "authorization_revoked"),
+                Some(TwitchMessage::Revocation(_))
+            ));
+        }
+        assert!(fixtures.notification("channel.unknown").is_none());
+    }
+
+    #[test]
+    fn same_seed_builds_same_messages() {
+        let time = Utc::now();
+        let build = || {
+            let mut fixtures = Fixtures::seeded(42).at(time);
+            let welcome = serde_json::to_string(&fixtures.welcome(10)).unwrap();
+            let cheer = serde_json::to_string(&fixtures.notification("channel.cheer")).unwrap();
+            (welcome, cheer)
+        };
+        assert_eq!(build(), build());
+        assert!(matches!(
+            Fixtures::seeded(42).reconnect("wss://eventsub.wss.twitch.tv/ws"),
+            TwitchMessage::Reconnect(_)
+        ));
+    }
+}
@@ -1,28 +1,115 @@
-use crate::error::KeepaliveErr;
+use crate::clock::{Clock, SystemClock};
+use crate::dedup::HandledMessages;
+use crate::events::ClientEvent;
+use crate::helix::SubscriptionStatus;
+use crate::keepalive::KeepaliveWatchdog;
+use crate::migration::{Migration, ReconnectUrlPolicy};
+use crate::recording::Recorder;
+use crate::source::{Connector, MessageSource, WebSocketConnector};
+use crate::status::ConnectionMetrics;
+use crate::subscriptions::{
+    DesiredSubscription, MessageCallback, RevocationCallback, SubscriptionManager, WelcomeCallback,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::Number;
 use serde_json::Value;
+use std::collections::VecDeque;
+use std::fmt;
 use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
 use std::time::Duration;
-use tungstenite::{stream::MaybeTlsStream, WebSocket};
+use tungstenite::{stream::MaybeTlsStream, Message, WebSocket};
 use url::Url;
 
-#[derive(Debug)]
 /// The connection to the EventSub server with Twitch, which contains a socket, a session ID, and
 /// the vector of handled messages (to avoid handling duplicates).
 pub struct Session {
-    /// The socket which is connected to Twitch's EventSub WebSocket server.
-    pub socket: Socket,
+    /// The connection to Twitch's EventSub WebSocket server, or any other `MessageSource`.
+    pub socket: Box<dyn MessageSource>,
+    /// Opens new connections, for reconnects and migrations.
+    pub connector: Arc<dyn Connector>,
     /// The session ID Twitch returns with the `Welcome` message. Initially empty String.
     pub id: String,
-    /// The `handled` vector contains the message IDs of those messages which have already been
-    /// handled, to avoid taking action twice when Twitch repeats their notification.
-    pub handled_messsage_ids: Vec<String>,
+    /// The message IDs of those messages which have already been handled, to avoid taking action
+    /// twice when Twitch repeats their notification.
+    pub handled_messsage_ids: HandledMessages,
     /// The url used to connect to the EventSub server, if a different url was recieved from Twitch
     /// in a `Reconnect` message. (Or used in testing.)
     pub eventsub_url: Url,
+    /// Receives the `ClientEvent`s describing the lifecycle of the connection, if set.
+    pub event_forwarder: Option<Sender<ClientEvent>>,
+    /// Creates the desired subscriptions whenever Twitch issues a new session, if set.
+    pub subscriptions: Option<SubscriptionManager>,
+    /// Called with the ID of every new session, after the desired subscriptions were created.
+    pub on_welcome: Option<WelcomeCallback>,
+    /// Called with every subscription Twitch revoked.
+    pub on_revoked: Option<RevocationCallback>,
+    /// Called with every message before it is handled, e.g. to keep the budget of a
+    /// `HelixClient` up to date through `HelixClient::observe`. The `SubscriptionManager`'s
+    /// creator sees every message regardless.
+    pub on_message: Option<MessageCallback>,
+    /// Creates revoked subscriptions again if their reason is recoverable, see
+    /// `SubscriptionStatus::is_recoverable`. Off by default.
+    pub resubscribe_revoked: bool,
+    /// Set while the connection is being replaced after it was lost, so the next `Welcome`
+    /// message is reported as a reconnect.
+    pub(crate) reconnecting: bool,
+    /// How many times a lost connection is tried to be replaced, before the message loop gives
+    /// up with `EventSubErr::ReconnectFailed`. Unlimited if `None`, which is the default.
+    pub max_reconnect_attempts: Option<u32>,
+    /// Decides which urls Twitch's `Reconnect` messages may move the connection to.
+    pub reconnect_policy: ReconnectUrlPolicy,
+    /// The progress of moving to the url of a `Reconnect` message, while one is under way.
+    pub migration: Option<Migration>,
+    /// Messages to process before reading from the socket again, such as those the new
+    /// connection received while the old one was drained during a migration.
+    pub(crate) backlog: VecDeque<Message>,
+    /// Notices when Twitch stopped sending messages for longer than the keepalive time.
+    pub watchdog: KeepaliveWatchdog,
+    /// Ping and latency measurements, reported through `status`.
+    pub metrics: ConnectionMetrics,
+    /// The source of time for all timeouts and measurements, see `set_clock`.
+    pub(crate) clock: Arc<dyn Clock>,
+    /// Writes every frame received to a recording, if set.
+    pub recorder: Option<Recorder>,
+    /// Identifies the current connection in recordings. Every connection opened by the session
+    /// gets the next ID.
+    pub(crate) connection_id: u64,
+    connections_opened: u64,
+    stop: StopHandle,
+}
+
+/// Ends a session's message loop from another thread, without waiting for the session's lock,
+/// which the loop may hold for as long as creating subscriptions takes. See `Session::stop_handle`.
+#[derive(Debug, Clone, Default)]
+pub struct StopHandle(Arc<AtomicBool>);
+
+impl StopHandle {
+    /// Makes the message loop close the connection and return, once it is done with the message
+    /// or reconnect attempt at hand.
+    pub fn stop(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session")
+            .field("socket", &self.socket)
+            .field("id", &self.id)
+            .field("handled_messsage_ids", &self.handled_messsage_ids)
+            .field("eventsub_url", &self.eventsub_url)
+            .field("subscriptions", &self.subscriptions)
+            .field("migration", &self.migration)
+            .finish_non_exhaustive()
+    }
 }
 
 /// This layered type is [`tungstenite`](https://crates.io/crates/tungstenite)'s WebSocket connection.
@@ -31,14 +118,16 @@ pub type Socket = WebSocket<MaybeTlsStream<TcpStream>>;
 pub struct EventResult {
     pub listener: JoinHandle<Result<(), String>>,
     pub session: Arc<Mutex<crate::types::Session>>,
+    pub events: Receiver<ClientEvent>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged)]
 pub enum TwitchMessage {
     Notification(Notification),
-    Welcome(Welcome),
+    // `Reconnect` has to be tried before `Welcome`, which would also accept its payload.
     Reconnect(Reconnect),
+    Welcome(Welcome),
     Revocation(Revocation),
     Keepalive(Keepalive),
 }
@@ -70,7 +159,7 @@ pub struct Keepalive {
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Revocation {
     pub metadata: SubscriptionMetadata,
-    pub payload: SubscriptionPayload,
+    pub payload: RevocationPayload,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -85,7 +174,8 @@ pub struct WelcomeSessionData {
     pub id: String,
     pub status: String,
     pub connected_at: String,
-    pub keepalive_timeout_seconds: Number,
+    /// Only missing from the `Welcome` message of a connection opened after a `Reconnect`.
+    pub keepalive_timeout_seconds: Option<Number>,
     pub reconnect_url: Option<String>,
 }
 
@@ -129,6 +219,24 @@ pub struct SubscriptionPayload {
     pub created_at: String,
 }
 
+impl SubscriptionPayload {
+    pub fn to_desired(&self) -> DesiredSubscription {
+        DesiredSubscription::new(&self.r#type, &self.version, self.condition.clone())
+    }
+
+    /// The `status`, parsed. Statuses added by Twitch after this was written are `Unknown`.
+    pub fn parsed_status(&self) -> SubscriptionStatus {
+        serde_json::from_value(Value::String(self.status.clone()))
+            .unwrap_or(SubscriptionStatus::Unknown)
+    }
+}
+
+/// The revoked subscription, whose `status` carries the reason it was revoked.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct RevocationPayload {
+    pub subscription: SubscriptionPayload,
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct NotificationPayload {
     pub subscription: SubscriptionPayload,
@@ -143,29 +251,103 @@ pub struct NotificationPayload {
 /// received — a vector of message IDs that have already been handled — to avoid double-handling
 /// replayed messages — and the url used to connect to the EventSub server.
 impl Session {
-    pub fn new(socket: Socket, url: Url) -> Session {
+    /// Creates a session reading from the `socket`, which uses the `WebSocketConnector` for new
+    /// connections.
+    pub fn new(socket: impl MessageSource + 'static, url: Url) -> Session {
+        Session::with_connector(Box::new(socket), url, Arc::new(WebSocketConnector))
+    }
+
+    pub fn with_connector(
+        socket: Box<dyn MessageSource>,
+        url: Url,
+        connector: Arc<dyn Connector>,
+    ) -> Session {
         Session {
             socket,
+            connector,
             id: String::new(),
-            handled_messsage_ids: vec![],
+            handled_messsage_ids: HandledMessages::default(),
             eventsub_url: url,
+            event_forwarder: None,
+            subscriptions: None,
+            on_welcome: None,
+            on_revoked: None,
+            on_message: None,
+            resubscribe_revoked: false,
+            reconnecting: false,
+            max_reconnect_attempts: None,
+            reconnect_policy: ReconnectUrlPolicy::default(),
+            migration: None,
+            backlog: VecDeque::new(),
+            watchdog: KeepaliveWatchdog::default(),
+            metrics: ConnectionMetrics::default(),
+            clock: Arc::new(SystemClock),
+            recorder: None,
+            connection_id: 0,
+            connections_opened: 1,
+            stop: StopHandle::default(),
         }
     }
 
-    /// Sets the timeout on the `Session`'s contained `Socket` to match the keepalive time returned
-    /// by Twitch in a `Welcome` message. Adds an extra second as a grace period.
-    pub fn set_keepalive(&mut self, keepalive: u64) -> Result<(), KeepaliveErr> {
-        let binding = &mut self.socket;
-        let stream = match binding.get_mut() {
-            MaybeTlsStream::NativeTls(stream) => stream.get_mut(),
-            MaybeTlsStream::Plain(stream) => stream,
-            _ => unreachable!("Stream has to always be either TLS or plain"),
-        };
-        stream
-            // Allow a short grace period by adding one second to the reported keepalive
-            // timeout
-            .set_read_timeout(Some(Duration::from_secs(keepalive + 1)))?;
-        Ok(())
+    /// Replaces the `SystemClock`, e.g. with a `ManualClock` in tests. The keepalive time starts
+    /// over on the new clock.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.watchdog.feed(clock.now());
+        self.clock = clock;
+    }
+
+    pub fn clock(&self) -> &dyn Clock {
+        &*self.clock
+    }
+
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+
+    /// Reserves the ID of a newly opened connection.
+    pub(crate) fn next_connection_id(&mut self) -> u64 {
+        self.connections_opened += 1;
+        self.connections_opened - 1
+    }
+
+    /// Passes a frame received on the `connection` to the recorder, if one is set. A recorder
+    /// that fails to write is dropped, and reported, so the connection itself is unaffected.
+    pub(crate) fn record(&mut self, connection: u64, msg: &Message) {
+        let received_at = self.clock.wall();
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(err) = recorder.record(connection, received_at, msg) {
+                self.recorder = None;
+                self.emit(ClientEvent::RecordingStopped {
+                    reason: err.to_string(),
+                });
+            }
+        }
+    }
+
+    /// Passes a message that arrived on the current session to the `SubscriptionManager`'s
+    /// creator and the `on_message` callback, before it is handled.
+    pub(crate) fn observe(&mut self, message: &TwitchMessage) {
+        let session_id = self.id.clone();
+        if let Some(manager) = &self.subscriptions {
+            manager.observe(&session_id, message);
+        }
+        if let Some(on_message) = &mut self.on_message {
+            on_message(&session_id, message);
+        }
+    }
+
+    /// Passes the `event` on to the event forwarder, if one is set. Events are informational, so
+    /// a dropped receiver is not treated as an error.
+    pub fn emit(&self, event: ClientEvent) {
+        if let Some(forwarder) = &self.event_forwarder {
+            let _ = forwarder.send(event);
+        }
+    }
+
+    /// Sets the keepalive time returned by Twitch in a `Welcome` message on the `Session`'s
+    /// watchdog. Its grace period is added on top of this.
+    pub fn set_keepalive(&mut self, keepalive: u64) {
+        self.watchdog.set_keepalive(Duration::from_secs(keepalive));
     }
 }
 
@@ -180,4 +362,15 @@ impl TwitchMessage {
             Self::Revocation(msg) => msg.metadata.message_id.clone(),
         }
     }
+
+    /// The time Twitch sent the message at, as an RFC3339 timestamp.
+    pub fn timestamp(&self) -> &str {
+        match self {
+            Self::Welcome(msg) => &msg.metadata.message_timestamp,
+            Self::Keepalive(msg) => &msg.metadata.message_timestamp,
+            Self::Notification(msg) => &msg.metadata.message_timestamp,
+            Self::Reconnect(msg) => &msg.metadata.message_timestamp,
+            Self::Revocation(msg) => &msg.metadata.message_timestamp,
+        }
+    }
 }
@@ -0,0 +1,455 @@
+//! Receives EventSub notifications over the webhook transport, for subscription types which
+//! Twitch doesn't deliver over WebSockets, such as drop entitlements and extension bits
+//! transactions.
+//!
+//! Twitch POSTs every message to the subscription's callback. The `WebhookSource` checks each
+//! request's signature, answers `webhook_callback_verification` challenges itself, and turns
+//! notifications and revocations into the same frames the WebSocket transport delivers. Since it
+//! is a `MessageSource`, the message loop then deduplicates them, drops stale ones, and forwards
+//! them as `TwitchMessage`s:
+//!
+//! ```no_run
+//! use eventsub_websocket::types::Session;
+//! use eventsub_websocket::webhook::WebhookSource;
+//! use std::sync::{mpsc, Arc, Mutex};
+//! use url::Url;
+//!
+//! let source = WebhookSource::bind("0.0.0.0:8080", "s3cr3t-s3cr3t").unwrap();
+//! let url = Url::parse("https://example.com/eventsub").unwrap();
+//! let session = Arc::new(Mutex::new(Session::new(source, url)));
+//! let (tx, rx) = mpsc::channel();
+//! std::thread::spawn(move || eventsub_websocket::create_message_processor(session, &tx));
+//! for message in rx {
+//!     println!("{:?}", message);
+//! }
+//! ```
+
+use crate::error::WebhookErr;
+use crate::keepalive::READ_TICK;
+use crate::source::MessageSource;
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{Header, Request, Response, Server};
+use tungstenite::protocol::CloseFrame;
+use tungstenite::Message;
+
+pub const MESSAGE_ID: &str = "Twitch-Eventsub-Message-Id";
+pub const MESSAGE_TIMESTAMP: &str = "Twitch-Eventsub-Message-Timestamp";
+pub const MESSAGE_SIGNATURE: &str = "Twitch-Eventsub-Message-Signature";
+pub const MESSAGE_TYPE: &str = "Twitch-Eventsub-Message-Type";
+pub const SUBSCRIPTION_TYPE: &str = "Twitch-Eventsub-Subscription-Type";
+pub const SUBSCRIPTION_VERSION: &str = "Twitch-Eventsub-Subscription-Version";
+
+/// The `Twitch-Eventsub-Message-*` and `Twitch-Eventsub-Subscription-*` headers of a request.
+#[derive(Debug, Clone, Default)]
+pub struct WebhookHeaders {
+    pub message_id: String,
+    pub message_timestamp: String,
+    pub message_signature: String,
+    pub message_type: String,
+    pub subscription_type: String,
+    pub subscription_version: String,
+}
+
+impl WebhookHeaders {
+    /// Collects the headers from `(name, value)` pairs, whose names are compared ignoring case.
+    pub fn from_pairs<'a>(
+        pairs: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<WebhookHeaders, WebhookErr> {
+        let mut headers = WebhookHeaders::default();
+        for (name, value) in pairs {
+            let field = match name {
+                name if name.eq_ignore_ascii_case(MESSAGE_ID) => &mut headers.message_id,
+                name if name.eq_ignore_ascii_case(MESSAGE_TIMESTAMP) => {
+                    &mut headers.message_timestamp
+                }
+                name if name.eq_ignore_ascii_case(MESSAGE_SIGNATURE) => {
+                    &mut headers.message_signature
+                }
+                name if name.eq_ignore_ascii_case(MESSAGE_TYPE) => &mut headers.message_type,
+                name if name.eq_ignore_ascii_case(SUBSCRIPTION_TYPE) => {
+                    &mut headers.subscription_type
+                }
+                name if name.eq_ignore_ascii_case(SUBSCRIPTION_VERSION) => {
+                    &mut headers.subscription_version
+                }
+                _ => continue,
+            };
+            *field = value.to_owned();
+        }
+        for (name, value) in [
+            (MESSAGE_ID, &headers.message_id),
+            (MESSAGE_TIMESTAMP, &headers.message_timestamp),
+            (MESSAGE_SIGNATURE, &headers.message_signature),
+            (MESSAGE_TYPE, &headers.message_type),
+        ] {
+            if value.is_empty() {
+                return Err(WebhookErr::MissingHeader(name));
+            }
+        }
+        Ok(headers)
+    }
+}
+
+/// What a verified request asked for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Received {
+    /// Twitch is verifying the callback of a new subscription, which is confirmed by responding
+    /// with the challenge.
+    Challenge(String),
+    /// A notification or revocation, as the text of the frame the WebSocket transport would have
+    /// delivered.
+    Frame(String),
+}
+
+/// Computes the `Twitch-Eventsub-Message-Signature` of a message: `sha256=` followed by the
+/// hex-encoded HMAC-SHA256 of its ID, timestamp and body.
+pub fn sign(secret: &[u8], message_id: &str, timestamp: &str, body: &[u8]) -> String {
+    let mac = mac(secret, message_id, timestamp, body);
+    let digest = mac.finalize().into_bytes();
+    let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("sha256={}", hex)
+}
+
+/// Checks a `Twitch-Eventsub-Message-Signature`, in constant time.
+pub fn verify_signature(
+    secret: &[u8],
+    message_id: &str,
+    timestamp: &str,
+    body: &[u8],
+    signature: &str,
+) -> bool {
+    let Some(expected) = signature.strip_prefix("sha256=").and_then(decode_hex) else {
+        return false;
+    };
+    mac(secret, message_id, timestamp, body)
+        .verify_slice(&expected)
+        .is_ok()
+}
+
+fn mac(secret: &[u8], message_id: &str, timestamp: &str, body: &[u8]) -> Hmac<Sha256> {
+    // HMAC accepts keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC key of any length");
+    mac.update(message_id.as_bytes());
+    mac.update(timestamp.as_bytes());
+    mac.update(body);
+    mac
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Verifies a request and works out what it asked for. Webhook bodies lack the `metadata` of
+/// WebSocket messages, so it is rebuilt from the headers.
+pub fn receive(
+    secret: &[u8],
+    headers: &WebhookHeaders,
+    body: &[u8],
+) -> Result<Received, WebhookErr> {
+    if !verify_signature(
+        secret,
+        &headers.message_id,
+        &headers.message_timestamp,
+        body,
+        &headers.message_signature,
+    ) {
+        return Err(WebhookErr::InvalidSignature);
+    }
+    let body: Value = serde_json::from_slice(body)?;
+    match headers.message_type.as_str() {
+        "webhook_callback_verification" => match body.get("challenge") {
+            Some(Value::String(challenge)) => Ok(Received::Challenge(challenge.clone())),
+            _ => Err(WebhookErr::MissingChallenge),
+        },
+        "notification" | "revocation" => {
+            let frame = json!({
+                "metadata": {
+                    "message_id": headers.message_id,
+                    "message_type": headers.message_type,
+                    "message_timestamp": headers.message_timestamp,
+                    "subscription_type": headers.subscription_type,
+                    "subscription_version": headers.subscription_version,
+                },
+                "payload": body,
+            });
+            Ok(Received::Frame(frame.to_string()))
+        }
+        other => Err(WebhookErr::UnknownMessageType(other.to_owned())),
+    }
+}
+
+/// A `MessageSource` fed by an HTTP server receiving Twitch's webhook requests. Requests with a
+/// bad signature are rejected with `403 Forbidden`, and those which can't be understood with
+/// `400 Bad Request`. Everything else is acknowledged right away, including duplicates, since
+/// Twitch keeps retrying messages which weren't.
+pub struct WebhookSource {
+    server: Arc<Server>,
+    incoming: Receiver<Message>,
+    closing: bool,
+}
+
+impl fmt::Debug for WebhookSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebhookSource")
+            .field("addr", &self.local_addr())
+            .field("closing", &self.closing)
+            .finish()
+    }
+}
+
+impl WebhookSource {
+    /// Starts listening for requests signed with the `secret` that was passed to Twitch when
+    /// creating the subscriptions.
+    pub fn bind(addr: impl ToSocketAddrs, secret: impl Into<Vec<u8>>) -> io::Result<WebhookSource> {
+        let server = Server::http(addr).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        let server = Arc::new(server);
+        let (sender, incoming) = mpsc::channel();
+        let secret = secret.into();
+        let listener = Arc::clone(&server);
+        thread::Builder::new()
+            .name("eventsub-webhook".to_owned())
+            .spawn(move || {
+                for request in listener.incoming_requests() {
+                    respond(&secret, request, &sender);
+                }
+            })?;
+        Ok(WebhookSource {
+            server,
+            incoming,
+            closing: false,
+        })
+    }
+
+    /// The address the server listens on, which is useful when binding to port 0.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+}
+
+fn respond(secret: &[u8], mut request: Request, sender: &Sender<Message>) {
+    let mut body = Vec::new();
+    if request.as_reader().read_to_end(&mut body).is_err() {
+        let _ = request.respond(Response::empty(400));
+        return;
+    }
+    let pairs = request
+        .headers()
+        .iter()
+        .map(|header| (header.field.as_str().as_str(), header.value.as_str()));
+    let received =
+        WebhookHeaders::from_pairs(pairs).and_then(|headers| receive(secret, &headers, &body));
+    let response = match received {
+        Ok(Received::Challenge(challenge)) => Response::from_string(challenge)
+            .with_header(Header::from_bytes("Content-Type", "text/plain").expect("valid header")),
+        Ok(Received::Frame(frame)) => {
+            // The source was dropped, so nobody is interested in the message anymore
+            let _ = sender.send(Message::Text(frame));
+            Response::from_string("").with_status_code(204)
+        }
+        Err(WebhookErr::InvalidSignature | WebhookErr::MissingHeader(_)) => {
+            Response::from_string("").with_status_code(403)
+        }
+        Err(_) => Response::from_string("").with_status_code(400),
+    };
+    let _ = request.respond(response);
+}
+
+impl MessageSource for WebhookSource {
+    fn read_message(&mut self) -> tungstenite::Result<Message> {
+        if self.closing {
+            return Err(tungstenite::Error::ConnectionClosed);
+        }
+        match self.incoming.recv_timeout(READ_TICK) {
+            Ok(msg) => Ok(msg),
+            Err(RecvTimeoutError::Timeout) => {
+                Err(io::Error::from(io::ErrorKind::WouldBlock).into())
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(tungstenite::Error::ConnectionClosed),
+        }
+    }
+
+    /// Webhooks have no way to send anything to Twitch, so writes are dropped.
+    fn write_message(&mut self, _msg: Message) -> tungstenite::Result<()> {
+        if self.closing {
+            return Err(tungstenite::Error::AlreadyClosed);
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, _frame: Option<CloseFrame<'static>>) -> tungstenite::Result<()> {
+        self.closing = true;
+        self.server.unblock();
+        Ok(())
+    }
+
+    fn write_pending(&mut self) -> tungstenite::Result<()> {
+        Ok(())
+    }
+
+    fn can_write(&self) -> bool {
+        !self.closing
+    }
+}
+
+impl Drop for WebhookSource {
+    fn drop(&mut self) {
+        self.server.unblock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_message_processor;
+    use crate::testing::fixtures::Fixtures;
+    use crate::types::{Session, TwitchMessage};
+    use chrono::{Duration, Utc};
+    use std::sync::Mutex;
+    use url::Url;
+
+    const SECRET: &str = "s3cr3t-s3cr3t";
+
+    #[test]
+    fn signatures_cover_id_timestamp_and_body() {
+        // The HMAC-SHA256 of "The quick brown fox jumps over the lazy dog" with the key "key"
+        let signature = "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8";
+        let body = b"jumps over the lazy dog";
+        assert_eq!(sign(b"key", "The quick ", "brown fox ", body), signature);
+        assert!(verify_signature(
+            b"key",
+            "The quick ",
+            "brown fox ",
+            body,
+            signature
+        ));
+        assert!(!verify_signature(
+            b"key",
+            "The quick ",
+            "brown fox ",
+            b"jumps",
+            signature
+        ));
+        assert!(!verify_signature(
+            b"yek",
+            "The quick ",
+            "brown fox ",
+            body,
+            signature
+        ));
+        assert!(!verify_signature(
+            b"key",
+            "The quick ",
+            "brown fox ",
+            body,
+            "sha256=zz"
+        ));
+    }
+
+    fn post(
+        url: &str,
+        message_type: &str,
+        id: &str,
+        timestamp: &str,
+        body: &str,
+        secret: &str,
+    ) -> u16 {
+        let signature = sign(secret.as_bytes(), id, timestamp, body.as_bytes());
+        let response = ureq::post(url)
+            .set(MESSAGE_ID, id)
+            .set(MESSAGE_TIMESTAMP, timestamp)
+            .set(MESSAGE_SIGNATURE, &signature)
+            .set(MESSAGE_TYPE, message_type)
+            .set(SUBSCRIPTION_TYPE, "channel.follow")
+            .set(SUBSCRIPTION_VERSION, "2")
+            .send_string(body);
+        match response {
+            Ok(response) => response.status(),
+            Err(ureq::Error::Status(status, _)) => status,
+            Err(err) => panic!("request failed: {}", err),
+        }
+    }
+
+    #[test]
+    fn requests_run_through_the_pipeline() {
+        let source = WebhookSource::bind("127.0.0.1:0", SECRET).unwrap();
+        let url = format!("http://{}/", source.local_addr().unwrap());
+        let session = Arc::new(Mutex::new(Session::new(source, Url::parse(&url).unwrap())));
+        let (tx, rx) = mpsc::channel();
+        let processor = Arc::clone(&session);
+        let listener = thread::spawn(move || create_message_processor(processor, &tx));
+
+        let now = Utc::now().to_rfc3339();
+        let challenge = r#"{"challenge":"pogchamp-kappa-360noscope-vohiyo","subscription":{}}"#;
+        let response = ureq::post(&url)
+            .set(MESSAGE_ID, "challenge")
+            .set(MESSAGE_TIMESTAMP, &now)
+            .set(
+                MESSAGE_SIGNATURE,
+                &sign(SECRET.as_bytes(), "challenge", &now, challenge.as_bytes()),
+            )
+            .set(MESSAGE_TYPE, "webhook_callback_verification")
+            .send_string(challenge)
+            .unwrap();
+        assert_eq!(
+            response.into_string().unwrap(),
+            "pogchamp-kappa-360noscope-vohiyo"
+        );
+
+        let mut fixtures = Fixtures::seeded(41);
+        let TwitchMessage::Notification(notification) =
+            fixtures.notification("channel.follow").unwrap()
+        else {
+            panic!("expected a notification");
+        };
+        let body = serde_json::to_string(&notification.payload).unwrap();
+        let old = (Utc::now() - Duration::minutes(11)).to_rfc3339();
+        assert_eq!(post(&url, "notification", "a", &now, &body, "wrong"), 403);
+        assert_eq!(post(&url, "notification", "a", &now, &body, SECRET), 204);
+        assert_eq!(post(&url, "notification", "a", &now, &body, SECRET), 204);
+        assert_eq!(post(&url, "notification", "old", &old, &body, SECRET), 204);
+        assert_eq!(post(&url, "surprise", "b", &now, &body, SECRET), 400);
+
+        let TwitchMessage::Revocation(revocation) = fixtures
+            .revocation("channel.follow", "authorization_revoked")
+            .unwrap()
+        else {
+            panic!("expected a revocation");
+        };
+        let body = serde_json::to_string(&revocation.payload).unwrap();
+        assert_eq!(post(&url, "revocation", "c", &now, &body, SECRET), 204);
+
+        let first = rx.recv_timeout(READ_TICK * 20).unwrap();
+        let TwitchMessage::Notification(received) = first else {
+            panic!("expected a notification, got {:?}", first);
+        };
+        assert_eq!(received.metadata.message_id, "a");
+        assert_eq!(received.metadata.subscription_type, "channel.follow");
+        assert_eq!(received.payload.event, notification.payload.event);
+        let second = rx.recv_timeout(READ_TICK * 20).unwrap();
+        let TwitchMessage::Revocation(received) = second else {
+            panic!("expected a revocation, got {:?}", second);
+        };
+        assert_eq!(
+            received.payload.subscription.status,
+            "authorization_revoked"
+        );
+
+        session.lock().unwrap().socket.close(None).unwrap();
+        assert!(listener.join().unwrap().is_ok());
+        assert!(rx.try_recv().is_err());
+    }
+}